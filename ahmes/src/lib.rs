@@ -1,13 +1,9 @@
 #[cfg(test)]
 mod test;
 
-use error::{Fallible, InvalidFile, WithPath};
-use std::{
-    fmt,
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-};
+use error::Fallible;
+use machine::{Core, Machine as _};
+use std::io::{Read, Write};
 
 /// Opcode of NOP
 pub const NOP: u8 = 0x0;
@@ -58,21 +54,50 @@ pub const ROL: u8 = 0xE3;
 /// Opcode of HLT
 pub const HLT: u8 = 0xF0;
 
-const MEM_HEADER: [u8; 4] = [0x03, 0x41, 0x48, 0x4D];
-const STATE_HEADER: [u8; 4] = [0x04, 0x41, 0x48, 0x4D];
+/// Header of version 4 `.state` files, which did not save the V flag.
+pub const STATE_HEADER_V4: [u8; 4] = [0x04, 0x41, 0x48, 0x4D];
 
-pub fn is_mem_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "mem")
+/// Registers of Ahmes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Ac,
+    Pc,
 }
 
-pub fn is_state_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "state")
+impl machine::Register for Register {
+    const ALL: &'static [Self] = &[Register::Ac, Register::Pc];
+
+    fn name(self) -> &'static str {
+        match self {
+            Register::Ac => "ac",
+            Register::Pc => "pc",
+        }
+    }
+}
+
+/// Flags of Ahmes. Negative and zero are computed from the accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    N,
+    Z,
+    V,
+    C,
+    B,
+}
+
+impl machine::Flag for Flag {
+    const ALL: &'static [Self] =
+        &[Flag::N, Flag::Z, Flag::V, Flag::C, Flag::B];
+
+    fn name(self) -> &'static str {
+        match self {
+            Flag::N => "n",
+            Flag::Z => "z",
+            Flag::V => "v",
+            Flag::C => "c",
+            Flag::B => "b",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -113,18 +138,13 @@ impl InstrInfo {
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Machine {
-    ri: u8,
-    pc: u8,
+    core: Core,
     ac: u8,
     overflow: bool,
     carry: bool,
     borrow: bool,
-    mem: [u8; 256],
-    cycling: bool,
-    cycles: u64,
-    accesses: u64,
 }
 
 impl Machine {
@@ -132,89 +152,21 @@ impl Machine {
         Self::default()
     }
 
-    pub fn read(&mut self, addr: u8) -> u8 {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize]
-    }
-
-    pub fn write(&mut self, addr: u8, byte: u8) {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize] = byte;
-    }
-
-    pub fn set_pc(&mut self, data: u8) {
-        self.pc = data;
-        self.cycles = 0;
-        self.accesses = 0;
-    }
-
-    pub fn write_raw(&mut self, addr: u8, data: u8) {
-        self.mem[addr as usize] = data;
-    }
-
-    pub fn cycle(&mut self) {
-        self.cycles += 1;
-        self.fetch();
-        self.decode_exec();
-    }
-
-    pub fn execute(&mut self) {
-        self.cycling = true;
-        while self.cycling {
-            self.cycle();
-        }
-    }
-
-    pub fn fetch(&mut self) {
-        self.ri = self.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-    }
-
-    pub fn decode_exec(&mut self) {
-        match (self.ri & 0xF0, self.ri & 0xFC, self.ri) {
-            (NOP, ..) => self.exec_nop(),
-            (STA, ..) => self.exec_sta(),
-            (LDA, ..) => self.exec_lda(),
-            (ADD, ..) => self.exec_add(),
-            (OR, ..) => self.exec_or(),
-            (AND, ..) => self.exec_and(),
-            (NOT, ..) => self.exec_not(),
-            (SUB, ..) => self.exec_sub(),
-            (_, JMP, _) => self.exec_jmp(),
-            (_, JN, _) => self.exec_jn(),
-            (_, JP, _) => self.exec_jp(),
-            (_, JV, _) => self.exec_jv(),
-            (_, JNV, _) => self.exec_jnv(),
-            (_, JZ, _) => self.exec_jz(),
-            (_, JNZ, _) => self.exec_jnz(),
-            (_, JC, _) => self.exec_jc(),
-            (_, JNC, _) => self.exec_jnc(),
-            (_, JB, _) => self.exec_jb(),
-            (_, JNB, _) => self.exec_jnb(),
-            (_, _, SHR) => self.exec_shr(),
-            (_, _, SHL) => self.exec_shl(),
-            (_, _, ROR) => self.exec_ror(),
-            (_, _, ROL) => self.exec_rol(),
-            (HLT, ..) => self.exec_hlt(),
-            _ => (),
-        }
-    }
-
     fn exec_nop(&mut self) {}
 
     fn exec_sta(&mut self) {
         self.fetch();
-        self.write(self.ri, self.ac);
+        self.write(self.core.ri, self.ac);
     }
 
     fn exec_lda(&mut self) {
         self.fetch();
-        self.ac = self.read(self.ri);
+        self.ac = self.read(self.core.ri);
     }
 
     fn exec_add(&mut self) {
         self.fetch();
-        let operand = self.read(self.ri);
+        let operand = self.read(self.core.ri);
         let (result, carry) = self.ac.overflowing_add(operand);
         let (_, overflow) = (self.ac as i8).overflowing_add(operand as i8);
         self.ac = result;
@@ -224,12 +176,12 @@ impl Machine {
 
     fn exec_or(&mut self) {
         self.fetch();
-        self.ac |= self.read(self.ri);
+        self.ac |= self.read(self.core.ri);
     }
 
     fn exec_and(&mut self) {
         self.fetch();
-        self.ac &= self.read(self.ri);
+        self.ac &= self.read(self.core.ri);
     }
 
     fn exec_not(&mut self) {
@@ -238,7 +190,7 @@ impl Machine {
 
     fn exec_sub(&mut self) {
         self.fetch();
-        let operand = self.read(self.ri);
+        let operand = self.read(self.core.ri);
         let (result, borrow) = self.ac.overflowing_sub(operand);
         let (_, overflow) = (self.ac as i8).overflowing_sub(operand as i8);
         self.ac = result;
@@ -248,76 +200,76 @@ impl Machine {
 
     fn exec_jmp(&mut self) {
         self.fetch();
-        self.pc = self.ri;
+        self.core.pc = self.core.ri;
     }
 
     fn exec_jn(&mut self) {
         self.fetch();
         if self.ac & 0x80 != 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jp(&mut self) {
         self.fetch();
         if self.ac & 0x80 == 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jv(&mut self) {
         self.fetch();
         if self.overflow {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jnv(&mut self) {
         self.fetch();
         if !self.overflow {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jz(&mut self) {
         self.fetch();
         if self.ac == 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jnz(&mut self) {
         self.fetch();
         if self.ac != 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jc(&mut self) {
         self.fetch();
         if self.carry {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jnc(&mut self) {
         self.fetch();
         if !self.carry {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jb(&mut self) {
         self.fetch();
         if self.borrow {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jnb(&mut self) {
         self.fetch();
         if !self.borrow {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
@@ -346,290 +298,176 @@ impl Machine {
     }
 
     fn exec_hlt(&mut self) {
-        self.cycling = false;
+        self.core.cycling = false;
     }
+}
 
-    pub fn save_mem<W>(&self, mut output: W) -> Fallible<()>
-    where
-        W: Write,
-    {
-        output.write_all(&MEM_HEADER)?;
+impl machine::Machine for Machine {
+    type Register = Register;
+    type Flag = Flag;
 
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
+    const NAME: &'static str = "ahmes";
+    const TITLE: &'static str = "Ahmes";
+    const MEM_HEADER: [u8; 4] = [0x03, 0x41, 0x48, 0x4D];
+    const STATE_HEADER: [u8; 4] = [0x05, 0x41, 0x48, 0x4D];
+    const OLD_STATE_HEADERS: &'static [[u8; 4]] = &[STATE_HEADER_V4];
 
-        Ok(())
+    fn core(&self) -> &Core {
+        &self.core
     }
 
-    pub fn load_mem<R>(&mut self, mut input: R) -> Fallible<()>
-    where
-        R: Read,
-    {
-        let mut buf = [0; 4];
+    fn core_mut(&mut self) -> &mut Core {
+        &mut self.core
+    }
 
-        input.read_exact(&mut buf)?;
+    fn decode_exec(&mut self) {
+        let ri = self.core.ri;
+        match (ri & 0xF0, ri & 0xFC, ri) {
+            (NOP, ..) => self.exec_nop(),
+            (STA, ..) => self.exec_sta(),
+            (LDA, ..) => self.exec_lda(),
+            (ADD, ..) => self.exec_add(),
+            (OR, ..) => self.exec_or(),
+            (AND, ..) => self.exec_and(),
+            (NOT, ..) => self.exec_not(),
+            (SUB, ..) => self.exec_sub(),
+            (_, JMP, _) => self.exec_jmp(),
+            (_, JN, _) => self.exec_jn(),
+            (_, JP, _) => self.exec_jp(),
+            (_, JV, _) => self.exec_jv(),
+            (_, JNV, _) => self.exec_jnv(),
+            (_, JZ, _) => self.exec_jz(),
+            (_, JNZ, _) => self.exec_jnz(),
+            (_, JC, _) => self.exec_jc(),
+            (_, JNC, _) => self.exec_jnc(),
+            (_, JB, _) => self.exec_jb(),
+            (_, JNB, _) => self.exec_jnb(),
+            (_, _, SHR) => self.exec_shr(),
+            (_, _, SHL) => self.exec_shl(),
+            (_, _, ROR) => self.exec_ror(),
+            (_, _, ROL) => self.exec_rol(),
+            (HLT, ..) => self.exec_hlt(),
+            _ => (),
+        }
+    }
 
-        if buf != MEM_HEADER {
-            Err(InvalidFile)?;
+    fn register(&self, reg: Register) -> u8 {
+        match reg {
+            Register::Ac => self.ac,
+            Register::Pc => self.core.pc,
         }
+    }
 
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
+    fn set_register(&mut self, reg: Register, data: u8) {
+        match reg {
+            Register::Ac => self.ac = data,
+            Register::Pc => self.core.pc = data,
         }
+    }
 
-        Ok(())
+    fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::N => self.ac & 0x80 != 0,
+            Flag::Z => self.ac == 0,
+            Flag::V => self.overflow,
+            Flag::C => self.carry,
+            Flag::B => self.borrow,
+        }
     }
 
-    pub fn save_state<W>(&self, mut output: W) -> Fallible<()>
+    fn set_flag(&mut self, flag: Flag, value: bool) {
+        match flag {
+            Flag::N | Flag::Z => (),
+            Flag::V => self.overflow = value,
+            Flag::C => self.carry = value,
+            Flag::B => self.borrow = value,
+        }
+    }
+
+    fn save_regs<W>(&self, mut output: W) -> Fallible<()>
     where
         W: Write,
     {
-        output.write_all(&STATE_HEADER)?;
-
-        output.write_all(&[self.ri, self.pc, self.ac])?;
+        output.write_all(&[self.ac])?;
         output.write_all(&[if self.carry { 1 } else { 0 }])?;
         output.write_all(&[if self.borrow { 1 } else { 0 }])?;
-        output.write_all(&[if self.cycling { 1 } else { 0 }])?;
-        output.write_all(&self.cycles.to_le_bytes())?;
-        output.write_all(&self.accesses.to_le_bytes())?;
-
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
-
+        output.write_all(&[if self.overflow { 1 } else { 0 }])?;
         Ok(())
     }
 
-    pub fn load_state<R>(&mut self, mut input: R) -> Fallible<()>
+    fn load_regs<R>(&mut self, mut input: R) -> Fallible<()>
     where
         R: Read,
     {
-        let mut buf = [0; 8];
-
-        input.read_exact(&mut buf)?;
-
-        if &buf[.. 4] != &STATE_HEADER {
-            Err(InvalidFile)?;
-        }
-
-        self.ri = buf[4];
-        self.pc = buf[5];
-        self.ac = buf[6];
-        self.carry = buf[7] != 0;
-        input.read_exact(&mut buf[.. 2])?;
-        self.borrow = buf[0] != 0;
-        self.cycling = buf[1] != 0;
-
-        input.read_exact(&mut buf)?;
-        self.cycles = u64::from_le_bytes(buf);
-
+        let mut buf = [0; 4];
         input.read_exact(&mut buf)?;
-        self.accesses = u64::from_le_bytes(buf);
-
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
-        }
-
+        self.ac = buf[0];
+        self.carry = buf[1] != 0;
+        self.borrow = buf[2] != 0;
+        self.overflow = buf[3] != 0;
         Ok(())
     }
 
-    pub fn save_at_path<P>(&self, path: &P) -> Fallible<()>
-    where
-        P: AsRef<Path> + ?Sized,
-    {
-        let file = File::create(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.save_mem(file)
-        } else if is_state_file(path) {
-            self.save_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
-
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
-        })
-    }
-
-    pub fn load_from_path<P>(&mut self, path: &P) -> Fallible<()>
-    where
-        P: AsRef<Path> + ?Sized,
-    {
-        let file = File::open(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.load_mem(file)
-        } else if is_state_file(path) {
-            self.load_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
-
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
-        })
-    }
-
-    pub fn display_mem_data<W, B>(
+    /// Version 4 did not save the V flag, so it is left cleared.
+    fn load_old_regs<R>(
         &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
+        _header: [u8; 4],
+        mut input: R,
     ) -> Fallible<()>
     where
-        B: IntoIterator<Item = u8>,
-        W: Write,
+        R: Read,
     {
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(
-                    output,
-                    "{:03} = {:03}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            }
-        }
-
+        let mut buf = [0; 3];
+        input.read_exact(&mut buf)?;
+        self.ac = buf[0];
+        self.carry = buf[1] != 0;
+        self.borrow = buf[2] != 0;
+        self.overflow = false;
         Ok(())
     }
 
-    pub fn display_mem_opcodes<W, B>(
-        &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        B: IntoIterator<Item = u8>,
-        W: Write,
-    {
-        let mut needs_operand = false;
-
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(output, "{:03} = {:03}", addr, self.mem[addr as usize])?
-            }
-
-            if needs_operand {
-                needs_operand = false;
-            } else {
-                if let Some(info) = InstrInfo::new(self.mem[addr as usize]) {
-                    needs_operand = info.operand;
-                    write!(output, "  {}", info.mnemonic)?
-                }
-            }
-
-            write!(output, "\n")?;
-        }
-
-        Ok(())
+    fn instr_info(opcode: u8) -> Option<machine::InstrInfo> {
+        InstrInfo::new(opcode).map(|info| machine::InstrInfo {
+            mnemonic: info.mnemonic,
+            operand: info.operand,
+        })
     }
 
-    pub fn display_registers<W>(
-        &mut self,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        W: Write,
-    {
-        let flag_n = self.ac >> 7;
-        let flag_z = if self.ac == 0 { 1 } else { 0 };
-        let flag_v = if self.overflow { 1 } else { 0 };
-        let flag_c = if self.carry { 1 } else { 0 };
-        let flag_b = if self.borrow { 1 } else { 0 };
-
-        if hex {
-            write!(output, "ac = {:02X}\n", self.ac)?;
-            write!(output, "pc = {:02X}\n", self.pc)?;
-            write!(output, "n  = {:02X}\n", flag_n)?;
-            write!(output, "z  = {:02X}\n", flag_z)?;
-            write!(output, "v  = {:02X}\n", flag_v)?;
-            write!(output, "c  = {:02X}\n", flag_c)?;
-            write!(output, "b  = {:02X}\n", flag_b)?;
-        } else {
-            write!(output, "ac = {:03}\n", self.ac)?;
-            write!(output, "pc = {:03}\n", self.pc)?;
-            write!(output, "n  = {:03}\n", flag_n)?;
-            write!(output, "z  = {:03}\n", flag_z)?;
-            write!(output, "v  = {:02X}\n", flag_v)?;
-            write!(output, "c  = {:02X}\n", flag_c)?;
-            write!(output, "b  = {:02X}\n", flag_b)?;
-        }
-
-        Ok(())
+    fn jump_taken(
+        instr: &machine::Instr,
+        before: &machine::Regs<Self>,
+    ) -> Option<bool> {
+        let (flag, value) = match instr.opcode & 0xFC {
+            JN => (Flag::N, true),
+            JP => (Flag::N, false),
+            JV => (Flag::V, true),
+            JNV => (Flag::V, false),
+            JZ => (Flag::Z, true),
+            JNZ => (Flag::Z, false),
+            JC => (Flag::C, true),
+            JNC => (Flag::C, false),
+            JB => (Flag::B, true),
+            JNB => (Flag::B, false),
+            _ => return None,
+        };
+        Some(before.flag(flag) == value)
     }
 
-    pub fn display_stats<W>(&mut self, mut output: W) -> Fallible<()>
+    fn debug_mnemonic<W>(
+        mut output: W,
+        instruction: u8,
+        _operand: u8,
+        _hex: bool,
+    ) -> Fallible<bool>
     where
         W: Write,
     {
-        write!(output, "cycles = {}\n", self.cycles)?;
-        write!(output, "accesses = {}\n", self.accesses)?;
-
-        Ok(())
-    }
-}
-
-impl fmt::Debug for Machine {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("neander::Machine")
-            .field("ri", &self.ri)
-            .field("pc", &self.pc)
-            .field("ac", &self.ac)
-            .field("mem", &(&self.mem as &[u8]))
-            .field("cycling", &self.cycling)
-            .field("cycles", &self.cycles)
-            .field("accesses", &self.accesses)
-            .finish()
-    }
-}
-
-impl Default for Machine {
-    fn default() -> Self {
-        Self {
-            ri: 0,
-            pc: 0,
-            ac: 0,
-            overflow: false,
-            carry: false,
-            borrow: false,
-            mem: [0; 256],
-            cycling: false,
-            cycles: 0,
-            accesses: 0,
+        match InstrInfo::new(instruction) {
+            Some(info) => {
+                write!(output, "  {}", info.mnemonic)?;
+                Ok(info.operand)
+            },
+            None => Ok(false),
         }
     }
 }
-
-impl PartialEq for Machine {
-    fn eq(&self, other: &Self) -> bool {
-        self.ri == other.ri
-            && self.pc == other.pc
-            && self.ac == other.ac
-            && self.overflow == other.overflow
-            && self.carry == other.carry
-            && self.cycling == other.cycling
-            && self.cycles == other.cycles
-            && self.accesses == other.accesses
-            && &self.mem as &[u8] == &other.mem as &[u8]
-    }
-}
-
-impl Eq for Machine {}
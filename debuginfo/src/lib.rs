@@ -0,0 +1,214 @@
+#[cfg(test)]
+mod test;
+
+use error::{Fallible, InvalidFile, WithPath};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// Extension of the debug info sidecar file, placed next to the `.mem` file.
+/// No assembler emits it yet, since daedalus only has a lexer, so it has to
+/// be written by hand or by another tool.
+pub const EXTENSION: &str = "dbg";
+
+/// Returns the path of the debug info sidecar of the given memory or state
+/// file.
+pub fn sidecar_path<P>(path: &P) -> PathBuf
+where
+    P: AsRef<Path> + ?Sized,
+{
+    path.as_ref().with_extension(EXTENSION)
+}
+
+/// Where a given memory address came from in the source code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrcLine {
+    /// Source file, relative to the sidecar file.
+    pub file: PathBuf,
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Label enclosing this line, if any.
+    pub label: Option<String>,
+}
+
+impl fmt::Display for SrcLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}:{}", self.file.display(), self.line)?;
+        if let Some(label) = &self.label {
+            write!(fmt, " ({})", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mapping from memory addresses to source lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Source line of each mapped address.
+    lines: BTreeMap<u8, SrcLine>,
+    /// Contents of the source files, when available.
+    sources: HashMap<PathBuf, Vec<String>>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps an address to the given source line.
+    pub fn insert(&mut self, addr: u8, src_line: SrcLine) {
+        self.lines.insert(addr, src_line);
+    }
+
+    /// Mapped addresses and their source lines, in order of address.
+    pub fn lines(&self) -> impl Iterator<Item = (u8, &SrcLine)> + '_ {
+        self.lines.iter().map(|(&addr, src_line)| (addr, src_line))
+    }
+
+    /// Source line of the given address, if mapped.
+    pub fn get(&self, addr: u8) -> Option<&SrcLine> {
+        self.lines.get(&addr)
+    }
+
+    /// Address of the given label: the first address it encloses.
+    pub fn label_addr(&self, label: &str) -> Option<u8> {
+        self.lines
+            .iter()
+            .find(|(_, src_line)| src_line.label.as_deref() == Some(label))
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Address of the given source line: the first address it encloses. The
+    /// file matches if it ends with the file recorded in the debug info.
+    pub fn line_addr<P>(&self, file: &P, line: usize) -> Option<u8>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        self.lines
+            .iter()
+            .find(|(_, src_line)| {
+                src_line.line == line && file.as_ref().ends_with(&src_line.file)
+            })
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Text of the source line of the given address, if both the address is
+    /// mapped and the source file could be read.
+    pub fn source_text(&self, addr: u8) -> Option<&str> {
+        let src_line = self.get(addr)?;
+        let source = self.sources.get(&src_line.file)?;
+        source.get(src_line.line.checked_sub(1)?).map(|text| text.trim())
+    }
+
+    /// Describes the source of the given address, such as
+    /// `prog.asm:3 (LOOP)  ADD 81h`.
+    pub fn describe(&self, addr: u8) -> Option<String> {
+        let src_line = self.get(addr)?;
+        Some(match self.source_text(addr) {
+            Some(text) => format!("{}  {}", src_line, text),
+            None => src_line.to_string(),
+        })
+    }
+
+    pub fn save<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        for (addr, src_line) in &self.lines {
+            writeln!(
+                output,
+                "{:02X}\t{}\t{}\t{}",
+                addr,
+                src_line.line,
+                src_line.label.as_ref().map_or("", |label| &**label),
+                src_line.file.display(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load<R>(input: R) -> Fallible<Self>
+    where
+        R: BufRead,
+    {
+        let mut this = Self::new();
+
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.splitn(4, '\t');
+            let mut next_field = || fields.next().ok_or(InvalidFile);
+
+            let addr = u8::from_str_radix(next_field()?, 16)?;
+            let line = next_field()?.parse()?;
+            let label = Some(next_field()?)
+                .filter(|label| !label.is_empty())
+                .map(String::from);
+            let file = PathBuf::from(next_field()?);
+
+            this.insert(addr, SrcLine { file, line, label });
+        }
+
+        Ok(this)
+    }
+
+    pub fn save_at_path<P>(&self, path: &P) -> Fallible<()>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        let file = File::create(path.as_ref())?;
+
+        self.save(file).map_err(|error| {
+            WithPath { path: path.as_ref().into(), error }.into()
+        })
+    }
+
+    /// Loads the debug info at the given path, and then the source files it
+    /// refers to. Source files that cannot be read are ignored.
+    pub fn load_from_path<P>(path: &P) -> Fallible<Self>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        let file = File::open(path.as_ref())?;
+
+        let mut this = Self::load(BufReader::new(file)).map_err(|error| {
+            WithPath { path: path.as_ref().into(), error }
+        })?;
+
+        let base = path.as_ref().parent().unwrap_or(Path::new(""));
+        for src_line in this.lines.values() {
+            if this.sources.contains_key(&src_line.file) {
+                continue;
+            }
+            if let Ok(contents) = fs::read_to_string(base.join(&src_line.file))
+            {
+                let source = contents.lines().map(String::from).collect();
+                this.sources.insert(src_line.file.clone(), source);
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Loads the debug info sidecar of the given memory or state file, if it
+    /// exists.
+    pub fn load_sidecar<P>(path: &P) -> Fallible<Option<Self>>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        let sidecar = sidecar_path(path);
+        if sidecar.is_file() {
+            Self::load_from_path(&sidecar).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use super::*;
+
+fn sample() -> DebugInfo {
+    let mut info = DebugInfo::new();
+    info.insert(
+        0x0,
+        SrcLine { file: "prog.asm".into(), line: 3, label: None },
+    );
+    info.insert(
+        0x2,
+        SrcLine {
+            file: "prog.asm".into(),
+            line: 5,
+            label: Some("LOOP".into()),
+        },
+    );
+    info
+}
+
+#[test]
+fn save_load() {
+    let info = sample();
+    let mut buf = Vec::new();
+    info.save(&mut buf).unwrap();
+    let info2 = DebugInfo::load(&buf[..]).unwrap();
+    assert_eq!(info, info2);
+}
+
+#[test]
+fn load_invalid() {
+    assert!(DebugInfo::load(&b"00\t3\n"[..]).is_err());
+    assert!(DebugInfo::load(&b"zz\t3\t\tprog.asm\n"[..]).is_err());
+}
+
+#[test]
+fn describe() {
+    let mut info = sample();
+    info.sources.insert(
+        "prog.asm".into(),
+        vec!["".into(), "".into(), "    LDA 80h".into()],
+    );
+    assert_eq!(info.describe(0x0).unwrap(), "prog.asm:3  LDA 80h");
+    assert_eq!(info.describe(0x2).unwrap(), "prog.asm:5 (LOOP)");
+    assert_eq!(info.describe(0x1), None);
+}
+
+#[test]
+fn label_addr() {
+    let mut info = sample();
+    info.insert(
+        0x4,
+        SrcLine {
+            file: "prog.asm".into(),
+            line: 6,
+            label: Some("LOOP".into()),
+        },
+    );
+    assert_eq!(info.label_addr("LOOP"), Some(0x2));
+    assert_eq!(info.label_addr("END"), None);
+}
+
+#[test]
+fn line_addr() {
+    let info = sample();
+    assert_eq!(info.line_addr("prog.asm", 5), Some(0x2));
+    assert_eq!(info.line_addr("/home/user/prog.asm", 3), Some(0x0));
+    assert_eq!(info.line_addr("prog.asm", 4), None);
+    assert_eq!(info.line_addr("other.asm", 5), None);
+}
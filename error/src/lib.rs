@@ -18,3 +18,203 @@ impl fmt::Display for WithPath {
 #[derive(Debug, Failure)]
 #[fail(display = "Invalid or corrupted file")]
 pub struct InvalidFile;
+
+/// Formats a file header as hex bytes, such as `03 4E 44 52`.
+struct Header<'header>(&'header [u8; 4]);
+
+impl<'header> fmt::Display for Header<'header> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(fmt, "{:02X} {:02X} {:02X} {:02X}", a, b, c, d)
+    }
+}
+
+#[derive(Debug, Failure)]
+pub struct WrongHeader {
+    pub machine: &'static str,
+    pub header: [u8; 4],
+}
+
+impl fmt::Display for WrongHeader {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Header {} is not of a {} memory or state file",
+            Header(&self.header),
+            self.machine
+        )
+    }
+}
+
+#[derive(Debug, Failure)]
+pub struct UnknownHeader {
+    pub header: [u8; 4],
+}
+
+impl fmt::Display for UnknownHeader {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Header {} is not of a memory or state file of any known machine",
+            Header(&self.header)
+        )
+    }
+}
+
+#[derive(Debug, Failure)]
+#[fail(
+    display = "File extension is .{}, but its header is of a {} file",
+    extension, kind
+)]
+pub struct ExtensionMismatch {
+    pub extension: &'static str,
+    pub kind: &'static str,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Unknown file extension, expected .mem or .state")]
+pub struct UnknownExtension;
+
+#[derive(Debug, Failure)]
+pub struct UnknownMachine {
+    pub name: String,
+    pub known: Vec<&'static str>,
+}
+
+impl fmt::Display for UnknownMachine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Unknown machine {}, expected one of: {}",
+            self.name,
+            self.known.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Failure)]
+pub struct MissingMachine {
+    pub known: Vec<&'static str>,
+}
+
+impl fmt::Display for MissingMachine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "A machine must be given with -m, one of: {}",
+            self.known.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Failure)]
+#[fail(
+    display = "Stopped after {} cycles without HLT, the program may be in an \
+               infinite loop (state saved, use --max-cycles, or -n with \
+               step, to raise the limit)",
+    max_cycles
+)]
+pub struct CycleLimit {
+    pub max_cycles: u64,
+}
+
+#[derive(Debug, Failure)]
+#[fail(
+    display = "Infinite loop: the machine state repeats every {} cycles from \
+               pc = {:02X}h (state saved)",
+    period, entry
+)]
+pub struct InfiniteLoop {
+    pub entry: u8,
+    pub period: u64,
+}
+
+#[derive(Debug, Failure)]
+pub struct InvalidAddress {
+    pub spec: String,
+    pub has_debug_info: bool,
+}
+
+impl fmt::Display for InvalidAddress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.has_debug_info {
+            write!(fmt, "{} is neither an address nor a known label", self.spec)
+        } else {
+            write!(
+                fmt,
+                "{} is not an address, and labels need debug info (-g)",
+                self.spec
+            )
+        }
+    }
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Invalid expression {}: {}", expr, message)]
+pub struct InvalidExpr {
+    pub expr: String,
+    pub message: String,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Invariant broken: {} (state saved)", invariant)]
+pub struct InvariantBroken {
+    pub invariant: String,
+}
+
+#[derive(Debug, Failure)]
+#[fail(
+    display = "Self-modifying code: the instruction at {:02X}h wrote into \
+               code at {:02X}h, now {} (state saved)",
+    pc, addr, instr
+)]
+pub struct SelfModifyingCode {
+    pub pc: u8,
+    pub addr: u8,
+    /// Text of the overwritten instruction, as it now reads.
+    pub instr: String,
+}
+
+#[derive(Debug, Failure)]
+#[fail(
+    display = "Undefined instruction {:02X}h at pc = {:02X}h (state saved)",
+    opcode, pc
+)]
+pub struct UndefinedInstr {
+    pub opcode: u8,
+    pub pc: u8,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Unknown command {}, try help", name)]
+pub struct UnknownCommand {
+    pub name: String,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Unknown register {}", name)]
+pub struct UnknownRegister {
+    pub name: String,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Usage: {}", usage)]
+pub struct CommandUsage {
+    pub usage: &'static str,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "No cycles were recorded, step or run with --record first")]
+pub struct NotRecorded;
+
+#[derive(Debug, Failure)]
+#[fail(display = "The programs diverge at cycle {}", cycle)]
+pub struct Diverged {
+    pub cycle: u64,
+}
+
+#[derive(Debug, Failure)]
+#[fail(display = "Invalid request: {}", message)]
+pub struct InvalidRequest {
+    pub message: String,
+}
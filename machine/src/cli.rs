@@ -0,0 +1,1353 @@
+use crate::{
+    coverage::CoverageFormat,
+    dap::DapServer,
+    debugger::Debugger,
+    diff::{self, Comparison, DiffOptions},
+    gdb::GdbStub,
+    history::WriteEvent,
+    trace::{TraceFormat, Tracer},
+    tui::Tui,
+    Coverage,
+    Expr,
+    History,
+    Instr,
+    Machine,
+    Profile,
+    Register as _,
+    SmcAllow,
+    StopReason,
+    WatchKind,
+    Watchpoint,
+};
+use debuginfo::DebugInfo;
+use error::{
+    CycleLimit,
+    Diverged,
+    Error,
+    Fallible,
+    InfiniteLoop,
+    InvalidAddress,
+    InvariantBroken,
+    NotRecorded,
+    SelfModifyingCode,
+    UndefinedInstr,
+    UnknownMachine,
+    UnknownRegister,
+    WithPath,
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+};
+use structopt::StructOpt;
+
+/// Cycles run when no limit is given: the default `--max-cycles` of `run`
+/// and `diff`, and the limit of `step --until-break` and of continuing in
+/// the debuggers.
+pub const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+
+/// Cycle limit of continuing in the debuggers: the machine's own limit, but
+/// no more than `DEFAULT_MAX_CYCLES`, so that a program that never halts
+/// gives control back.
+pub fn continue_limit(max_cycles: Option<u64>) -> u64 {
+    max_cycles.map_or(DEFAULT_MAX_CYCLES, |max| max.min(DEFAULT_MAX_CYCLES))
+}
+
+/// Exit code when `run` or `step --until-break` reaches its cycle limit.
+pub const EXIT_CYCLE_LIMIT: i32 = 2;
+/// Exit code when `run` detects an infinite loop.
+pub const EXIT_INFINITE_LOOP: i32 = 3;
+/// Exit code when `run` breaks an invariant.
+pub const EXIT_INVARIANT: i32 = 4;
+/// Exit code when `diff` finds a divergence.
+pub const EXIT_DIVERGED: i32 = 5;
+/// Exit code when `run` detects self-modifying code.
+pub const EXIT_SELF_MODIFYING: i32 = 6;
+/// Exit code when a strict `run` or `step` reaches an undefined instruction.
+pub const EXIT_UNDEFINED: i32 = 7;
+
+/// Subcommands of the simulators.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Creates a new zeroed memory or a zeroed state
+    #[structopt(name = "new")]
+    New {
+        #[structopt(short = "o", parse(from_os_str))]
+        output: PathBuf,
+        /// Machine of the file, needed only by niahu
+        #[structopt(short = "m")]
+        machine: Option<String>,
+    },
+
+    /// Writes a byte into Program Counter register
+    #[structopt(name = "write")]
+    Write {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+        #[structopt(short = "x")]
+        hex: bool,
+        #[structopt(short = "a")]
+        addr: String,
+        #[structopt(short = "d")]
+        data: String,
+    },
+
+    /// Writes a byte into a given address
+    #[structopt(name = "setpc")]
+    SetPc {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+        #[structopt(short = "x")]
+        hex: bool,
+        #[structopt(short = "d")]
+        data: String,
+    },
+
+    /// Runs the code in a machine until HLT is found
+    #[structopt(name = "run")]
+    Run {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Maximum number of cycles before giving up, 1000000 by default, 0
+        /// for no limit
+        #[structopt(long = "max-cycles")]
+        max_cycles: Option<u64>,
+        /// Stops as soon as the machine state repeats (an infinite loop)
+        #[structopt(long = "detect-loops")]
+        detect_loops: bool,
+        #[structopt(flatten)]
+        records: RecordArgs,
+        #[structopt(flatten)]
+        stops: StopArgs,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Runs only a few steps of the code in a machine
+    #[structopt(name = "step")]
+    Step {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+        #[structopt(flatten)]
+        step: StepArgs,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Opens an interactive debugger on a machine
+    #[structopt(name = "debug")]
+    Debug {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "x")]
+        hex: bool,
+        /// Stops before undefined instructions, as run --strict does
+        #[structopt(long = "strict")]
+        strict: bool,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Opens a full-screen terminal interface on a machine
+    #[structopt(name = "tui")]
+    Tui {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        /// Stops before undefined instructions, as run --strict does
+        #[structopt(long = "strict")]
+        strict: bool,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Serves the GDB remote serial protocol on a local port or socket
+    #[structopt(name = "gdb")]
+    Gdb {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        /// Where the state is saved when the client detaches
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Local TCP port to listen on
+        #[structopt(long = "port", default_value = "1234")]
+        port: u16,
+        /// Unix socket to listen on instead of a TCP port
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+        /// Stops before undefined instructions, as run --strict does
+        #[structopt(long = "strict")]
+        strict: bool,
+    },
+
+    /// Serves the Debug Adapter Protocol on the standard input and output,
+    /// for editors such as VS Code; the program is given by the launch
+    /// request
+    #[structopt(name = "dap")]
+    Dap {
+        /// Machine of the programs, needed only by niahu
+        #[structopt(short = "m")]
+        machine: Option<String>,
+        /// Stops before undefined instructions, as run --strict does
+        #[structopt(long = "strict")]
+        strict: bool,
+    },
+
+    /// Runs a machine and a reference in lockstep, showing the first cycle
+    /// where they diverge
+    #[structopt(name = "diff")]
+    Diff {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        /// Reference memory or state to compare against
+        #[structopt(short = "r", parse(from_os_str))]
+        reference: PathBuf,
+        /// Copies this address range (such as 80h..8Fh) from the reference
+        /// before running, so both start from the same inputs
+        #[structopt(long = "inputs", number_of_values = 1)]
+        inputs: Vec<String>,
+        /// Does not compare writes to this address range, such as code or
+        /// scratch areas
+        #[structopt(long = "ignore", number_of_values = 1)]
+        ignore: Vec<String>,
+        /// Does not compare this register
+        #[structopt(long = "ignore-reg", number_of_values = 1)]
+        ignore_reg: Vec<String>,
+        /// Does not compare the program counter, the same as --ignore-reg pc
+        #[structopt(long = "ignore-pc")]
+        ignore_pc: bool,
+        /// Number of cycles shown before the divergence
+        #[structopt(long = "context", default_value = "5")]
+        context: usize,
+        /// Maximum number of cycles compared, 1000000 by default
+        #[structopt(long = "max-cycles")]
+        max_cycles: Option<u64>,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Answers queries over the cycles recorded in a machine (.state)
+    #[structopt(name = "history")]
+    History {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        /// Shows every write to this address or label
+        #[structopt(long = "writes", number_of_values = 1)]
+        writes: Vec<String>,
+        /// Shows the last write to this address or label, and its pc
+        #[structopt(long = "last-write", number_of_values = 1)]
+        last_writes: Vec<String>,
+        /// Shows every value this expression held, such as ac or mem[82h]
+        #[structopt(long = "values", number_of_values = 1)]
+        values: Vec<String>,
+        /// Shows the first cycle after which this expression became true,
+        /// such as z
+        #[structopt(long = "became", number_of_values = 1)]
+        became: Vec<String>,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Shows a given range of memory data of a machine
+    #[structopt(name = "data")]
+    Data {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "x")]
+        hex: bool,
+        #[structopt(short = "s")]
+        start: Option<String>,
+        #[structopt(short = "e")]
+        end: Option<String>,
+    },
+
+    /// Shows a range of memory data with mnemonics in a machine.
+    #[structopt(name = "code")]
+    Code {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "x")]
+        hex: bool,
+        #[structopt(short = "s")]
+        start: Option<String>,
+        #[structopt(short = "e")]
+        end: Option<String>,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Shows register data of a machine (.state)
+    #[structopt(name = "registers")]
+    Regs {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(short = "x")]
+        hex: bool,
+        #[structopt(short = "g", parse(from_os_str))]
+        debug_info: Option<PathBuf>,
+    },
+
+    /// Shows statistics of code being run in a machine (.state)
+    #[structopt(name = "stats")]
+    Stats {
+        #[structopt(short = "i", parse(from_os_str))]
+        input: PathBuf,
+    },
+}
+
+/// Options of how `step` runs.
+#[derive(Debug, StructOpt)]
+pub struct StepArgs {
+    /// Number of steps, 1 by default, or the maximum number of steps with
+    /// --until-break, 1000000 by default
+    #[structopt(short = "n")]
+    pub steps: Option<u64>,
+    /// Keeps stepping until a breakpoint or HLT is reached
+    #[structopt(long = "until-break")]
+    pub until_break: bool,
+    /// Steps backwards through recorded cycles; with --until-break, back to
+    /// the previous breakpoint, watchpoint hit or condition
+    #[structopt(long = "back")]
+    pub back: bool,
+    /// Records the cycles stepped, so --back can undo them
+    #[structopt(long = "record")]
+    pub record: bool,
+    /// Stops before undefined instructions, as run --strict does
+    #[structopt(long = "strict")]
+    pub strict: bool,
+}
+
+/// Options that record an execution.
+#[derive(Debug, StructOpt)]
+pub struct RecordArgs {
+    /// Records the cycles run, so `step --back` can undo them
+    #[structopt(long = "record")]
+    pub record: bool,
+    /// Writes every instruction executed into this file, as CSV for .csv,
+    /// JSON Lines for .jsonl or .json, and text otherwise
+    #[structopt(long = "trace", parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+    /// Shows a profile after running: instructions executed per mnemonic
+    /// and address, conditional jumps taken, data accesses per address and
+    /// the hottest loops
+    #[structopt(long = "profile")]
+    pub profile: bool,
+    /// Writes which addresses were executed, read, written or never touched
+    /// into this file, as JSON for .json, a PPM heatmap for .ppm, and a text
+    /// grid otherwise
+    #[structopt(long = "coverage", parse(from_os_str))]
+    pub coverage: Option<PathBuf>,
+}
+
+/// Options that stop an execution before it halts.
+#[derive(Debug, StructOpt)]
+pub struct StopArgs {
+    /// Stops before fetching at this address (such as 0Ah) or label,
+    /// saved in the state for later runs
+    #[structopt(long = "break", number_of_values = 1)]
+    pub breakpoints: Vec<String>,
+    /// Stops after an instruction reads this address or range (such as
+    /// 80h..8Fh), saved in the state for later runs
+    #[structopt(long = "watch-read", number_of_values = 1)]
+    pub watch_read: Vec<String>,
+    /// Stops after an instruction writes this address or range, saved in
+    /// the state for later runs
+    #[structopt(long = "watch-write", number_of_values = 1)]
+    pub watch_write: Vec<String>,
+    /// Stops after an instruction changes the value of this address or
+    /// range, saved in the state for later runs
+    #[structopt(long = "watch-change", number_of_values = 1)]
+    pub watch_change: Vec<String>,
+    /// Stops before fetching when this condition holds, such as
+    /// "pc == LOOP && mem[80h] > 10", saved in the state for later runs
+    #[structopt(long = "break-if", number_of_values = 1)]
+    pub conditions: Vec<String>,
+    /// Stops after a cycle that makes this condition false, such as
+    /// "mem[80h] unchanged", saved in the state for later runs
+    #[structopt(long = "invariant", number_of_values = 1)]
+    pub invariants: Vec<String>,
+    /// Stops after an instruction writes into memory fetched before as an
+    /// opcode or operand (self-modifying code)
+    #[structopt(long = "detect-smc")]
+    pub detect_smc: bool,
+    /// Allows writes into code at this address, range or label, or by
+    /// instructions with this mnemonic (such as JSR), with --detect-smc
+    #[structopt(long = "allow-smc", number_of_values = 1)]
+    pub allow_smc: Vec<String>,
+    /// Stops before undefined instructions, and before Ramses instructions
+    /// naming register 3, instead of running them as the official
+    /// simulators do
+    #[structopt(long = "strict")]
+    pub strict: bool,
+}
+
+impl StopArgs {
+    /// Tests whether no option was given.
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+            && self.watch_read.is_empty()
+            && self.watch_write.is_empty()
+            && self.watch_change.is_empty()
+            && self.conditions.is_empty()
+            && self.invariants.is_empty()
+            && !self.detect_smc
+            && !self.strict
+    }
+
+    /// Adds the given breakpoints, watchpoints, conditions and invariants to
+    /// the machine, and enables detecting self-modifying code and strict
+    /// execution if asked, resolving labels with the debug info.
+    pub fn apply<M>(
+        &self,
+        vm: &mut M,
+        debug_info: Option<&DebugInfo>,
+    ) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        for spec in &self.breakpoints {
+            vm.add_breakpoint(parse_addr(spec, debug_info)?);
+        }
+
+        let watchpoints = [
+            (WatchKind::Read, &self.watch_read),
+            (WatchKind::Write, &self.watch_write),
+            (WatchKind::Change, &self.watch_change),
+        ];
+        for &(kind, specs) in &watchpoints {
+            for spec in specs {
+                let (start, end) = parse_range(spec, debug_info)?;
+                vm.add_watchpoint(Watchpoint { start, end, kind });
+            }
+        }
+
+        for src in &self.conditions {
+            vm.add_condition(Expr::parse::<M>(src, debug_info)?);
+        }
+        for src in &self.invariants {
+            vm.add_invariant(Expr::parse::<M>(src, debug_info)?);
+        }
+
+        if self.detect_smc {
+            let allow = self
+                .allow_smc
+                .iter()
+                .map(|spec| parse_smc_allow::<M>(spec, debug_info))
+                .collect::<Fallible<_>>()?;
+            vm.set_smc_detection(Some(allow));
+        }
+        if self.strict {
+            vm.set_strict(true);
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the simulator command line for the given machine, exiting the process
+/// on error.
+pub fn main<M>()
+where
+    M: Machine,
+{
+    if let Err(e) = try_main::<M>() {
+        eprintln!("{}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// Exit code of the process for the given error.
+pub fn exit_code(error: &Error) -> i32 {
+    if error.downcast_ref::<CycleLimit>().is_some() {
+        EXIT_CYCLE_LIMIT
+    } else if error.downcast_ref::<InfiniteLoop>().is_some() {
+        EXIT_INFINITE_LOOP
+    } else if error.downcast_ref::<InvariantBroken>().is_some() {
+        EXIT_INVARIANT
+    } else if error.downcast_ref::<Diverged>().is_some() {
+        EXIT_DIVERGED
+    } else if error.downcast_ref::<SelfModifyingCode>().is_some() {
+        EXIT_SELF_MODIFYING
+    } else if error.downcast_ref::<UndefinedInstr>().is_some() {
+        EXIT_UNDEFINED
+    } else {
+        -1
+    }
+}
+
+fn try_main<M>() -> Fallible<()>
+where
+    M: Machine,
+{
+    let about =
+        format!("Multiplatform Simulator of {} Hypothetical Machine", M::TITLE);
+    let matches = Command::clap().name(M::NAME).about(&*about).get_matches();
+
+    run::<M>(Command::from_clap(&matches))
+}
+
+impl Command {
+    /// Input file of this command, if any.
+    pub fn input(&self) -> Option<&Path> {
+        match self {
+            Command::New { .. } | Command::Dap { .. } => None,
+            Command::Write { input, .. }
+            | Command::SetPc { input, .. }
+            | Command::Run { input, .. }
+            | Command::Step { input, .. }
+            | Command::Debug { input, .. }
+            | Command::Tui { input, .. }
+            | Command::Gdb { input, .. }
+            | Command::Diff { input, .. }
+            | Command::History { input, .. }
+            | Command::Data { input, .. }
+            | Command::Code { input, .. }
+            | Command::Regs { input, .. }
+            | Command::Stats { input } => Some(input),
+        }
+    }
+}
+
+/// Runs the given command on the given machine.
+pub fn run<M>(command: Command) -> Fallible<()>
+where
+    M: Machine,
+{
+    match command {
+        Command::New { output, machine } => {
+            subcommand_new::<M>(output, machine)
+        },
+
+        Command::Write { input, output, hex, addr, data } => {
+            subcommand_write::<M>(input, output, hex, addr, data)
+        },
+
+        Command::SetPc { input, output, hex, data } => {
+            subcommand_setpc::<M>(input, output, hex, data)
+        },
+
+        Command::Run {
+            input,
+            output,
+            max_cycles,
+            detect_loops,
+            records,
+            stops,
+            debug_info,
+        } => subcommand_run::<M>(
+            input,
+            output,
+            max_cycles.unwrap_or(DEFAULT_MAX_CYCLES),
+            detect_loops,
+            records,
+            stops,
+            debug_info,
+        ),
+
+        Command::Step { input, output, step, debug_info } => {
+            subcommand_step::<M>(input, output, step, debug_info)
+        },
+
+        Command::Debug { input, hex, strict, debug_info } => {
+            subcommand_debug::<M>(input, hex, strict, debug_info)
+        },
+
+        Command::Tui { input, strict, debug_info } => {
+            subcommand_tui::<M>(input, strict, debug_info)
+        },
+
+        Command::Gdb { input, output, port, socket, strict } => {
+            subcommand_gdb::<M>(input, output, port, socket, strict)
+        },
+
+        Command::Dap { strict, .. } => subcommand_dap::<M>(strict),
+
+        Command::Diff {
+            input,
+            reference,
+            inputs,
+            ignore,
+            ignore_reg,
+            ignore_pc,
+            context,
+            max_cycles,
+            debug_info,
+        } => {
+            let debug_info = load_debug_info(&input, debug_info)?;
+            let info = debug_info.as_ref();
+            let mut ignore_regs = ignore_reg
+                .iter()
+                .map(|name| parse_register::<M>(name))
+                .collect::<Fallible<Vec<_>>>()?;
+            if ignore_pc {
+                ignore_regs.push(parse_register::<M>("pc")?);
+            }
+            let options = DiffOptions {
+                ignore: ignore
+                    .iter()
+                    .map(|spec| parse_range(spec, info))
+                    .collect::<Fallible<_>>()?,
+                ignore_regs,
+                context,
+                max_cycles: max_cycles.unwrap_or(DEFAULT_MAX_CYCLES),
+            };
+            let inputs = inputs
+                .iter()
+                .map(|spec| parse_range(spec, info))
+                .collect::<Fallible<Vec<_>>>()?;
+            subcommand_diff::<M>(input, reference, &inputs, &options)
+        },
+
+        Command::History {
+            input,
+            writes,
+            last_writes,
+            values,
+            became,
+            debug_info,
+        } => subcommand_history::<M>(
+            input,
+            writes,
+            last_writes,
+            values,
+            became,
+            debug_info,
+        ),
+
+        Command::Data { input, hex, start, end } => {
+            subcommand_data::<M>(input, hex, start, end)
+        },
+
+        Command::Code { input, hex, start, end, debug_info } => {
+            subcommand_code::<M>(input, hex, start, end, debug_info)
+        },
+
+        Command::Regs { input, hex, debug_info } => {
+            subcommand_regs::<M>(input, hex, debug_info)
+        },
+
+        Command::Stats { input } => subcommand_stats::<M>(input),
+    }
+}
+
+fn subcommand_new<M>(
+    output: PathBuf,
+    machine: Option<String>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    if let Some(name) = machine.filter(|name| name != M::NAME) {
+        Err(UnknownMachine { name, known: vec![M::NAME] })?;
+    }
+
+    let vm = M::default();
+    vm.save_at_path(&output)?;
+    Ok(())
+}
+
+fn subcommand_write<M>(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    hex: bool,
+    addr: String,
+    data: String,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let addr = parse_dec_or_hex(&addr, hex)?;
+    let data = parse_dec_or_hex(&data, hex)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    vm.write_raw(addr, data);
+    vm.save_at_path(resolve_output(&input, &output))?;
+
+    Ok(())
+}
+
+fn subcommand_setpc<M>(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    hex: bool,
+    data: String,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let data = parse_dec_or_hex(&data, hex)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    vm.set_pc(data);
+    vm.save_at_path(resolve_output(&input, &output))?;
+
+    Ok(())
+}
+
+fn subcommand_run<M>(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    max_cycles: u64,
+    detect_loops: bool,
+    records: RecordArgs,
+    stops: StopArgs,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    let debug_info = if !stops.is_empty() || records.coverage.is_some() {
+        load_debug_info(&input, debug_info)?
+    } else {
+        None
+    };
+    stops.apply(&mut vm, debug_info.as_ref())?;
+    vm.set_max_cycles(Some(max_cycles).filter(|&max| max > 0));
+    vm.set_detect_loops(detect_loops);
+    if records.record {
+        vm.set_recording(true);
+    }
+    let mut tracer = match &records.trace {
+        Some(path) => {
+            let file = File::create(path).map_err(|error| WithPath {
+                path: path.clone(),
+                error: error.into(),
+            })?;
+            let format = TraceFormat::from_path(path);
+            Some(Tracer::new(BufWriter::new(file), format))
+        },
+        None => None,
+    };
+
+    let mut profile = records.profile.then(Profile::new);
+    let mut coverage = records.coverage.as_ref().map(|_| Coverage::new());
+    let mut last = None;
+    let mut trace_error = None;
+    let reason = vm.execute_with(|vm, step| {
+        last = Some(step.instr);
+        if let Some(profile) = profile.as_mut() {
+            profile.record(vm, step);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.record(step);
+        }
+        if let (Some(tracer), None) = (tracer.as_mut(), &trace_error) {
+            trace_error = tracer.write_step(step).err();
+        }
+    });
+    if let Some(error) = trace_error {
+        Err(error)?;
+    }
+    if let Some(tracer) = tracer {
+        tracer.finish()?;
+    }
+    if let (Some(coverage), Some(path)) = (coverage, &records.coverage) {
+        let mut file = File::create(path).map_err(|error| WithPath {
+            path: path.clone(),
+            error: error.into(),
+        })?;
+        let format = CoverageFormat::from_path(path);
+        coverage.write_report(format, debug_info.as_ref(), &mut file)?;
+    }
+    vm.save_at_path(resolve_output(&input, &output))?;
+    println!("{}", summary(&vm, reason, last.as_ref()));
+    if let Some(profile) = profile {
+        profile.write_report(&vm, io::stdout().lock())?;
+    }
+
+    match reason {
+        StopReason::Halt
+        | StopReason::Breakpoint(_)
+        | StopReason::Watch(_)
+        | StopReason::Condition(_)
+        | StopReason::Interrupt => Ok(()),
+        StopReason::Invariant(index) => Err(InvariantBroken {
+            invariant: vm.core().invariants[index].to_string(),
+        })?,
+        StopReason::CycleLimit => Err(CycleLimit { max_cycles })?,
+        StopReason::Loop(found) => Err(InfiniteLoop {
+            entry: found.entry,
+            period: found.period,
+        })?,
+        StopReason::SelfModify(hit) => Err(SelfModifyingCode {
+            pc: hit.instr.addr,
+            addr: hit.addr,
+            instr: M::format_instr(&vm.decode(hit.code)),
+        })?,
+        StopReason::Undefined(instr) => {
+            Err(UndefinedInstr { opcode: instr.opcode, pc: instr.addr })?
+        },
+    }
+}
+
+/// One-line summary of an execution, given why it stopped and the last
+/// instruction executed, such as `halted after 6 cycles, 14 accesses (last:
+/// 09 HLT)`.
+pub fn summary<M>(vm: &M, reason: StopReason, last: Option<&Instr>) -> String
+where
+    M: Machine,
+{
+    let core = vm.core();
+    let reason = match reason {
+        StopReason::Condition(index) => {
+            format!("{}: {}", reason, core.conditions[index])
+        },
+        StopReason::Invariant(index) => {
+            format!("{}: {}", reason, core.invariants[index])
+        },
+        StopReason::SelfModify(hit) => hit.describe(vm),
+        _ => reason.to_string(),
+    };
+
+    match last {
+        Some(instr) => format!(
+            "{} after {} cycles, {} accesses (last: {:02X} {})",
+            reason,
+            core.cycles,
+            core.accesses,
+            instr.addr,
+            M::format_instr(instr)
+        ),
+        None => format!(
+            "{} after {} cycles, {} accesses",
+            reason, core.cycles, core.accesses
+        ),
+    }
+}
+
+/// Message shown when stepping back reaches the oldest recorded cycle.
+pub(crate) fn history_start<M>(vm: &M) -> String
+where
+    M: Machine,
+{
+    format!(
+        "reached the start of the recorded history at {} cycles",
+        vm.core().cycles
+    )
+}
+
+fn subcommand_step<M>(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    step: StepArgs,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let StepArgs { steps, until_break, back, record, strict } = step;
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    if record {
+        vm.set_recording(true);
+    }
+    if strict {
+        vm.set_strict(true);
+    }
+    if back && vm.core().undo.is_none() {
+        Err(NotRecorded)?;
+    }
+    let max_cycles = steps.unwrap_or(DEFAULT_MAX_CYCLES);
+    let mut reason = None;
+    if back && until_break {
+        vm.set_max_cycles(Some(max_cycles));
+        reason = vm.reverse_execute();
+        match reason {
+            Some(reason) => println!("{}", summary(&vm, reason, None)),
+            None => println!("{}", history_start(&vm)),
+        }
+    } else if back {
+        let steps = steps.unwrap_or(1);
+        if vm.step_back(steps) < steps {
+            println!("{}", history_start(&vm));
+        }
+    } else if until_break {
+        vm.set_max_cycles(Some(max_cycles));
+        let mut last = None;
+        let stop = vm.execute_with(|_, step| last = Some(step.instr));
+        println!("{}", summary(&vm, stop, last.as_ref()));
+        reason = Some(stop);
+    } else {
+        for _ in 0 .. steps.unwrap_or(1) {
+            if let Some(stop) = vm.strict_stop() {
+                println!("{}", summary(&vm, stop, None));
+                reason = Some(stop);
+                break;
+            }
+            vm.cycle();
+        }
+    }
+    vm.save_at_path(resolve_output(&input, &output))?;
+
+    if let Some(src) =
+        debug_info.as_ref().and_then(|info| info.describe(vm.pc()))
+    {
+        println!("pc = {:03}  ; {}", vm.pc(), src);
+    }
+
+    match reason {
+        Some(StopReason::CycleLimit) => Err(CycleLimit { max_cycles })?,
+        Some(StopReason::Undefined(instr)) => {
+            Err(UndefinedInstr { opcode: instr.opcode, pc: instr.addr })?
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn subcommand_debug<M>(
+    input: PathBuf,
+    hex: bool,
+    strict: bool,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    if strict {
+        vm.set_strict(true);
+    }
+    let mut debugger = Debugger::new(vm, input, debug_info);
+    debugger.hex = hex;
+    debugger.run(io::stdin().lock(), io::stdout())
+}
+
+fn subcommand_tui<M>(
+    input: PathBuf,
+    strict: bool,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    if strict {
+        vm.set_strict(true);
+    }
+    Tui::new(vm, input, debug_info).run()
+}
+
+fn subcommand_gdb<M>(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    port: u16,
+    socket: Option<PathBuf>,
+    strict: bool,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    if strict {
+        vm.set_strict(true);
+    }
+    let mut stub = GdbStub::new(vm);
+
+    match socket {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = std::os::unix::net::UnixListener::bind(&path)
+                .map_err(|error| WithPath {
+                    path: path.clone(),
+                    error: error.into(),
+                })?;
+            println!("listening on {}", path.display());
+            let (stream, _) = listener.accept()?;
+            stub.serve(stream)?;
+            std::fs::remove_file(&path)?;
+        },
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Unix sockets are not supported here",
+        ))?,
+        None => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            println!("listening on {}", listener.local_addr()?);
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            stub.serve(stream)?;
+        },
+    }
+
+    if let Some(output) = output {
+        stub.vm.save_at_path(&output)?;
+    }
+    Ok(())
+}
+
+fn subcommand_dap<M>(strict: bool) -> Fallible<()>
+where
+    M: Machine,
+{
+    let mut server = DapServer::<M>::new();
+    server.strict = strict;
+    server.serve(io::stdin().lock(), io::stdout().lock())
+}
+
+fn subcommand_diff<M>(
+    input: PathBuf,
+    reference: PathBuf,
+    inputs: &[(u8, u8)],
+    options: &DiffOptions<M>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let mut vm = M::default();
+    let mut ref_vm = M::default();
+
+    vm.load_from_path(&input)?;
+    ref_vm.load_from_path(&reference)?;
+    for &(start, end) in inputs {
+        for addr in start ..= end {
+            vm.write_raw(addr, ref_vm.core().mem[addr as usize]);
+        }
+    }
+
+    match diff::lockstep(&mut vm, &mut ref_vm, options) {
+        Comparison::Same(cycles) => {
+            println!("no divergence, both halted after {} cycles", cycles);
+            Ok(())
+        },
+        Comparison::Limit(cycles) => {
+            println!("no divergence in {} cycles", cycles);
+            Ok(())
+        },
+        Comparison::Diverged(divergence) => {
+            print!("{}", divergence);
+            Err(Diverged { cycle: divergence.cycle })?
+        },
+    }
+}
+
+fn subcommand_history<M>(
+    input: PathBuf,
+    writes: Vec<String>,
+    last_writes: Vec<String>,
+    values: Vec<String>,
+    became: Vec<String>,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let info = debug_info.as_ref();
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    let history = History::of(&vm).ok_or(NotRecorded)?;
+
+    for spec in &writes {
+        let addr = parse_addr(spec, info)?;
+        let events = history.writes(addr);
+        if events.is_empty() {
+            println!("no writes to {:02X}h recorded", addr);
+        }
+        for event in events {
+            println!("{}", format_write(&event));
+        }
+    }
+
+    for spec in &last_writes {
+        let addr = parse_addr(spec, info)?;
+        match history.writes(addr).last() {
+            Some(event) => println!("{}", format_write(event)),
+            None => println!("no writes to {:02X}h recorded", addr),
+        }
+    }
+
+    for src in &values {
+        let expr = Expr::parse::<M>(src, info)?;
+        for event in history.values(&expr) {
+            match event.pc {
+                Some(pc) => println!(
+                    "cycle {} (pc = {:02X}h): {}",
+                    event.cycle, pc, event.value
+                ),
+                None => println!("cycle {}: {}", event.cycle, event.value),
+            }
+        }
+    }
+
+    for src in &became {
+        let expr = Expr::parse::<M>(src, info)?;
+        match history.first_became(&expr) {
+            Some(event) => println!(
+                "{} became true at cycle {} (pc = {:02X}h)",
+                expr,
+                event.cycle,
+                event.pc.unwrap_or(0)
+            ),
+            None => println!(
+                "{} never became true in {} recorded cycles",
+                expr,
+                history.len()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Shows a recorded write, such as `cycle 12: pc = 04h wrote 05h over 04h at
+/// 82h`.
+fn format_write(event: &WriteEvent) -> String {
+    format!(
+        "cycle {}: pc = {:02X}h wrote {:02X}h over {:02X}h at {:02X}h",
+        event.cycle, event.pc, event.new, event.old, event.addr
+    )
+}
+
+fn subcommand_data<M>(
+    input: PathBuf,
+    hex: bool,
+    start: Option<String>,
+    end: Option<String>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let start = start.map_or(Ok(128), |s| parse_dec_or_hex(&s, hex))?;
+    let end = end.map_or(Ok(255), |s| parse_dec_or_hex(&s, hex))?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    vm.display_mem_data(start ..= end, io::stdout(), hex)?;
+
+    Ok(())
+}
+
+fn subcommand_code<M>(
+    input: PathBuf,
+    hex: bool,
+    start: Option<String>,
+    end: Option<String>,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let start = start.map_or(Ok(0), |s| parse_dec_or_hex(&s, hex))?;
+    let end = end.map_or(Ok(127), |s| parse_dec_or_hex(&s, hex))?;
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+
+    match debug_info {
+        Some(info) => {
+            let mut buf = Vec::new();
+            vm.display_mem_opcodes(start ..= end, &mut buf, hex)?;
+            let mut prev = None;
+            annotate_lines(&buf, io::stdout(), |i, _| {
+                let addr = start.wrapping_add(i as u8);
+                let src_line = info.get(addr);
+                let changed = src_line != prev;
+                prev = src_line;
+                if changed {
+                    info.describe(addr)
+                } else {
+                    None
+                }
+            })?;
+        },
+        None => vm.display_mem_opcodes(start ..= end, io::stdout(), hex)?,
+    }
+
+    Ok(())
+}
+
+fn subcommand_regs<M>(
+    input: PathBuf,
+    hex: bool,
+    debug_info: Option<PathBuf>,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let debug_info = load_debug_info(&input, debug_info)?;
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+
+    match debug_info {
+        Some(info) => {
+            let mut buf = Vec::new();
+            vm.display_registers(&mut buf, hex)?;
+            annotate_lines(&buf, io::stdout(), |_, line| {
+                if line.starts_with("pc ") {
+                    info.describe(vm.pc())
+                } else {
+                    None
+                }
+            })?;
+        },
+        None => vm.display_registers(io::stdout(), hex)?,
+    }
+
+    Ok(())
+}
+
+fn subcommand_stats<M>(input: PathBuf) -> Fallible<()>
+where
+    M: Machine,
+{
+    let mut vm = M::default();
+
+    vm.load_from_path(&input)?;
+    vm.display_stats(io::stdout())?;
+
+    Ok(())
+}
+
+/// Loads the debug info given by the user, or the sidecar of the input, if
+/// any.
+fn load_debug_info(
+    input: &Path,
+    debug_info: Option<PathBuf>,
+) -> Fallible<Option<DebugInfo>> {
+    match debug_info {
+        Some(path) => DebugInfo::load_from_path(&path).map(Some),
+        None => DebugInfo::load_sidecar(input),
+    }
+}
+
+/// Writes each line of the given text, appending the source annotation given
+/// by the function (if any) as a comment.
+fn annotate_lines<W, F>(
+    text: &[u8],
+    mut output: W,
+    mut annotate: F,
+) -> Fallible<()>
+where
+    W: Write,
+    F: FnMut(usize, &str) -> Option<String>,
+{
+    let text = String::from_utf8_lossy(text);
+
+    for (i, line) in text.lines().enumerate() {
+        match annotate(i, line) {
+            Some(src) => writeln!(output, "{}  ; {}", line, src)?,
+            None => writeln!(output, "{}", line)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn resolve_output<'args>(
+    input: &'args Path,
+    output_arg: &'args Option<PathBuf>,
+) -> &'args Path {
+    output_arg.as_ref().map_or(input, |buf| &**buf)
+}
+
+/// Parses an address given by the user: a decimal number, a hexadecimal
+/// number such as `0Ah` or `0x0A`, or a label from the debug info.
+pub(crate) fn parse_addr(
+    spec: &str,
+    debug_info: Option<&DebugInfo>,
+) -> Fallible<u8> {
+    let invalid = || InvalidAddress {
+        spec: spec.to_owned(),
+        has_debug_info: debug_info.is_some(),
+    };
+
+    if !spec.starts_with(|ch: char| ch.is_ascii_digit()) {
+        let addr = debug_info.and_then(|info| info.label_addr(spec));
+        return addr.ok_or_else(|| invalid().into());
+    }
+
+    let res = if let Some(hex) = spec.strip_suffix(&['h', 'H'][..]) {
+        u8::from_str_radix(hex, 16)
+    } else if let Some(hex) = spec.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        spec.parse()
+    };
+    res.map_err(|_| invalid().into())
+}
+
+/// Parses an address, or an inclusive range of addresses such as `80h..8Fh`.
+pub(crate) fn parse_range(
+    spec: &str,
+    debug_info: Option<&DebugInfo>,
+) -> Fallible<(u8, u8)> {
+    match spec.find("..") {
+        Some(pos) => Ok((
+            parse_addr(&spec[.. pos], debug_info)?,
+            parse_addr(&spec[pos + 2 ..], debug_info)?,
+        )),
+        None => {
+            let addr = parse_addr(spec, debug_info)?;
+            Ok((addr, addr))
+        },
+    }
+}
+
+/// Parses the name of a register of the machine, such as `ac`.
+fn parse_register<M>(name: &str) -> Fallible<M::Register>
+where
+    M: Machine,
+{
+    match M::Register::ALL.iter().find(|reg| reg.name() == name) {
+        Some(&reg) => Ok(reg),
+        None => Err(UnknownRegister { name: name.to_owned() })?,
+    }
+}
+
+/// Parses an exception to self-modifying code detection: the mnemonic of an
+/// instruction of the machine, in any case, or an address range.
+fn parse_smc_allow<M>(
+    spec: &str,
+    debug_info: Option<&DebugInfo>,
+) -> Fallible<SmcAllow>
+where
+    M: Machine,
+{
+    let mnemonic = (0 ..= 255)
+        .filter_map(M::instr_info)
+        .map(|info| info.mnemonic)
+        .find(|mnemonic| mnemonic.eq_ignore_ascii_case(spec));
+    match mnemonic {
+        Some(mnemonic) => Ok(SmcAllow::Mnemonic(mnemonic)),
+        None => {
+            let (start, end) = parse_range(spec, debug_info)?;
+            Ok(SmcAllow::Range(start, end))
+        },
+    }
+}
+
+fn parse_dec_or_hex(num: &str, hex: bool) -> Fallible<u8> {
+    u8::from_str_radix(num, if hex { 16 } else { 10 })
+        .map_err(|e| WithPath { path: num.into(), error: e.into() }.into())
+}
@@ -0,0 +1,237 @@
+use crate::{Access, Machine, Step};
+use debuginfo::DebugInfo;
+use error::Fallible;
+use serde_json::json;
+use std::{io::Write, path::Path};
+
+/// Side, in pixels, of the square of each address in a heatmap.
+const CELL_SIZE: usize = 16;
+
+/// Format of a coverage report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageFormat {
+    /// A 16x16 grid with what happened at each address, followed by notes.
+    Text,
+    /// Counts per address as JSON.
+    Json,
+    /// A 16x16 heatmap as a binary PPM image.
+    Ppm,
+}
+
+impl CoverageFormat {
+    /// Format given by the extension of the path: `.json` for JSON, `.ppm`
+    /// for an image, text otherwise.
+    pub fn from_path<P>(path: &P) -> Self
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("json") => CoverageFormat::Json,
+            Some("ppm") => CoverageFormat::Ppm,
+            _ => CoverageFormat::Text,
+        }
+    }
+}
+
+/// How many times each address of memory was executed, read and written
+/// during an execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// Fetches of each address as an opcode or operand.
+    pub executed: Vec<u64>,
+    /// Data reads of each address, not counting instruction fetches.
+    pub read: Vec<u64>,
+    /// Writes to each address.
+    pub written: Vec<u64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            executed: vec![0; 256],
+            read: vec![0; 256],
+            written: vec![0; 256],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the accesses of the given cycle.
+    pub fn record<M>(&mut self, step: &Step<M>)
+    where
+        M: Machine,
+    {
+        for access in step.fetches() {
+            self.executed[access.addr() as usize] += 1;
+        }
+        for &access in step.data_accesses() {
+            match access {
+                Access::Read { addr, .. } => self.read[addr as usize] += 1,
+                Access::Write { addr, .. } => self.written[addr as usize] += 1,
+            }
+        }
+    }
+
+    /// Tests whether the address was neither executed, read nor written.
+    pub fn untouched(&self, addr: u8) -> bool {
+        let addr = addr as usize;
+        [&self.executed, &self.read, &self.written]
+            .iter()
+            .all(|counts| counts[addr] == 0)
+    }
+
+    /// Writes the report in the given format. Text reports list the source
+    /// lines never touched, if debug info is given.
+    pub fn write_report<W>(
+        &self,
+        format: CoverageFormat,
+        debug_info: Option<&DebugInfo>,
+        output: W,
+    ) -> Fallible<()>
+    where
+        W: Write,
+    {
+        match format {
+            CoverageFormat::Text => self.write_text(debug_info, output),
+            CoverageFormat::Json => self.write_json(output),
+            CoverageFormat::Ppm => self.write_ppm(output),
+        }
+    }
+
+    /// Writes a grid such as
+    /// ```text
+    ///     0   1   2   3 ...
+    /// 00  x-- x-- x-w --- ...
+    /// ```
+    /// with `x` for executed, `r` for read and `w` for written, followed by
+    /// totals, addresses written but never read and source lines whose
+    /// address was never touched.
+    fn write_text<W>(
+        &self,
+        debug_info: Option<&DebugInfo>,
+        mut output: W,
+    ) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let header =
+            (0 .. 16).map(|col| format!("{:X}", col)).collect::<Vec<_>>();
+        writeln!(output, "    {}", header.join("   "))?;
+
+        for row in 0 .. 16 {
+            write!(output, "{:X}0 ", row)?;
+            for col in 0 .. 16 {
+                let addr = row * 16 + col;
+                let mark = |counts: &[u64], ch| {
+                    if counts[addr] > 0 {
+                        ch
+                    } else {
+                        '-'
+                    }
+                };
+                write!(
+                    output,
+                    " {}{}{}",
+                    mark(&self.executed, 'x'),
+                    mark(&self.read, 'r'),
+                    mark(&self.written, 'w')
+                )?;
+            }
+            writeln!(output)?;
+        }
+
+        let count = |counts: &[u64]| counts.iter().filter(|&&n| n > 0).count();
+        let untouched = (0 ..= 255).filter(|&addr| self.untouched(addr));
+        writeln!(
+            output,
+            "executed {}, read {}, written {}, untouched {}",
+            count(&self.executed),
+            count(&self.read),
+            count(&self.written),
+            untouched.count()
+        )?;
+
+        let unread = (0 .. 256)
+            .filter(|&addr| self.written[addr] > 0 && self.read[addr] == 0)
+            .map(|addr| format!("{:02X}", addr))
+            .collect::<Vec<_>>();
+        if !unread.is_empty() {
+            writeln!(output, "written but never read: {}", unread.join(" "))?;
+        }
+
+        if let Some(info) = debug_info {
+            let untouched = info
+                .lines()
+                .map(|(addr, _)| addr)
+                .filter(|&addr| self.untouched(addr))
+                .collect::<Vec<_>>();
+            if !untouched.is_empty() {
+                writeln!(output, "never touched:")?;
+            }
+            for addr in untouched {
+                if let Some(src) = info.describe(addr) {
+                    writeln!(output, "  {:02X}  {}", addr, src)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes an object such as `{"executed":[1,1,0,...],"read":[...],
+    /// "written":[...]}`, each array with the counts of the 256 addresses.
+    fn write_json<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let report = json!({
+            "executed": self.executed,
+            "read": self.read,
+            "written": self.written,
+        });
+        writeln!(output, "{}", report)?;
+        Ok(())
+    }
+
+    /// Writes a 256x256 image with a square per address, in rows of 16
+    /// addresses. Blue shows executions, green reads and red writes, brighter
+    /// the more accesses, on a logarithmic scale. Untouched addresses are
+    /// black.
+    fn write_ppm<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let side = 16 * CELL_SIZE;
+        write!(output, "P6\n{} {}\n255\n", side, side)?;
+
+        let channels = [&self.written, &self.read, &self.executed];
+        let maxima =
+            channels.map(|counts| counts.iter().cloned().max().unwrap_or(0));
+        let mut pixels = Vec::with_capacity(side * side * 3);
+        for y in 0 .. side {
+            for x in 0 .. side {
+                let addr = y / CELL_SIZE * 16 + x / CELL_SIZE;
+                for (counts, &max) in channels.iter().zip(&maxima) {
+                    pixels.push(intensity(counts[addr], max));
+                }
+            }
+        }
+        output.write_all(&pixels)?;
+        Ok(())
+    }
+}
+
+/// Brightness of a channel given the count and the largest count, on a
+/// logarithmic scale that keeps single accesses visible.
+fn intensity(count: u64, max: u64) -> u8 {
+    if count == 0 {
+        0
+    } else {
+        let scale = (count as f64).ln_1p() / (max as f64).ln_1p();
+        (64.0 + 191.0 * scale).round() as u8
+    }
+}
@@ -0,0 +1,592 @@
+use crate::{
+    cli::{self, parse_addr},
+    Expr,
+    Flag as _,
+    Machine,
+    Register as _,
+};
+use debuginfo::DebugInfo;
+use error::{Fallible, InvalidRequest};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    io::{BufRead, Write},
+    path::PathBuf,
+    str,
+};
+
+/// Variables reference of the registers scope.
+const REGISTERS_REF: u64 = 1;
+
+/// Server of the Debug Adapter Protocol over a machine, with a single thread
+/// and a single stack frame.
+#[derive(Debug)]
+pub struct DapServer<M>
+where
+    M: Machine,
+{
+    pub vm: M,
+    /// Program launched, a memory or state file.
+    program: PathBuf,
+    debug_info: Option<DebugInfo>,
+    /// Breakpoints set by source line, for each source file.
+    line_breaks: BTreeMap<PathBuf, Vec<u8>>,
+    /// Breakpoints set by address.
+    addr_breaks: BTreeSet<u8>,
+    stop_on_entry: bool,
+    /// Whether launched programs stop before undefined instructions.
+    pub strict: bool,
+    /// Id of the last breakpoint reported to the client.
+    break_id: u64,
+    /// Sequence number of the last message sent.
+    seq: u64,
+    /// Events to send after the response of the current request.
+    events: Vec<Value>,
+}
+
+impl<M> Default for DapServer<M>
+where
+    M: Machine,
+{
+    fn default() -> Self {
+        Self {
+            vm: M::default(),
+            program: PathBuf::new(),
+            debug_info: None,
+            line_breaks: BTreeMap::new(),
+            addr_breaks: BTreeSet::new(),
+            stop_on_entry: false,
+            strict: false,
+            break_id: 0,
+            seq: 0,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl<M> DapServer<M>
+where
+    M: Machine,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers requests from the client until it disconnects or closes the
+    /// input.
+    pub fn serve<R, W>(&mut self, mut input: R, mut output: W) -> Fallible<()>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        while let Some(request) = read_message(&mut input)? {
+            let (messages, done) = self.handle(&request);
+            for mut message in messages {
+                self.seq += 1;
+                message["seq"] = json!(self.seq);
+                write_message(&mut output, &message)?;
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Response to the given request followed by the events it caused, and
+    /// whether the session is over.
+    pub fn handle(&mut self, request: &Value) -> (Vec<Value>, bool) {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": true,
+        });
+
+        match self.dispatch(command, args) {
+            Ok(body) => response["body"] = body,
+            Err(error) => {
+                response["success"] = json!(false);
+                response["message"] = json!(error.to_string());
+                self.events.clear();
+            },
+        }
+
+        let mut messages = vec![response];
+        messages.append(&mut self.events);
+        (messages, command == "disconnect")
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> Fallible<Value> {
+        let body = match command {
+            "initialize" => {
+                self.event("initialized", json!({}));
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsStepBack": true,
+                })
+            },
+
+            "launch" => self.launch(args)?,
+
+            "setBreakpoints" => self.set_line_breaks(args)?,
+
+            "setInstructionBreakpoints" => self.set_addr_breaks(args)?,
+
+            "setExceptionBreakpoints" => json!({}),
+
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.resume();
+                }
+                json!({})
+            },
+
+            "threads" => json!({ "threads": [{ "id": 1, "name": M::TITLE }] }),
+
+            "stackTrace" => self.stack_trace(),
+
+            "scopes" => json!({
+                "scopes": [{
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REF,
+                    "expensive": false,
+                }],
+            }),
+
+            "variables" => self.variables(args),
+
+            "setVariable" => self.set_variable(args)?,
+
+            "continue" => {
+                self.resume();
+                json!({ "allThreadsContinued": true })
+            },
+
+            "next" | "stepIn" | "stepOut" => {
+                if let Some(reason) = self.vm.strict_stop() {
+                    self.stopped("exception", Some(reason.to_string()));
+                } else {
+                    self.vm.cycle();
+                    if self.vm.core().cycling {
+                        self.stopped("step", None);
+                    } else {
+                        self.exited();
+                    }
+                }
+                json!({})
+            },
+
+            "stepBack" => {
+                if self.vm.step_back(1) == 0 {
+                    let output = format!("{}\n", cli::history_start(&self.vm));
+                    let body = json!({
+                        "category": "console",
+                        "output": output,
+                    });
+                    self.event("output", body);
+                } else {
+                    self.stopped("step", None);
+                }
+                json!({})
+            },
+
+            "reverseContinue" => {
+                let max_cycles = self.vm.core().max_cycles;
+                self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+                let reason = self.vm.reverse_execute();
+                self.vm.set_max_cycles(max_cycles);
+                let description = reason.map(|reason| reason.to_string());
+                self.stopped("breakpoint", description);
+                json!({})
+            },
+
+            "pause" => Err(InvalidRequest {
+                message: "pause is not supported, the machine runs until it \
+                          stops"
+                    .to_owned(),
+            })?,
+
+            "evaluate" => {
+                let src = string_arg(args, "expression")?;
+                let expr = Expr::parse::<M>(src, self.debug_info.as_ref())?;
+                let value = expr.eval(&self.vm, &expr.baseline(&self.vm));
+                json!({ "result": value.to_string(), "variablesReference": 0 })
+            },
+
+            "readMemory" => self.read_mem(args)?,
+
+            "writeMemory" => self.write_mem(args)?,
+
+            "disconnect" => json!({}),
+
+            _ => Err(InvalidRequest {
+                message: format!("unsupported command {}", command),
+            })?,
+        };
+        Ok(body)
+    }
+
+    fn launch(&mut self, args: &Value) -> Fallible<Value> {
+        self.program = string_arg(args, "program")?.into();
+        self.vm.load_from_path(&self.program)?;
+        self.debug_info = match args["debugInfo"].as_str() {
+            Some(path) => Some(DebugInfo::load_from_path(path)?),
+            None => DebugInfo::load_sidecar(&self.program)?,
+        };
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm.core_mut().cycling = true;
+        self.vm.set_recording(true);
+        if self.strict {
+            self.vm.set_strict(true);
+        }
+        Ok(json!({}))
+    }
+
+    /// Replaces the breakpoints of a source file, given by line.
+    fn set_line_breaks(&mut self, args: &Value) -> Fallible<Value> {
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or(""));
+        let lines = args["breakpoints"].as_array().cloned().unwrap_or_default();
+
+        let mut addrs = Vec::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in &lines {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let addr = self
+                .debug_info
+                .as_ref()
+                .and_then(|info| info.line_addr(&path, line));
+            addrs.extend(addr);
+            breakpoints.push(match addr {
+                Some(addr) => json!({
+                    "id": self.next_break_id(),
+                    "verified": true,
+                    "line": line,
+                    "instructionReference": format_ref(addr),
+                }),
+                None => json!({
+                    "id": self.next_break_id(),
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line",
+                }),
+            });
+        }
+
+        self.line_breaks.insert(path, addrs);
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Replaces the breakpoints set by address.
+    fn set_addr_breaks(&mut self, args: &Value) -> Fallible<Value> {
+        let mut addrs = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = string_arg(breakpoint, "instructionReference")?;
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let info = self.debug_info.as_ref();
+            let addr = parse_addr(reference, info)? as i64 + offset;
+            let addr = u8::try_from(addr).ok();
+            addrs.extend(addr);
+            breakpoints.push(json!({
+                "id": self.next_break_id(),
+                "verified": addr.is_some(),
+                "instructionReference": reference,
+                "offset": offset,
+            }));
+        }
+
+        self.addr_breaks = addrs;
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Id for a new breakpoint reported to the client.
+    fn next_break_id(&mut self) -> u64 {
+        self.break_id += 1;
+        self.break_id
+    }
+
+    /// Makes the breakpoints of the machine match the ones set by the client.
+    fn update_breakpoints(&mut self) {
+        let addrs = self
+            .line_breaks
+            .values()
+            .flatten()
+            .chain(&self.addr_breaks)
+            .cloned()
+            .collect();
+        self.vm.core_mut().breakpoints = addrs;
+    }
+
+    fn stack_trace(&self) -> Value {
+        let pc = self.vm.pc();
+        let mut frame = json!({
+            "id": 0,
+            "name": M::format_instr(&self.vm.decode(pc)),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format_ref(pc),
+        });
+
+        let src_line = self.debug_info.as_ref().and_then(|info| info.get(pc));
+        if let Some(src_line) = src_line {
+            let dir = self.program.parent().unwrap_or(&self.program);
+            let path = dir.join(&src_line.file);
+            frame["line"] = json!(src_line.line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": src_line.file.display().to_string(),
+                "path": path.display().to_string(),
+            });
+        }
+
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+            return json!({ "variables": [] });
+        }
+
+        let regs = M::Register::ALL.iter().map(|&reg| {
+            let data = self.vm.register(reg);
+            json!({
+                "name": reg.name(),
+                "value": format!("{:02X}h", data),
+                "variablesReference": 0,
+                "memoryReference": format_ref(data),
+            })
+        });
+        let flags = M::Flag::ALL.iter().map(|&flag| {
+            json!({
+                "name": flag.name(),
+                "value": (self.vm.flag(flag) as u8).to_string(),
+                "variablesReference": 0,
+            })
+        });
+        json!({ "variables": regs.chain(flags).collect::<Vec<_>>() })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Fallible<Value> {
+        let name = string_arg(args, "name")?;
+        let data = parse_addr(string_arg(args, "value")?, None)?;
+
+        if let Some(&reg) = M::Register::ALL.iter().find(|r| r.name() == name) {
+            self.vm.set_register(reg, data);
+            let value = format!("{:02X}h", self.vm.register(reg));
+            Ok(json!({ "value": value }))
+        } else if let Some(&flag) =
+            M::Flag::ALL.iter().find(|flag| flag.name() == name)
+        {
+            self.vm.set_flag(flag, data != 0);
+            let value = (self.vm.flag(flag) as u8).to_string();
+            Ok(json!({ "value": value }))
+        } else {
+            Err(InvalidRequest { message: format!("no register {}", name) })?
+        }
+    }
+
+    fn read_mem(&self, args: &Value) -> Fallible<Value> {
+        let start = self.mem_start(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let end = start.saturating_add(count).min(256).max(start);
+        let data = self.vm.core().mem.get(start .. end).unwrap_or(&[]);
+        Ok(json!({
+            "address": format_ref(start.min(255) as u8),
+            "data": base64_encode(data),
+            "unreadableBytes": count - data.len(),
+        }))
+    }
+
+    fn write_mem(&mut self, args: &Value) -> Fallible<Value> {
+        let start = self.mem_start(args)?;
+        let data = base64_decode(string_arg(args, "data")?).ok_or_else(|| {
+            InvalidRequest { message: "invalid base64 data".to_owned() }
+        })?;
+        if start + data.len() > 256 {
+            Err(InvalidRequest {
+                message: "write past the end of memory".to_owned(),
+            })?;
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            self.vm.write_raw((start + i) as u8, byte);
+        }
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+
+    /// Address given by the memory reference and offset of a request, which
+    /// may be past the end of memory.
+    fn mem_start(&self, args: &Value) -> Fallible<usize> {
+        let reference = string_arg(args, "memoryReference")?;
+        let addr = parse_addr(reference, self.debug_info.as_ref())?;
+        let offset = args["offset"].as_i64().unwrap_or(0);
+        let start = addr as i64 + offset;
+        if start < 0 {
+            Err(InvalidRequest {
+                message: "address before the start of memory".to_owned(),
+            })?;
+        }
+        Ok(start as usize)
+    }
+
+    /// Runs until the machine stops, sending the events of the stop.
+    fn resume(&mut self) {
+        let max_cycles = self.vm.core().max_cycles;
+        self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+        let reason = self.vm.execute();
+        self.vm.set_max_cycles(max_cycles);
+
+        let kind = match reason {
+            crate::StopReason::Halt => return self.exited(),
+            crate::StopReason::Breakpoint(_)
+            | crate::StopReason::Condition(_) => "breakpoint",
+            crate::StopReason::Watch(_) => "data breakpoint",
+            crate::StopReason::CycleLimit
+            | crate::StopReason::Interrupt => "pause",
+            crate::StopReason::Invariant(_)
+            | crate::StopReason::Loop(_)
+            | crate::StopReason::SelfModify(_)
+            | crate::StopReason::Undefined(_) => "exception",
+        };
+        self.stopped(kind, Some(reason.to_string()));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": 1,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    fn exited(&mut self) {
+        self.event("exited", json!({ "exitCode": 0 }));
+        self.event("terminated", json!({}));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events.push(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+}
+
+/// Reads a message with its `Content-Length` header, or `None` at the end
+/// of the input.
+pub fn read_message<R>(mut input: R) -> Fallible<Option<Value>>
+where
+    R: BufRead,
+{
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+        } else if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    let message = serde_json::from_slice(&body).map_err(|error| {
+        InvalidRequest { message: error.to_string() }
+    })?;
+    Ok(Some(message))
+}
+
+/// Writes a message with its `Content-Length` header.
+pub fn write_message<W>(mut output: W, message: &Value) -> Fallible<()>
+where
+    W: Write,
+{
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn string_arg<'args>(args: &'args Value, name: &str) -> Fallible<&'args str> {
+    args[name].as_str().ok_or_else(|| {
+        InvalidRequest { message: format!("missing argument {}", name) }
+            .into()
+    })
+}
+
+/// Memory reference of an address, such as `0x0A`.
+fn format_ref(addr: u8) -> String {
+    format!("0x{:02X}", addr)
+}
+
+const BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes the data in base64, padded to a multiple of 4 characters.
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                let index = bits >> (18 - 6 * i) & 0x3F;
+                text.push(BASE64[index as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Decodes padded base64, or gives `None` if the text is not valid base64.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 4 != 0 {
+        return None;
+    }
+    let text = text
+        .strip_suffix("==")
+        .or_else(|| text.strip_suffix('='))
+        .unwrap_or(text);
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == byte)? as u32;
+        bits = (bits << 6 | value) & 0xFFFF;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    if bits & ((1 << count) - 1) != 0 {
+        return None;
+    }
+    Some(data)
+}
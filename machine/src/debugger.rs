@@ -0,0 +1,393 @@
+use crate::{
+    cli::{self, parse_addr, parse_range},
+    Expr,
+    Machine,
+    Register as _,
+    WatchKind,
+    Watchpoint,
+};
+use debuginfo::DebugInfo;
+use error::{CommandUsage, Fallible, UnknownCommand};
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+/// Commands of the debugger: names, usage and description.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("step", "step [n]", "runs n cycles (1 by default), showing each one"),
+    ("continue", "continue", "runs until HLT, a breakpoint or a watchpoint"),
+    ("back", "back [n]", "undoes n cycles (1 by default)"),
+    (
+        "reverse-continue",
+        "reverse-continue",
+        "undoes cycles until the previous breakpoint or watchpoint",
+    ),
+    ("break", "break addr", "adds a breakpoint (address or label)"),
+    ("delete", "delete addr", "removes a breakpoint"),
+    ("break-if", "break-if expr", "stops before fetching when expr holds"),
+    ("invariant", "invariant expr", "stops when expr no longer holds"),
+    ("watch", "watch read|write|change range", "adds a watchpoint"),
+    ("regs", "regs", "shows the registers and flags"),
+    ("data", "data start [end]", "shows memory data"),
+    ("code", "code start [end]", "shows memory with mnemonics"),
+    ("disas", "disas [n]", "disassembles n instructions around pc"),
+    ("set", "set reg|mem[addr] value", "sets a register or memory byte"),
+    ("stats", "stats", "shows the statistics"),
+    ("save", "save [path]", "saves the memory or state"),
+    ("help", "help", "shows this help"),
+    ("quit", "quit", "exits the debugger"),
+];
+
+/// Short names of commands.
+const ALIASES: &[(&str, &str)] = &[
+    ("s", "step"),
+    ("c", "continue"),
+    ("rc", "reverse-continue"),
+    ("b", "break"),
+    ("d", "delete"),
+    ("w", "watch"),
+    ("r", "regs"),
+    ("h", "help"),
+    ("q", "quit"),
+];
+
+/// Interactive debugger over a machine.
+#[derive(Debug)]
+pub struct Debugger<M>
+where
+    M: Machine,
+{
+    pub vm: M,
+    /// File the machine was loaded from, where `save` writes by default.
+    pub path: PathBuf,
+    pub debug_info: Option<DebugInfo>,
+    /// Whether numbers are shown in hexadecimal.
+    pub hex: bool,
+    /// Whether `save` writes the undo log, since the machine was already
+    /// recording when loaded.
+    record: bool,
+}
+
+impl<M> Debugger<M>
+where
+    M: Machine,
+{
+    /// Creates a debugger over the machine, recording its cycles so they
+    /// can be undone. The record is saved only if the machine was already
+    /// recording.
+    pub fn new(
+        mut vm: M,
+        path: PathBuf,
+        debug_info: Option<DebugInfo>,
+    ) -> Self {
+        let record = vm.core().undo.is_some();
+        vm.set_recording(true);
+        Self { vm, path, debug_info, hex: false, record }
+    }
+
+    /// Reads and executes commands until `quit` or the end of the input.
+    /// Errors of a command are shown, and do not stop the debugger.
+    pub fn run<R, W>(&mut self, input: R, mut output: W) -> Fallible<()>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let mut lines = input.lines();
+
+        loop {
+            write!(output, "({}) ", M::NAME)?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            match self.exec(&line, &mut output) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(error) => writeln!(output, "{}", error)?,
+            }
+        }
+
+        writeln!(output)?;
+        Ok(())
+    }
+
+    /// Executes a command line, returning whether the debugger should go on.
+    pub fn exec<W>(&mut self, line: &str, mut output: W) -> Fallible<bool>
+    where
+        W: Write,
+    {
+        let line = line.trim();
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[.. pos], line[pos ..].trim()),
+            None => (line, ""),
+        };
+        let name = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map_or(name, |(_, cmd)| cmd);
+        let words = args.split_whitespace().collect::<Vec<_>>();
+        let info = self.debug_info.as_ref();
+
+        match (name, &words[..]) {
+            ("", _) => (),
+
+            ("step", [] | [_]) => {
+                let steps = match words.first() {
+                    Some(steps) => steps.parse().map_err(|_| usage("step"))?,
+                    None => 1,
+                };
+                for _ in 0 .. steps {
+                    if let Some(reason) = self.vm.strict_stop() {
+                        writeln!(output, "{}", reason)?;
+                        break;
+                    }
+                    writeln!(output, "{}", self.vm.cycle())?;
+                }
+            },
+
+            ("continue", []) => {
+                let max_cycles = self.vm.core().max_cycles;
+                self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+                let mut last = None;
+                let reason =
+                    self.vm.execute_with(|_, step| last = Some(step.instr));
+                self.vm.set_max_cycles(max_cycles);
+                let summary = cli::summary(&self.vm, reason, last.as_ref());
+                writeln!(output, "{}", summary)?;
+                self.show_src(&mut output)?;
+            },
+
+            ("back", [] | [_]) => {
+                let steps = match words.first() {
+                    Some(steps) => steps.parse().map_err(|_| usage("back"))?,
+                    None => 1,
+                };
+                for _ in 0 .. steps {
+                    if self.vm.step_back(1) == 0 {
+                        writeln!(output, "{}", cli::history_start(&self.vm))?;
+                        break;
+                    }
+                    let instr = self.vm.decode(self.vm.pc());
+                    let text = M::format_instr(&instr);
+                    writeln!(output, "{:02X}  {}  ; undone", instr.addr, text)?;
+                }
+            },
+
+            ("reverse-continue", []) => {
+                let max_cycles = self.vm.core().max_cycles;
+                self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+                let reason = self.vm.reverse_execute();
+                self.vm.set_max_cycles(max_cycles);
+                match reason {
+                    Some(reason) => {
+                        let summary = cli::summary(&self.vm, reason, None);
+                        writeln!(output, "{}", summary)?;
+                    },
+                    None => {
+                        writeln!(output, "{}", cli::history_start(&self.vm))?
+                    },
+                }
+                self.show_src(&mut output)?;
+            },
+
+            ("break", [addr]) => {
+                self.vm.add_breakpoint(parse_addr(addr, info)?);
+            },
+
+            ("delete", [addr]) => {
+                let addr = parse_addr(addr, info)?;
+                if !self.vm.remove_breakpoint(addr) {
+                    writeln!(output, "No breakpoint at {:02X}h", addr)?;
+                }
+            },
+
+            ("break-if", _) if !args.is_empty() => {
+                self.vm.add_condition(Expr::parse::<M>(args, info)?);
+            },
+
+            ("invariant", _) if !args.is_empty() => {
+                self.vm.add_invariant(Expr::parse::<M>(args, info)?);
+            },
+
+            ("watch", [kind, range]) => {
+                let kind = match *kind {
+                    "read" => WatchKind::Read,
+                    "write" => WatchKind::Write,
+                    "change" => WatchKind::Change,
+                    _ => Err(usage("watch"))?,
+                };
+                let (start, end) = parse_range(range, info)?;
+                self.vm.add_watchpoint(Watchpoint { start, end, kind });
+            },
+
+            ("regs", []) => self.vm.display_registers(output, self.hex)?,
+
+            ("data", [start] | [start, _]) => {
+                let start = parse_addr(start, info)?;
+                let end = match words.get(1) {
+                    Some(end) => parse_addr(end, info)?,
+                    None => start,
+                };
+                self.vm.display_mem_data(start ..= end, output, self.hex)?;
+            },
+
+            ("code", [start] | [start, _]) => {
+                let start = parse_addr(start, info)?;
+                let end = match words.get(1) {
+                    Some(end) => parse_addr(end, info)?,
+                    None => start.saturating_add(15),
+                };
+                self.vm.display_mem_opcodes(start ..= end, output, self.hex)?;
+            },
+
+            ("disas", [] | [_]) => {
+                let count = match words.first() {
+                    Some(count) => {
+                        count.parse().map_err(|_| usage("disas"))?
+                    },
+                    None => 10,
+                };
+                self.disassemble(count, output)?;
+            },
+
+            ("set", [target, value]) => {
+                let value = parse_addr(value, info)?;
+                self.set(target, value)?;
+            },
+
+            ("stats", []) => self.vm.display_stats(output)?,
+
+            ("save", [] | [_]) => {
+                let path = words.first().map_or(self.path.clone(), Into::into);
+                save_session(&mut self.vm, &path, self.record)?;
+            },
+
+            ("help", []) => {
+                for (_, usage, description) in COMMANDS {
+                    writeln!(output, "{:<32} {}", usage, description)?;
+                }
+            },
+
+            ("quit", []) => return Ok(false),
+
+            _ if COMMANDS.iter().any(|(cmd, ..)| *cmd == name) => {
+                Err(usage(name))?
+            },
+
+            _ => Err(UnknownCommand { name: name.to_owned() })?,
+        }
+
+        Ok(true)
+    }
+
+    /// Shows the source line of the program counter, if known.
+    fn show_src<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let pc = self.vm.pc();
+        if let Some(src) = self.debug_info.as_ref().and_then(|i| i.describe(pc))
+        {
+            writeln!(output, "pc = {:02X}  ; {}", pc, src)?;
+        }
+        Ok(())
+    }
+
+    /// Disassembles about the given number of instructions around the
+    /// program counter.
+    fn disassemble<W>(&self, count: usize, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let pc = self.vm.pc();
+        for addr in addrs_around_pc(&self.vm, count) {
+            let instr = M::format_instr(&self.vm.decode(addr));
+            let marker = if addr == pc { "=>" } else { "  " };
+            write!(output, "{} {:02X}  {}", marker, addr, instr)?;
+            match self.debug_info.as_ref().and_then(|i| i.describe(addr)) {
+                Some(src) => writeln!(output, "  ; {}", src)?,
+                None => writeln!(output)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets a register, or a memory byte given as `mem[addr]`.
+    fn set(&mut self, target: &str, value: u8) -> Fallible<()> {
+        let info = self.debug_info.as_ref();
+        if let Some(addr) =
+            target.strip_prefix("mem[").and_then(|t| t.strip_suffix(']'))
+        {
+            let addr = parse_addr(addr, info)?;
+            self.vm.write_raw(addr, value);
+        } else {
+            let reg = M::Register::ALL
+                .iter()
+                .find(|reg| reg.name() == target)
+                .ok_or_else(|| usage("set"))?;
+            self.vm.set_register(*reg, value);
+        }
+        Ok(())
+    }
+}
+
+/// Saves a machine under a debugging session, leaving out the undo log the
+/// session records unless the user asked to record.
+pub(crate) fn save_session<M>(
+    vm: &mut M,
+    path: &Path,
+    record: bool,
+) -> Fallible<()>
+where
+    M: Machine,
+{
+    let undo = if record { None } else { vm.core_mut().undo.take() };
+    let result = vm.save_at_path(path);
+    if undo.is_some() {
+        vm.core_mut().undo = undo;
+    }
+    result
+}
+
+/// Addresses of about the given number of instructions, a third of them
+/// before the program counter. Instructions before it are found by decoding
+/// from address 0, so data mixed with code may misalign them.
+pub(crate) fn addrs_around_pc<M>(vm: &M, count: usize) -> Vec<u8>
+where
+    M: Machine,
+{
+    let pc = vm.pc();
+    let mut before = Vec::new();
+    let mut addr = 0u8;
+    while addr < pc {
+        before.push(addr);
+        addr = addr.saturating_add(vm.decode(addr).size());
+    }
+
+    let skip = before.len().saturating_sub(count / 3);
+    let mut addrs = before.split_off(skip);
+    let mut addr = pc;
+    while addrs.len() < count {
+        addrs.push(addr);
+        let size = vm.decode(addr).size();
+        match addr.checked_add(size) {
+            Some(next) => addr = next,
+            None => break,
+        }
+    }
+    addrs
+}
+
+/// Error showing the usage of the given command.
+fn usage(name: &str) -> CommandUsage {
+    let usage = COMMANDS
+        .iter()
+        .find(|(cmd, ..)| *cmd == name)
+        .map_or("", |(_, usage, _)| usage);
+    CommandUsage { usage }
+}
@@ -0,0 +1,217 @@
+use crate::{cli::DEFAULT_MAX_CYCLES, Flag as _, Machine, Register as _, Step};
+use std::{collections::VecDeque, fmt};
+
+/// What a lockstep comparison looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions<M>
+where
+    M: Machine,
+{
+    /// Address ranges, inclusive, whose writes are not compared, such as
+    /// code and scratch areas.
+    pub ignore: Vec<(u8, u8)>,
+    /// Registers left out of the comparison, such as the program counter
+    /// for programs whose code is laid out differently.
+    pub ignore_regs: Vec<M::Register>,
+    /// Number of cycles before the divergence kept as context.
+    pub context: usize,
+    /// Maximum number of cycles compared.
+    pub max_cycles: u64,
+}
+
+impl<M> Default for DiffOptions<M>
+where
+    M: Machine,
+{
+    fn default() -> Self {
+        Self {
+            ignore: Vec::new(),
+            ignore_regs: Vec::new(),
+            context: 5,
+            max_cycles: DEFAULT_MAX_CYCLES,
+        }
+    }
+}
+
+impl<M> DiffOptions<M>
+where
+    M: Machine,
+{
+    /// Tests whether writes to the given address are compared.
+    pub fn compares(&self, addr: u8) -> bool {
+        !self.ignore.iter().any(|&(start, end)| start <= addr && addr <= end)
+    }
+}
+
+/// Cycles of both machines at the same point. A machine that halted has no
+/// step.
+pub type StepPair<M> = (Option<Step<M>>, Option<Step<M>>);
+
+/// Outcome of running two machines in lockstep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Comparison<M>
+where
+    M: Machine,
+{
+    /// Both halted after the given number of cycles with no divergence.
+    Same(u64),
+    /// Neither diverged before the cycle limit.
+    Limit(u64),
+    /// The machines diverged.
+    Diverged(Divergence<M>),
+}
+
+/// First cycle at which two machines diverged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence<M>
+where
+    M: Machine,
+{
+    /// Number of the cycle, counting from the start of the comparison.
+    pub cycle: u64,
+    /// What differs, such as `ac: 05 vs 06`.
+    pub differences: Vec<String>,
+    /// Cycles of both machines up to and including the divergent one,
+    /// oldest first.
+    pub context: Vec<StepPair<M>>,
+}
+
+impl<M> fmt::Display for Divergence<M>
+where
+    M: Machine,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "first divergence at cycle {}:", self.cycle)?;
+        for difference in &self.differences {
+            writeln!(fmt, "  {}", difference)?;
+        }
+
+        let first = self.cycle + 1 - self.context.len() as u64;
+        for (cycle, (left, right)) in (first ..).zip(&self.context) {
+            writeln!(fmt, "cycle {}", cycle)?;
+            for (marker, step) in [("<", left), (">", right)].iter() {
+                match step {
+                    Some(step) => writeln!(fmt, "{} {}", marker, step)?,
+                    None => writeln!(fmt, "{} halted", marker)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs both machines a cycle at a time until both halt, returning the
+/// first cycle where registers, flags, memory writes or halting differ.
+pub fn lockstep<M>(
+    left: &mut M,
+    right: &mut M,
+    options: &DiffOptions<M>,
+) -> Comparison<M>
+where
+    M: Machine,
+{
+    left.core_mut().cycling = true;
+    right.core_mut().cycling = true;
+    let mut context = VecDeque::new();
+    let mut cycle = 0;
+
+    loop {
+        if !left.core().cycling && !right.core().cycling {
+            break Comparison::Same(cycle);
+        }
+        if cycle >= options.max_cycles {
+            break Comparison::Limit(cycle);
+        }
+        cycle += 1;
+
+        let left_step = step(left);
+        let right_step = step(right);
+        let differences =
+            compare(left, right, &left_step, &right_step, options);
+
+        context.push_back((left_step, right_step));
+        while context.len() > options.context + 1 {
+            context.pop_front();
+        }
+
+        if !differences.is_empty() {
+            break Comparison::Diverged(Divergence {
+                cycle,
+                differences,
+                context: context.into(),
+            });
+        }
+    }
+}
+
+/// Runs a cycle of the machine, unless it halted.
+fn step<M>(vm: &mut M) -> Option<Step<M>>
+where
+    M: Machine,
+{
+    if vm.core().cycling {
+        Some(vm.cycle())
+    } else {
+        None
+    }
+}
+
+/// Differences between the machines after a cycle of each.
+fn compare<M>(
+    left: &M,
+    right: &M,
+    left_step: &Option<Step<M>>,
+    right_step: &Option<Step<M>>,
+    options: &DiffOptions<M>,
+) -> Vec<String>
+where
+    M: Machine,
+{
+    let mut differences = Vec::new();
+
+    let (a, b) = (left.core().cycling, right.core().cycling);
+    if a != b {
+        let state = |cycling| if cycling { "running" } else { "halted" };
+        differences.push(format!("{} vs {}", state(a), state(b)));
+    }
+
+    for &reg in M::Register::ALL {
+        if options.ignore_regs.contains(&reg) {
+            continue;
+        }
+        let (a, b) = (left.register(reg), right.register(reg));
+        if a != b {
+            let name = reg.name();
+            differences.push(format!("{}: {:02X} vs {:02X}", name, a, b));
+        }
+    }
+
+    for &flag in M::Flag::ALL {
+        let (a, b) = (left.flag(flag), right.flag(flag));
+        if a != b {
+            let name = flag.name();
+            differences.push(format!("{}: {} vs {}", name, a as u8, b as u8));
+        }
+    }
+
+    let writes = |step: &Option<Step<M>>| {
+        step.iter()
+            .flat_map(|step| step.writes())
+            .filter(|&(addr, _, _)| options.compares(addr))
+            .map(|(addr, _, new)| format!("[{:02X}] = {:02X}", addr, new))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (writes(left_step), writes(right_step));
+    if a != b {
+        let show = |writes: Vec<String>| {
+            if writes.is_empty() {
+                "nothing".to_owned()
+            } else {
+                writes.join(", ")
+            }
+        };
+        differences.push(format!("writes: {} vs {}", show(a), show(b)));
+    }
+
+    differences
+}
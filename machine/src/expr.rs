@@ -0,0 +1,410 @@
+use crate::{Flag as _, Machine, Register as _};
+use debuginfo::DebugInfo;
+use error::{Fallible, InvalidExpr};
+use std::fmt;
+
+/// Unary operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    /// Logical not, `!`.
+    Not,
+    /// Arithmetic negation, `-`.
+    Neg,
+    /// Bitwise complement, `~`.
+    Compl,
+}
+
+/// Binary operators, from the lowest precedence to the highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Or => "||",
+            BinOp::And => "&&",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::BitAnd => "&",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+        }
+    }
+
+    /// Operators of each precedence level, from the lowest.
+    const LEVELS: &'static [&'static [BinOp]] = &[
+        &[BinOp::Or],
+        &[BinOp::And],
+        &[BinOp::Eq, BinOp::Ne, BinOp::Le, BinOp::Lt, BinOp::Ge, BinOp::Gt],
+        &[BinOp::BitOr],
+        &[BinOp::BitXor],
+        &[BinOp::BitAnd],
+        &[BinOp::Add, BinOp::Sub],
+    ];
+}
+
+/// An expression over the registers, flags and memory of a machine, such as
+/// `pc == 0Ah && mem[80h] > 10`. Values are integers, and conditions are true
+/// when nonzero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    /// A register, by name.
+    Reg(&'static str),
+    /// A flag, by name, valued 0 or 1.
+    Flag(&'static str),
+    /// `mem[addr]`, the byte at an address.
+    Mem(Box<Expr>),
+    /// `expr unchanged`: whether the value is the same as when the execution
+    /// started. Numbered in order of appearance.
+    Unchanged(usize, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Parses an expression for the given machine. Names other than its
+    /// registers and flags are labels, looked up in the debug info.
+    pub fn parse<M>(src: &str, debug_info: Option<&DebugInfo>) -> Fallible<Self>
+    where
+        M: Machine,
+    {
+        let invalid = |message: &str| InvalidExpr {
+            expr: src.to_owned(),
+            message: message.to_owned(),
+        };
+
+        let tokens = tokenize(src).map_err(invalid)?;
+        let mut parser = Parser::<M> {
+            tokens,
+            pos: 0,
+            unchanged: 0,
+            debug_info,
+            machine: Default::default(),
+        };
+        let expr = parser.parse_level(0).map_err(|msg| invalid(&msg))?;
+        if parser.pos < parser.tokens.len() {
+            Err(invalid("unexpected trailing input"))?;
+        }
+        Ok(expr)
+    }
+
+    /// Evaluates the expression on the given machine, with the values
+    /// `unchanged` compares against.
+    pub fn eval<M>(&self, vm: &M, baseline: &[i64]) -> i64
+    where
+        M: Machine,
+    {
+        match self {
+            Expr::Num(num) => *num,
+            Expr::Reg(name) => M::Register::ALL
+                .iter()
+                .find(|reg| reg.name() == *name)
+                .map_or(0, |&reg| vm.register(reg) as i64),
+            Expr::Flag(name) => M::Flag::ALL
+                .iter()
+                .find(|flag| flag.name() == *name)
+                .map_or(0, |&flag| vm.flag(flag) as i64),
+            Expr::Mem(addr) => {
+                let addr = addr.eval(vm, baseline) as u8;
+                vm.core().mem[addr as usize] as i64
+            },
+            Expr::Unchanged(index, expr) => {
+                let value = expr.eval(vm, baseline);
+                baseline.get(*index).map_or(true, |&old| old == value) as i64
+            },
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(vm, baseline);
+                match op {
+                    UnOp::Not => (value == 0) as i64,
+                    UnOp::Neg => value.wrapping_neg(),
+                    UnOp::Compl => !value,
+                }
+            },
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                (lhs.eval(vm, baseline) != 0 || rhs.eval(vm, baseline) != 0)
+                    as i64
+            },
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                (lhs.eval(vm, baseline) != 0 && rhs.eval(vm, baseline) != 0)
+                    as i64
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(vm, baseline);
+                let rhs = rhs.eval(vm, baseline);
+                match op {
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::BitOr => lhs | rhs,
+                    BinOp::BitXor => lhs ^ rhs,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            },
+        }
+    }
+
+    /// Tests whether the expression holds on the given machine.
+    pub fn holds<M>(&self, vm: &M, baseline: &[i64]) -> bool
+    where
+        M: Machine,
+    {
+        self.eval(vm, baseline) != 0
+    }
+
+    /// Values of the `unchanged` subexpressions on the given machine, to be
+    /// compared against later.
+    pub fn baseline<M>(&self, vm: &M) -> Vec<i64>
+    where
+        M: Machine,
+    {
+        let mut baseline = Vec::new();
+        self.collect_baseline(vm, &mut baseline);
+        baseline
+    }
+
+    fn collect_baseline<M>(&self, vm: &M, baseline: &mut Vec<i64>)
+    where
+        M: Machine,
+    {
+        match self {
+            Expr::Num(_) | Expr::Reg(_) | Expr::Flag(_) => (),
+            Expr::Mem(expr) | Expr::Unary(_, expr) => {
+                expr.collect_baseline(vm, baseline)
+            },
+            Expr::Unchanged(index, expr) => {
+                expr.collect_baseline(vm, baseline);
+                if baseline.len() <= *index {
+                    baseline.resize(index + 1, 0);
+                }
+                baseline[*index] = expr.eval(vm, &[]);
+            },
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_baseline(vm, baseline);
+                rhs.collect_baseline(vm, baseline);
+            },
+        }
+    }
+}
+
+/// Formats the expression so that it parses back to the same expression.
+impl fmt::Display for Expr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(num) => write!(fmt, "{}", num),
+            Expr::Reg(name) | Expr::Flag(name) => write!(fmt, "{}", name),
+            Expr::Mem(addr) => write!(fmt, "mem[{}]", addr),
+            Expr::Unchanged(_, expr) => write!(fmt, "({} unchanged)", expr),
+            Expr::Unary(op, expr) => {
+                let symbol = match op {
+                    UnOp::Not => "!",
+                    UnOp::Neg => "-",
+                    UnOp::Compl => "~",
+                };
+                write!(fmt, "{}{}", symbol, expr)
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                write!(fmt, "({} {} {})", lhs, op.symbol(), rhs)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Punct(&'static str),
+}
+
+/// Punctuation, longest first so that `<=` is not read as `<`.
+const PUNCTS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!",
+    "~", "(", ")", "[", "]",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut rest = src.trim_start();
+
+    while !rest.is_empty() {
+        let word_len = rest
+            .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+            .unwrap_or(rest.len());
+
+        if word_len > 0 {
+            let word = &rest[.. word_len];
+            if word.starts_with(|ch: char| ch.is_ascii_digit()) {
+                tokens.push(Token::Num(parse_num(word)?));
+            } else {
+                tokens.push(Token::Ident(word.to_owned()));
+            }
+            rest = &rest[word_len ..];
+        } else {
+            let punct = PUNCTS
+                .iter()
+                .find(|punct| rest.starts_with(*punct))
+                .ok_or("unexpected character")?;
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len() ..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a decimal number, or a hexadecimal one such as `80h` or `0x80`.
+fn parse_num(word: &str) -> Result<i64, &'static str> {
+    let res = if let Some(hex) = word.strip_suffix(&['h', 'H'][..]) {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(hex) = word.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else {
+        word.parse()
+    };
+    res.map_err(|_| "invalid number")
+}
+
+struct Parser<'info, M> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Number of `unchanged` found so far.
+    unchanged: usize,
+    debug_info: Option<&'info DebugInfo>,
+    machine: std::marker::PhantomData<M>,
+}
+
+impl<'info, M> Parser<'info, M>
+where
+    M: Machine,
+{
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(format!("expected {}", punct))
+        }
+    }
+
+    /// Parses a binary expression with operators of the given precedence
+    /// level or higher.
+    fn parse_level(&mut self, level: usize) -> Result<Expr, String> {
+        if level == BinOp::LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut expr = self.parse_level(level + 1)?;
+        while let Some(&op) =
+            BinOp::LEVELS[level].iter().find(|op| self.eat(op.symbol()))
+        {
+            let rhs = self.parse_level(level + 1)?;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let op = if self.eat("!") {
+            Some(UnOp::Not)
+        } else if self.eat("-") {
+            Some(UnOp::Neg)
+        } else if self.eat("~") {
+            Some(UnOp::Compl)
+        } else {
+            None
+        };
+
+        match op {
+            Some(op) => Ok(Expr::Unary(op, Box::new(self.parse_unary()?))),
+            None => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let expr = self.parse_primary()?;
+        if self.peek() == Some(&Token::Ident("unchanged".to_owned())) {
+            self.pos += 1;
+            self.unchanged += 1;
+            Ok(Expr::Unchanged(self.unchanged - 1, Box::new(expr)))
+        } else {
+            Ok(expr)
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned().ok_or("unexpected end")?;
+        self.pos += 1;
+
+        match token {
+            Token::Num(num) => Ok(Expr::Num(num)),
+            Token::Punct("(") => {
+                let expr = self.parse_level(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            Token::Ident(ref name) if name == "mem" => {
+                self.expect("[")?;
+                let addr = self.parse_level(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(addr)))
+            },
+            Token::Ident(name) => self.resolve(&name),
+            Token::Punct(punct) => Err(format!("unexpected {}", punct)),
+        }
+    }
+
+    /// Resolves a name into a register, a flag or the address of a label.
+    fn resolve(&self, name: &str) -> Result<Expr, String> {
+        if let Some(reg) = M::Register::ALL.iter().find(|r| r.name() == name) {
+            return Ok(Expr::Reg(reg.name()));
+        }
+        if let Some(flag) = M::Flag::ALL.iter().find(|f| f.name() == name) {
+            return Ok(Expr::Flag(flag.name()));
+        }
+        self.debug_info
+            .and_then(|info| info.label_addr(name))
+            .map(|addr| Expr::Num(addr as i64))
+            .ok_or_else(|| format!("unknown register, flag or label {}", name))
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{cli, Flag as _, Machine, Register as _, StopReason};
+use error::Fallible;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+};
+
+/// Number of cycles `c` runs between checks for an interrupt.
+const INTERRUPT_CYCLES: u64 = 10000;
+
+/// Byte sent by the client to interrupt a running machine.
+const INTERRUPT: u8 = 0x03;
+
+/// Connection to a GDB client.
+pub trait Connection: Read + Write {
+    /// Whether the client sent an interrupt, checked without waiting. Other
+    /// bytes received are discarded, since the client sends no packets while
+    /// the machine runs.
+    fn interrupted(&mut self) -> Fallible<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> Fallible<bool> {
+        self.set_nonblocking(true)?;
+        let interrupted = read_interrupt(&mut *self);
+        self.set_nonblocking(false)?;
+        interrupted
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn interrupted(&mut self) -> Fallible<bool> {
+        self.set_nonblocking(true)?;
+        let interrupted = read_interrupt(&mut *self);
+        self.set_nonblocking(false)?;
+        interrupted
+    }
+}
+
+/// Server of the GDB remote serial protocol over a machine. Registers are
+/// numbered in display order, followed by the flags, each one byte wide.
+/// Software breakpoints are the breakpoints of the machine.
+#[derive(Debug)]
+pub struct GdbStub<M>
+where
+    M: Machine,
+{
+    pub vm: M,
+    /// Whether packets are acknowledged, until the client disables it.
+    ack: bool,
+}
+
+impl<M> GdbStub<M>
+where
+    M: Machine,
+{
+    pub fn new(vm: M) -> Self {
+        Self { vm, ack: true }
+    }
+
+    /// Answers packets from the client until it detaches, kills the machine
+    /// or closes the connection.
+    pub fn serve<S>(&mut self, mut stream: S) -> Fallible<()>
+    where
+        S: Connection,
+    {
+        while let Some(packet) = self.recv(&mut stream)? {
+            if packet.starts_with('c') {
+                let reply = self.resume(|| stream.interrupted())?;
+                self.send(&mut stream, &reply)?;
+                continue;
+            }
+            let reply = match self.handle(&packet) {
+                Some(reply) => reply,
+                None => {
+                    self.send(&mut stream, "OK")?;
+                    break;
+                },
+            };
+            self.send(&mut stream, &reply)?;
+        }
+        Ok(())
+    }
+
+    /// Reply to the given packet, or `None` if the session is over. Here `c`
+    /// cannot be interrupted.
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        let (kind, args) = packet.split_at(packet.len().min(1));
+        let reply = match kind {
+            "?" => "S05".to_owned(),
+            "g" => self.read_regs(),
+            "G" => self.write_regs(args),
+            "p" => self.read_reg(args),
+            "P" => self.write_reg(args),
+            "m" => self.read_mem(args),
+            "M" => self.write_mem(args),
+            "s" => match self.vm.strict_stop() {
+                Some(reason) => self.stop_reply(Some(reason)),
+                None => {
+                    self.vm.cycle();
+                    self.stop_reply(None)
+                },
+            },
+            "c" => match self.resume(|| Ok(false)) {
+                Ok(reply) => reply,
+                Err(_) => "E01".to_owned(),
+            },
+            "Z" | "z" => self.breakpoint(kind == "Z", args),
+            "D" | "k" => return None,
+            "H" => "OK".to_owned(),
+            _ => self.query(packet),
+        };
+        Some(reply)
+    }
+
+    /// Runs until the machine stops or is interrupted, checking for an
+    /// interrupt every few cycles, and gives the stop reply.
+    fn resume<F>(&mut self, mut interrupted: F) -> Fallible<String>
+    where
+        F: FnMut() -> Fallible<bool>,
+    {
+        let max_cycles = self.vm.core().max_cycles;
+        self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+        let mut count = 0;
+        let mut error = None;
+        let reason = self.vm.execute_until(|_, _| {
+            count += 1;
+            if count % INTERRUPT_CYCLES != 0 {
+                return false;
+            }
+            interrupted().unwrap_or_else(|err| {
+                error = Some(err);
+                true
+            })
+        });
+        self.vm.set_max_cycles(max_cycles);
+        match error {
+            Some(error) => Err(error),
+            None => Ok(self.stop_reply(Some(reason))),
+        }
+    }
+
+    /// Replies to general queries and settings.
+    fn query(&mut self, packet: &str) -> String {
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_owned()
+            },
+            "qAttached" => "1".to_owned(),
+            "qC" => "QC1".to_owned(),
+            "qfThreadInfo" => "m1".to_owned(),
+            "qsThreadInfo" => "l".to_owned(),
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+"
+                    .to_owned()
+            },
+            _ if packet.starts_with("qXfer:features:read:") => {
+                self.read_features(&packet["qXfer:features:read:".len() ..])
+            },
+            _ => String::new(),
+        }
+    }
+
+    /// Reads part of a target description, given as `annex:offset,length`.
+    /// Only `target.xml` exists.
+    fn read_features(&self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let annex = parts.next();
+        let range = parts.next().and_then(|range| {
+            let mut parts = range.splitn(2, ',');
+            let offset = usize::from_str_radix(parts.next()?, 16).ok()?;
+            let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+            Some((offset, len))
+        });
+        match (annex, range) {
+            (Some("target.xml"), Some((offset, len))) => {
+                let xml = target_xml::<M>();
+                let start = offset.min(xml.len());
+                let end = offset.saturating_add(len).min(xml.len());
+                let kind = if end < xml.len() { 'm' } else { 'l' };
+                format!("{}{}", kind, &xml[start .. end])
+            },
+            (Some(_), Some(_)) => "E00".to_owned(),
+            _ => "E01".to_owned(),
+        }
+    }
+
+    /// Stop reply after running: exited on HLT, stopped by an illegal
+    /// instruction before an undefined one, by an interrupt when the client
+    /// interrupted, by a trap otherwise.
+    fn stop_reply(&self, reason: Option<StopReason>) -> String {
+        match reason {
+            Some(StopReason::Halt) => "W00".to_owned(),
+            Some(StopReason::Undefined(_)) => "S04".to_owned(),
+            Some(StopReason::Interrupt) => "S02".to_owned(),
+            _ => "S05".to_owned(),
+        }
+    }
+
+    fn read_regs(&self) -> String {
+        let regs = M::Register::ALL.iter().map(|&reg| self.vm.register(reg));
+        let flags = M::Flag::ALL.iter().map(|&flag| self.vm.flag(flag) as u8);
+        regs.chain(flags).map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn write_regs(&mut self, args: &str) -> String {
+        match parse_hex_bytes(args) {
+            Some(ref bytes) if bytes.len() == reg_count::<M>() => {
+                for (num, &data) in bytes.iter().enumerate() {
+                    self.set_reg(num, data);
+                }
+                "OK".to_owned()
+            },
+            _ => "E01".to_owned(),
+        }
+    }
+
+    fn read_reg(&self, args: &str) -> String {
+        let num = match usize::from_str_radix(args, 16) {
+            Ok(num) => num,
+            Err(_) => return "E01".to_owned(),
+        };
+        let regs = M::Register::ALL;
+        match (regs.get(num), num.checked_sub(regs.len())) {
+            (Some(&reg), _) => format!("{:02x}", self.vm.register(reg)),
+            (None, Some(num)) if num < M::Flag::ALL.len() => {
+                format!("{:02x}", self.vm.flag(M::Flag::ALL[num]) as u8)
+            },
+            _ => "E01".to_owned(),
+        }
+    }
+
+    fn write_reg(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let num = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+        let data = parts.next().and_then(parse_hex_bytes);
+        match (num, data.as_deref()) {
+            (Some(num), Some(&[data])) if num < reg_count::<M>() => {
+                self.set_reg(num, data);
+                "OK".to_owned()
+            },
+            _ => "E01".to_owned(),
+        }
+    }
+
+    /// Sets the register or flag with the given number.
+    fn set_reg(&mut self, num: usize, data: u8) {
+        let regs = M::Register::ALL;
+        match regs.get(num) {
+            Some(&reg) => self.vm.set_register(reg, data),
+            None => self.vm.set_flag(M::Flag::ALL[num - regs.len()], data != 0),
+        }
+    }
+
+    fn read_mem(&self, args: &str) -> String {
+        match parse_range(args) {
+            Some((addr, len)) => {
+                let mem = &self.vm.core().mem;
+                mem[addr .. addr + len]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            },
+            None => "E01".to_owned(),
+        }
+    }
+
+    fn write_mem(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_range);
+        let data = parts.next().and_then(parse_hex_bytes);
+        match (range, data) {
+            (Some((addr, len)), Some(data)) if data.len() == len => {
+                for (i, &byte) in data.iter().enumerate() {
+                    self.vm.write_raw((addr + i) as u8, byte);
+                }
+                "OK".to_owned()
+            },
+            _ => "E01".to_owned(),
+        }
+    }
+
+    /// Inserts or removes a software breakpoint, given as `0,addr,kind`.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        if parts.next() != Some("0") {
+            return String::new();
+        }
+        let addr = parts.next().and_then(|a| u8::from_str_radix(a, 16).ok());
+        match addr {
+            Some(addr) if insert => self.vm.add_breakpoint(addr),
+            Some(addr) => {
+                self.vm.remove_breakpoint(addr);
+            },
+            None => return "E01".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    /// Receives the next packet, or `None` if the connection was closed.
+    /// Interrupts and acknowledgements are skipped, and corrupted packets
+    /// are asked again.
+    fn recv<S>(&self, mut stream: S) -> Fallible<Option<String>>
+    where
+        S: Read + Write,
+    {
+        loop {
+            match read_byte(&mut stream)? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match read_byte(&mut stream)? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                if self.ack {
+                    stream.write_all(b"+")?;
+                }
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            } else if self.ack {
+                stream.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send<S>(&self, mut stream: S, reply: &str) -> Fallible<()>
+    where
+        S: Write,
+    {
+        let checksum = checksum_of(reply.as_bytes());
+        write!(stream, "${}#{:02x}", reply, checksum)?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Target description with the registers and flags of the machine, one byte
+/// each, numbered as in the `g` packet.
+fn target_xml<M>() -> String
+where
+    M: Machine,
+{
+    let mut xml = format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \
+         \"gdb-target.dtd\">\n<target version=\"1.0\">\n<feature \
+         name=\"org.niahu.{}\">\n",
+        M::NAME
+    );
+    for reg in M::Register::ALL {
+        let kind = if reg.name() == "pc" { "code_ptr" } else { "uint8" };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"8\" type=\"{}\"/>\n",
+            reg.name(),
+            kind
+        ));
+    }
+    for flag in M::Flag::ALL {
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"8\" type=\"bool\"/>\n",
+            flag.name()
+        ));
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+/// Number of registers and flags of the machine.
+fn reg_count<M>() -> usize
+where
+    M: Machine,
+{
+    M::Register::ALL.len() + M::Flag::ALL.len()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Reads every byte available without waiting, telling whether one of them
+/// is an interrupt. A closed connection counts as one.
+fn read_interrupt<R>(mut input: R) -> Fallible<bool>
+where
+    R: Read,
+{
+    let mut interrupted = false;
+    let mut buf = [0; 64];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(count) => interrupted |= buf[.. count].contains(&INTERRUPT),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                return Ok(interrupted)
+            },
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+fn read_byte<R>(mut input: R) -> Fallible<Option<u8>>
+where
+    R: Read,
+{
+    let mut byte = [0];
+    match input.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(byte[0])),
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Parses bytes given as pairs of hexadecimal digits.
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0 .. text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i .. i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses a memory range given as `addr,len` in hexadecimal, which must fit
+/// in the 256 bytes of memory.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    (addr.checked_add(len)? <= 256).then_some((addr, len))
+}
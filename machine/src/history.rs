@@ -0,0 +1,161 @@
+use crate::{
+    undo::{self, Context, Undo},
+    Access,
+    Expr,
+    Machine,
+};
+use std::mem;
+
+/// A cycle that wrote into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteEvent {
+    /// Number of the cycle.
+    pub cycle: u64,
+    /// Address of the instruction that wrote.
+    pub pc: u8,
+    pub addr: u8,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// A value an expression took, and the cycle that gave it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueEvent {
+    /// Number of the cycle after which the expression had this value.
+    pub cycle: u64,
+    /// Address of the instruction executed by the cycle, or `None` for the
+    /// value at the start of the history.
+    pub pc: Option<u8>,
+    pub value: i64,
+}
+
+/// A recorded execution, rebuilt from the undo log of a machine. Cycles
+/// still kept in full are read from the log; older ones are run again from
+/// a snapshot, as `back` does. Only the state at the start is kept in full;
+/// the states after each cycle are rebuilt from it when needed.
+#[derive(Debug, Clone)]
+pub struct History<M>
+where
+    M: Machine,
+{
+    /// State at the start of the recorded history.
+    pub start: M,
+    /// Each recorded cycle, oldest first, with the context before it and
+    /// the bytes it wrote.
+    pub steps: Vec<Undo>,
+    /// Context after the last recorded cycle.
+    pub end: Context,
+}
+
+impl<M> History<M>
+where
+    M: Machine,
+{
+    /// History of the given machine, or `None` if it recorded nothing. The
+    /// cycles before the kept records are rebuilt by running the machine
+    /// again from the latest snapshot before them, which gives the same
+    /// cycles, since a machine has nothing but its own state as input.
+    pub fn of(vm: &M) -> Option<Self> {
+        vm.core().undo.as_ref()?;
+        let end = Context::of(vm);
+        let mut vm = vm.clone();
+        let mut steps = Vec::new();
+        while let Some(undo) = undo::undo_cycle(&mut vm) {
+            steps.push(undo);
+        }
+        vm.core_mut().undo = None;
+
+        steps.reverse();
+        Some(Self { start: vm, steps, end })
+    }
+
+    /// Calls the given function with each recorded cycle and the state after
+    /// it, oldest first. The states are rebuilt one after the other on a
+    /// single copy of the start.
+    pub fn replay<F>(&self, mut visit: F)
+    where
+        F: FnMut(&Undo, &M),
+    {
+        let mut vm = self.start.clone();
+        let after = self
+            .steps
+            .iter()
+            .skip(1)
+            .map(|undo| &undo.context)
+            .chain(Some(&self.end));
+        for (undo, context) in self.steps.iter().zip(after) {
+            for &access in &undo.accesses {
+                if let Access::Write { addr, new, .. } = access {
+                    vm.core_mut().mem[addr as usize] = new;
+                }
+            }
+            context.restore(&mut vm);
+            visit(undo, &vm);
+        }
+    }
+
+    /// Every recorded write to the given address, oldest first.
+    pub fn writes(&self, addr: u8) -> Vec<WriteEvent> {
+        let mut events = Vec::new();
+        for undo in &self.steps {
+            for &access in &undo.accesses {
+                if let Access::Write { addr: written, old, new } = access {
+                    if written == addr {
+                        events.push(WriteEvent {
+                            cycle: undo.context.cycles + 1,
+                            pc: undo.instr.addr,
+                            addr,
+                            old,
+                            new,
+                        });
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Every value the expression held, starting with its value at the
+    /// start of the history, then each change, oldest first.
+    pub fn values(&self, expr: &Expr) -> Vec<ValueEvent> {
+        let baseline = expr.baseline(&self.start);
+        let mut events = vec![ValueEvent {
+            cycle: self.start.core().cycles,
+            pc: None,
+            value: expr.eval(&self.start, &baseline),
+        }];
+
+        self.replay(|undo, state| {
+            let value = expr.eval(state, &baseline);
+            if events.last().is_some_and(|last| last.value != value) {
+                events.push(ValueEvent {
+                    cycle: state.core().cycles,
+                    pc: Some(undo.instr.addr),
+                    value,
+                });
+            }
+        });
+        events
+    }
+
+    /// First cycle after which the expression holds, having not held before
+    /// it.
+    pub fn first_became(&self, expr: &Expr) -> Option<ValueEvent> {
+        let mut values = self.values(expr).into_iter();
+        let mut held = values.next().is_some_and(|start| start.value != 0);
+        values.find(|event| {
+            let holds = event.value != 0;
+            !mem::replace(&mut held, holds) && holds
+        })
+    }
+
+    /// Number of recorded cycles.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Tests whether no cycle was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
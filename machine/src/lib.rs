@@ -0,0 +1,1089 @@
+#[cfg(test)]
+mod test;
+
+/// Generic command line driver shared by the simulators.
+pub mod cli;
+
+/// Detection of infinite loops.
+pub mod loops;
+
+/// Records of executed cycles.
+pub mod step;
+
+/// Watchpoints over memory accesses.
+pub mod watch;
+
+/// Expressions for conditional breakpoints and invariants.
+pub mod expr;
+
+/// Interactive debugger.
+pub mod debugger;
+
+/// Recording of executed cycles so they can be undone.
+pub mod undo;
+
+/// Queries over recorded executions.
+pub mod history;
+
+/// Traces of executions.
+pub mod trace;
+
+/// Lockstep comparison of two machines.
+pub mod diff;
+
+/// GDB remote serial protocol stub.
+pub mod gdb;
+
+/// Debug Adapter Protocol server.
+pub mod dap;
+
+/// Full-screen terminal interface.
+pub mod tui;
+
+/// Execution profiles per instruction and address.
+pub mod profile;
+
+/// Memory coverage of executions.
+pub mod coverage;
+
+/// Self-modifying code detection.
+pub mod smc;
+
+pub use coverage::Coverage;
+pub use expr::Expr;
+pub use history::History;
+pub use loops::{Loop, LoopDetector};
+pub use profile::Profile;
+pub use smc::{SmcAllow, SmcDetector, SmcHit};
+pub use step::{Access, Instr, InstrInfo, Regs, Step, StopReason};
+pub use undo::UndoLog;
+pub use watch::{WatchHit, WatchKind, Watchpoint};
+
+use error::{
+    ExtensionMismatch,
+    Fallible,
+    InvalidFile,
+    UnknownExtension,
+    WithPath,
+    WrongHeader,
+};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    fs::File,
+    hash::Hasher,
+    io::{Read, Write},
+    mem,
+    path::Path,
+};
+
+pub fn is_mem_file<P>(path: &P) -> bool
+where
+    P: AsRef<Path> + ?Sized,
+{
+    path.as_ref().extension().is_some_and(|ext| ext == "mem")
+}
+
+pub fn is_state_file<P>(path: &P) -> bool
+where
+    P: AsRef<Path> + ?Sized,
+{
+    path.as_ref().extension().is_some_and(|ext| ext == "state")
+}
+
+/// Kind of a file holding a machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A `.mem` file, with only the memory.
+    Mem,
+    /// A `.state` file, with registers, statistics and memory.
+    State,
+}
+
+impl FileKind {
+    /// Guesses the kind of the file from its extension.
+    pub fn from_path<P>(path: &P) -> Option<Self>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        if is_mem_file(path) {
+            Some(FileKind::Mem)
+        } else if is_state_file(path) {
+            Some(FileKind::State)
+        } else {
+            None
+        }
+    }
+
+    /// Extension of files of this kind.
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileKind::Mem => "mem",
+            FileKind::State => "state",
+        }
+    }
+
+    /// Checks that the extension of the given path, if known, agrees with
+    /// this kind.
+    pub fn check_path<P>(self, path: &P) -> Fallible<()>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        match Self::from_path(path) {
+            Some(kind) if kind != self => Err(ExtensionMismatch {
+                extension: kind.extension(),
+                kind: self.extension(),
+            })?,
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the 4-byte header of the file at the given path.
+pub fn read_header<P>(path: &P) -> Fallible<[u8; 4]>
+where
+    P: AsRef<Path> + ?Sized,
+{
+    let mut header = [0; 4];
+    let res = File::open(path.as_ref())
+        .and_then(|mut file| file.read_exact(&mut header));
+
+    match res {
+        Ok(()) => Ok(header),
+        Err(error) => {
+            Err(WithPath { path: path.as_ref().into(), error: error.into() })?
+        },
+    }
+}
+
+/// A register of a machine, as shown to the user.
+pub trait Register: Copy + Eq + fmt::Debug + 'static {
+    /// Every register of the machine, in display order.
+    const ALL: &'static [Self];
+
+    /// Lowercase name of the register, such as `ac`.
+    fn name(self) -> &'static str;
+}
+
+/// A flag of a machine, as shown to the user.
+pub trait Flag: Copy + Eq + fmt::Debug + 'static {
+    /// Every flag of the machine, in display order.
+    const ALL: &'static [Self];
+
+    /// Lowercase name of the flag, such as `n`.
+    fn name(self) -> &'static str;
+}
+
+/// State shared by every machine: instruction register, program counter,
+/// memory and statistics.
+#[derive(Debug, Clone, Eq)]
+pub struct Core {
+    pub ri: u8,
+    pub pc: u8,
+    pub mem: [u8; 256],
+    pub cycling: bool,
+    pub cycles: u64,
+    pub accesses: u64,
+    /// Maximum number of cycles a single execution may run, if limited.
+    pub max_cycles: Option<u64>,
+    /// Whether executions stop when the machine state repeats.
+    pub detect_loops: bool,
+    /// Addresses where executions stop before fetching.
+    pub breakpoints: BTreeSet<u8>,
+    /// Breakpoint that stopped the last execution, if any.
+    pub break_hit: Option<u8>,
+    /// Memory accesses that stop executions.
+    pub watchpoints: Vec<Watchpoint>,
+    /// Conditions that stop executions before fetching when they hold.
+    pub conditions: Vec<Expr>,
+    /// Conditions that stop executions after a cycle that breaks them.
+    pub invariants: Vec<Expr>,
+    /// Memory accesses of the current cycle.
+    pub log: Vec<Access>,
+    /// Record of executed cycles, if enabled.
+    pub undo: Option<UndoLog>,
+    /// Detector of writes into code, if enabled.
+    pub smc: Option<SmcDetector>,
+    /// Whether executions stop before undefined instructions, instead of
+    /// running them as the official simulators do.
+    pub strict: bool,
+}
+
+impl Core {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, addr: u8) -> u8 {
+        self.accesses = self.accesses.saturating_add(1);
+        let data = self.mem[addr as usize];
+        self.log.push(Access::Read { addr, data });
+        data
+    }
+
+    pub fn write(&mut self, addr: u8, data: u8) {
+        self.accesses = self.accesses.saturating_add(1);
+        let old = self.mem[addr as usize];
+        self.mem[addr as usize] = data;
+        self.log.push(Access::Write { addr, old, new: data });
+    }
+
+    pub fn fetch(&mut self) {
+        self.ri = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+    }
+}
+
+/// Leaves out what only lives while running: the accesses of the current
+/// cycle, the undo log and the self-modifying code detector.
+impl PartialEq for Core {
+    fn eq(&self, other: &Self) -> bool {
+        self.ri == other.ri
+            && self.pc == other.pc
+            && self.mem == other.mem
+            && self.cycling == other.cycling
+            && self.cycles == other.cycles
+            && self.accesses == other.accesses
+            && self.max_cycles == other.max_cycles
+            && self.detect_loops == other.detect_loops
+            && self.breakpoints == other.breakpoints
+            && self.break_hit == other.break_hit
+            && self.watchpoints == other.watchpoints
+            && self.conditions == other.conditions
+            && self.invariants == other.invariants
+            && self.strict == other.strict
+    }
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        Self {
+            ri: 0,
+            pc: 0,
+            mem: [0; 256],
+            cycling: false,
+            cycles: 0,
+            accesses: 0,
+            max_cycles: None,
+            detect_loops: false,
+            breakpoints: BTreeSet::new(),
+            break_hit: None,
+            watchpoints: Vec::new(),
+            conditions: Vec::new(),
+            invariants: Vec::new(),
+            log: Vec::new(),
+            undo: None,
+            smc: None,
+            strict: false,
+        }
+    }
+}
+
+/// A hypothetical machine. Implementors provide the architecture-specific
+/// registers and instruction set, everything else is shared.
+pub trait Machine: Default + Clone + fmt::Debug + Eq {
+    /// Registers of this machine.
+    type Register: Register;
+    /// Flags of this machine.
+    type Flag: Flag;
+
+    /// Lowercase name of the machine, such as `neander`.
+    const NAME: &'static str;
+    /// Name of the machine for display, such as `Neander`.
+    const TITLE: &'static str;
+    /// Header of `.mem` files.
+    const MEM_HEADER: [u8; 4];
+    /// Header of `.state` files.
+    const STATE_HEADER: [u8; 4];
+    /// Headers of older versions of `.state` files, which can still be
+    /// loaded.
+    const OLD_STATE_HEADERS: &'static [[u8; 4]] = &[];
+
+    fn core(&self) -> &Core;
+
+    fn core_mut(&mut self) -> &mut Core;
+
+    /// Decodes and executes the instruction in the instruction register.
+    fn decode_exec(&mut self);
+
+    fn register(&self, reg: Self::Register) -> u8;
+
+    fn set_register(&mut self, reg: Self::Register, data: u8);
+
+    fn flag(&self, flag: Self::Flag) -> bool;
+
+    /// Sets a flag. Flags computed from registers are left as they are.
+    fn set_flag(&mut self, flag: Self::Flag, value: bool);
+
+    /// Saves the architecture-specific registers into a state file.
+    fn save_regs<W>(&self, output: W) -> Fallible<()>
+    where
+        W: Write;
+
+    /// Loads the architecture-specific registers from a state file.
+    fn load_regs<R>(&mut self, input: R) -> Fallible<()>
+    where
+        R: Read;
+
+    /// Loads the architecture-specific registers from a state file with one
+    /// of the old headers.
+    fn load_old_regs<R>(&mut self, _header: [u8; 4], input: R) -> Fallible<()>
+    where
+        R: Read,
+    {
+        self.load_regs(input)
+    }
+
+    /// Static information about the given opcode, if defined.
+    fn instr_info(opcode: u8) -> Option<InstrInfo>;
+
+    /// Tests whether the given opcode is a defined instruction. Undefined
+    /// instructions run as the official simulators run them, unless
+    /// executions are strict.
+    fn is_defined(opcode: u8) -> bool {
+        Self::instr_info(opcode).is_some()
+    }
+
+    /// Writes the mnemonic (and operand) of the given instruction, returning
+    /// whether the instruction has an operand.
+    fn debug_mnemonic<W>(
+        output: W,
+        instruction: u8,
+        operand: u8,
+        hex: bool,
+    ) -> Fallible<bool>
+    where
+        W: Write;
+
+    /// Decodes the instruction at the given address, without counting memory
+    /// accesses.
+    fn decode(&self, addr: u8) -> Instr {
+        let mem = &self.core().mem;
+        let opcode = mem[addr as usize];
+        let info = Self::instr_info(opcode);
+        Instr {
+            addr,
+            opcode,
+            operand: info
+                .filter(|info| info.operand)
+                .map(|_| mem[addr.wrapping_add(1) as usize]),
+            mnemonic: info.map(|info| info.mnemonic),
+        }
+    }
+
+    /// Address the operand of the given instruction refers to, computed from
+    /// the current state before the instruction is executed.
+    fn effective_addr(&self, instr: &Instr) -> Option<u8> {
+        instr.operand
+    }
+
+    /// Whether the given instruction is a conditional jump that jumps with
+    /// the given registers and flags, those before it is executed. `None`
+    /// for any other instruction.
+    fn jump_taken(_instr: &Instr, _before: &Regs<Self>) -> Option<bool> {
+        None
+    }
+
+    /// Disassembles the given instruction, such as `ADD 81`.
+    fn format_instr(instr: &Instr) -> String {
+        match (instr.mnemonic, instr.operand) {
+            (Some(mnemonic), Some(operand)) => {
+                format!("{} {:02X}", mnemonic, operand)
+            },
+            (Some(mnemonic), None) => mnemonic.to_owned(),
+            (None, _) => format!("??? {:02X}", instr.opcode),
+        }
+    }
+
+    /// Values of every register and flag.
+    fn regs(&self) -> Regs<Self> {
+        Regs {
+            regs: Self::Register::ALL
+                .iter()
+                .map(|&reg| (reg, self.register(reg)))
+                .collect(),
+            flags: Self::Flag::ALL
+                .iter()
+                .map(|&flag| (flag, self.flag(flag)))
+                .collect(),
+        }
+    }
+
+    /// Kind of the file with the given header, if it belongs to this machine.
+    fn file_kind(header: [u8; 4]) -> Option<FileKind> {
+        if header == Self::MEM_HEADER {
+            Some(FileKind::Mem)
+        } else if header == Self::STATE_HEADER
+            || Self::OLD_STATE_HEADERS.contains(&header)
+        {
+            Some(FileKind::State)
+        } else {
+            None
+        }
+    }
+
+    fn read(&mut self, addr: u8) -> u8 {
+        self.core_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u8, data: u8) {
+        self.core_mut().write(addr, data)
+    }
+
+    fn pc(&self) -> u8 {
+        self.core().pc
+    }
+
+    fn set_pc(&mut self, data: u8) {
+        let core = self.core_mut();
+        core.pc = data;
+        core.cycles = 0;
+        core.accesses = 0;
+    }
+
+    fn write_raw(&mut self, addr: u8, data: u8) {
+        self.core_mut().mem[addr as usize] = data;
+    }
+
+    /// Runs a single cycle, returning what it did.
+    fn cycle(&mut self) -> Step<Self> {
+        let instr = self.decode(self.pc());
+        let addr = self.effective_addr(&instr);
+        let before = self.regs();
+        let mut log = self.core_mut().undo.take();
+        let context = log.as_mut().map(|log| {
+            let context = undo::Context::of(self);
+            log.snapshot_if_due(self, &context);
+            context
+        });
+        self.core_mut().undo = log;
+
+        let core = self.core_mut();
+        core.log.clear();
+        core.break_hit = None;
+        core.cycles += 1;
+        self.fetch();
+        self.decode_exec();
+
+        let step = Step {
+            cycle: self.core().cycles,
+            instr,
+            addr,
+            before,
+            after: self.regs(),
+            accesses: mem::take(&mut self.core_mut().log),
+        };
+        if let Some(smc) = &mut self.core_mut().smc {
+            smc.record(&step);
+        }
+        if let (Some(context), Some(log)) = (context, &mut self.core_mut().undo)
+        {
+            let accesses = step.accesses.clone();
+            log.push(undo::Undo { context, instr, accesses });
+        }
+        step
+    }
+
+    /// Enables or disables recording cycles so they can be undone. Enabling
+    /// it again keeps what was recorded.
+    fn set_recording(&mut self, recording: bool) {
+        let core = self.core_mut();
+        if !recording {
+            core.undo = None;
+        } else if core.undo.is_none() {
+            core.undo = Some(UndoLog::new());
+        }
+    }
+
+    /// Undoes up to the given number of recorded cycles, returning how many
+    /// were undone.
+    fn step_back(&mut self, cycles: u64) -> u64 {
+        let mut count = 0;
+        while count < cycles && undo::undo_cycle(self).is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Undoes recorded cycles until the previous breakpoint, watchpoint hit
+    /// or holding condition, or until the cycle limit is reached. Returns
+    /// `None` if the start of the recorded history is reached first. An
+    /// execution stopped at a breakpoint or condition this way resumes past
+    /// it. Invariants are not checked.
+    fn reverse_execute(&mut self) -> Option<StopReason> {
+        let max_cycles = self.core().max_cycles;
+        undo::reverse_execute(self, max_cycles)
+    }
+
+    /// Limits how many cycles a single execution may run.
+    fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.core_mut().max_cycles = max_cycles;
+    }
+
+    /// Enables stopping after a write into memory fetched before as an
+    /// instruction, with the given exceptions, or disables it.
+    fn set_smc_detection(&mut self, allow: Option<Vec<SmcAllow>>) {
+        self.core_mut().smc = allow.map(SmcDetector::new);
+    }
+
+    /// Enables or disables stopping before undefined instructions.
+    fn set_strict(&mut self, strict: bool) {
+        self.core_mut().strict = strict;
+    }
+
+    /// Why a strict execution stops before the instruction at the program
+    /// counter, if it is undefined.
+    fn strict_stop(&self) -> Option<StopReason> {
+        let core = self.core();
+        if core.strict && !Self::is_defined(core.mem[core.pc as usize]) {
+            Some(StopReason::Undefined(self.decode(core.pc)))
+        } else {
+            None
+        }
+    }
+
+    /// Enables or disables stopping when the machine state repeats.
+    fn set_detect_loops(&mut self, detect_loops: bool) {
+        self.core_mut().detect_loops = detect_loops;
+    }
+
+    /// Stops executions before fetching at the given address.
+    fn add_breakpoint(&mut self, addr: u8) {
+        self.core_mut().breakpoints.insert(addr);
+    }
+
+    /// Removes the breakpoint at the given address, returning whether there
+    /// was one.
+    fn remove_breakpoint(&mut self, addr: u8) -> bool {
+        self.core_mut().breakpoints.remove(&addr)
+    }
+
+    /// Stops executions after an instruction accesses memory as given,
+    /// unless the same watchpoint was already added.
+    fn add_watchpoint(&mut self, watch: Watchpoint) {
+        let watchpoints = &mut self.core_mut().watchpoints;
+        if !watchpoints.contains(&watch) {
+            watchpoints.push(watch);
+        }
+    }
+
+    /// Stops executions before fetching when the condition holds.
+    fn add_condition(&mut self, condition: Expr) {
+        self.core_mut().conditions.push(condition);
+    }
+
+    /// Stops executions after a cycle when the invariant no longer holds.
+    fn add_invariant(&mut self, invariant: Expr) {
+        self.core_mut().invariants.push(invariant);
+    }
+
+    /// Runs cycles until a HLT is executed, a breakpoint or the cycle limit
+    /// is reached, a watchpoint or a condition is triggered, an invariant is
+    /// broken or, if enabled, an infinite loop, self-modifying code or an
+    /// undefined instruction is detected. An execution stopped at a
+    /// breakpoint or condition resumes past it.
+    fn execute(&mut self) -> StopReason {
+        self.execute_with(|_, _| ())
+    }
+
+    /// Same as `execute`, but hands the record of each cycle to the given
+    /// function.
+    fn execute_with<F>(&mut self, mut on_step: F) -> StopReason
+    where
+        F: FnMut(&Self, &Step<Self>),
+    {
+        self.execute_until(|vm, step| {
+            on_step(vm, step);
+            false
+        })
+    }
+
+    /// Same as `execute_with`, but the given function may also stop the
+    /// execution, by returning `true`, after any other reason to stop.
+    fn execute_until<F>(&mut self, mut on_step: F) -> StopReason
+    where
+        F: FnMut(&Self, &Step<Self>) -> bool,
+    {
+        let core = self.core_mut();
+        core.cycling = true;
+        let resume = core.break_hit.take();
+        let start = core.cycles;
+        let conditions = core.conditions.clone();
+        let invariants = core.invariants.clone();
+        let cond_baselines = baselines(self, &conditions);
+        let inv_baselines = baselines(self, &invariants);
+        let mut detector = if self.core().detect_loops {
+            Some(LoopDetector::new(self))
+        } else {
+            None
+        };
+
+        loop {
+            let core = self.core();
+            if !core.cycling {
+                break StopReason::Halt;
+            }
+            if core.max_cycles.is_some_and(|max| core.cycles - start >= max) {
+                break StopReason::CycleLimit;
+            }
+            let pc = core.pc;
+            let resuming = core.cycles == start && resume == Some(pc);
+            if !resuming && core.breakpoints.contains(&pc) {
+                self.core_mut().break_hit = Some(pc);
+                break StopReason::Breakpoint(pc);
+            }
+            if let (false, Some(index)) = (
+                resuming,
+                conditions
+                    .iter()
+                    .zip(&cond_baselines)
+                    .position(|(cond, baseline)| cond.holds(self, baseline)),
+            ) {
+                self.core_mut().break_hit = Some(pc);
+                break StopReason::Condition(index);
+            }
+            if let Some(reason) = self.strict_stop() {
+                break reason;
+            }
+
+            let step = self.cycle();
+            let interrupt = on_step(self, &step);
+
+            if let Some(hit) = watch::first_hit(&self.core().watchpoints, &step)
+            {
+                break StopReason::Watch(hit);
+            }
+
+            if let Some(hit) = self.core().smc.as_ref().and_then(|s| s.hit()) {
+                break StopReason::SelfModify(hit);
+            }
+
+            if let Some(index) = invariants
+                .iter()
+                .zip(&inv_baselines)
+                .position(|(inv, baseline)| !inv.holds(self, baseline))
+            {
+                break StopReason::Invariant(index);
+            }
+
+            if let (true, Some(detector)) =
+                (self.core().cycling, detector.as_mut())
+            {
+                if let Some(found) = detector.check(self) {
+                    break StopReason::Loop(found);
+                }
+            }
+
+            if interrupt && self.core().cycling {
+                break StopReason::Interrupt;
+            }
+        }
+    }
+
+    fn fetch(&mut self) {
+        self.core_mut().fetch();
+    }
+
+    /// Feeds the architectural state (registers, flags and memory) into the
+    /// hasher. Statistics are left out.
+    fn hash_state<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        for &reg in Self::Register::ALL {
+            hasher.write_u8(self.register(reg));
+        }
+        for &flag in Self::Flag::ALL {
+            hasher.write_u8(self.flag(flag) as u8);
+        }
+        hasher.write(&self.core().mem);
+    }
+
+    /// Tests whether both machines have the same architectural state.
+    fn same_state(&self, other: &Self) -> bool {
+        let regs_eq = Self::Register::ALL
+            .iter()
+            .all(|&reg| self.register(reg) == other.register(reg));
+        let flags_eq = Self::Flag::ALL
+            .iter()
+            .all(|&flag| self.flag(flag) == other.flag(flag));
+        regs_eq && flags_eq && self.core().mem == other.core().mem
+    }
+
+    fn save_mem<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        output.write_all(&Self::MEM_HEADER)?;
+
+        for &byte in self.core().mem.iter() {
+            output.write_all(&[byte, 0x00])?;
+        }
+
+        Ok(())
+    }
+
+    fn load_mem<R>(&mut self, mut input: R) -> Fallible<()>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 4];
+
+        input.read_exact(&mut buf)?;
+
+        if buf != Self::MEM_HEADER {
+            Err(InvalidFile)?;
+        }
+
+        for byte in self.core_mut().mem.iter_mut() {
+            input.read_exact(&mut buf[.. 2])?;
+            *byte = buf[0];
+        }
+
+        Ok(())
+    }
+
+    fn save_state<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let core = self.core();
+
+        output.write_all(&Self::STATE_HEADER)?;
+
+        output.write_all(&[core.ri, core.pc])?;
+        self.save_regs(&mut output)?;
+        output.write_all(&[if core.cycling { 1 } else { 0 }])?;
+        output.write_all(&core.cycles.to_le_bytes())?;
+        output.write_all(&core.accesses.to_le_bytes())?;
+
+        for &byte in core.mem.iter() {
+            output.write_all(&[byte, 0x00])?;
+        }
+
+        let breakpoints = core.breakpoints.iter().cloned().collect::<Vec<_>>();
+        output.write_all(&(breakpoints.len() as u16).to_le_bytes())?;
+        output.write_all(&breakpoints)?;
+        match core.break_hit {
+            Some(addr) => output.write_all(&[1, addr])?,
+            None => output.write_all(&[0, 0])?,
+        }
+        save_exprs(&mut output, &core.conditions)?;
+        save_exprs(&mut output, &core.invariants)?;
+        match &core.undo {
+            Some(log) => {
+                output.write_all(&[1])?;
+                log.save(&mut output)?;
+            },
+            None => output.write_all(&[0])?,
+        }
+        save_watchpoints(&mut output, &core.watchpoints)?;
+        output.write_all(&[if core.strict { 1 } else { 0 }])?;
+
+        Ok(())
+    }
+
+    fn load_state<R>(&mut self, mut input: R) -> Fallible<()>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 8];
+
+        input.read_exact(&mut buf[.. 6])?;
+
+        let header = [buf[0], buf[1], buf[2], buf[3]];
+        self.core_mut().ri = buf[4];
+        self.core_mut().pc = buf[5];
+        if header == Self::STATE_HEADER {
+            self.load_regs(&mut input)?;
+        } else if Self::OLD_STATE_HEADERS.contains(&header) {
+            self.load_old_regs(header, &mut input)?;
+        } else {
+            Err(InvalidFile)?;
+        }
+
+        let core = self.core_mut();
+
+        input.read_exact(&mut buf[.. 1])?;
+        core.cycling = buf[0] != 0;
+
+        input.read_exact(&mut buf)?;
+        core.cycles = u64::from_le_bytes(buf);
+
+        input.read_exact(&mut buf)?;
+        core.accesses = u64::from_le_bytes(buf);
+
+        for byte in core.mem.iter_mut() {
+            input.read_exact(&mut buf[.. 2])?;
+            *byte = buf[0];
+        }
+
+        // Breakpoints come after the memory, so files without them are still
+        // valid.
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest)?;
+        core.breakpoints.clear();
+        core.break_hit = None;
+        core.conditions.clear();
+        core.invariants.clear();
+        core.undo = None;
+        core.watchpoints.clear();
+        core.strict = false;
+        if !rest.is_empty() {
+            let mut rest = &rest[..];
+            rest.read_exact(&mut buf[.. 2])?;
+            let count = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+            let mut breakpoints = vec![0; count];
+            rest.read_exact(&mut breakpoints)?;
+            core.breakpoints.extend(breakpoints);
+            rest.read_exact(&mut buf[.. 2])?;
+            if buf[0] != 0 {
+                core.break_hit = Some(buf[1]);
+            }
+            if !rest.is_empty() {
+                core.conditions = load_exprs::<Self, _>(&mut rest)?;
+                core.invariants = load_exprs::<Self, _>(&mut rest)?;
+            }
+            if !rest.is_empty() {
+                rest.read_exact(&mut buf[.. 1])?;
+                if buf[0] != 0 {
+                    core.undo = Some(UndoLog::load::<Self, _>(&mut rest)?);
+                }
+            }
+            if !rest.is_empty() {
+                core.watchpoints = load_watchpoints(&mut rest)?;
+            }
+            if !rest.is_empty() {
+                rest.read_exact(&mut buf[.. 1])?;
+                core.strict = buf[0] != 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves a memory or state file, according to the extension of the path.
+    fn save_at_path<P>(&self, path: &P) -> Fallible<()>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        let res = match FileKind::from_path(path) {
+            Some(kind) => File::create(path.as_ref())
+                .map_err(Into::into)
+                .and_then(|file| match kind {
+                    FileKind::Mem => self.save_mem(file),
+                    FileKind::State => self.save_state(file),
+                }),
+            None => Err(UnknownExtension.into()),
+        };
+
+        res.map_err(|error| {
+            WithPath { path: path.as_ref().into(), error }.into()
+        })
+    }
+
+    /// Loads a memory or state file, according to its header.
+    fn load_from_path<P>(&mut self, path: &P) -> Fallible<()>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        let header = read_header(path)?;
+
+        let res = match Self::file_kind(header) {
+            Some(kind) => kind.check_path(path).and_then(|()| {
+                let file = File::open(path.as_ref())?;
+                match kind {
+                    FileKind::Mem => self.load_mem(file),
+                    FileKind::State => self.load_state(file),
+                }
+            }),
+            None => Err(WrongHeader { machine: Self::NAME, header }.into()),
+        };
+
+        res.map_err(|error| {
+            WithPath { path: path.as_ref().into(), error }.into()
+        })
+    }
+
+    fn display_mem_data<W, B>(
+        &self,
+        bounds: B,
+        mut output: W,
+        hex: bool,
+    ) -> Fallible<()>
+    where
+        B: IntoIterator<Item = u8>,
+        W: Write,
+    {
+        let mem = &self.core().mem;
+
+        for addr in bounds {
+            if hex {
+                writeln!(output, "{:02X} = {:02X}", addr, mem[addr as usize])?
+            } else {
+                writeln!(output, "{:03} = {:03}", addr, mem[addr as usize])?
+            }
+        }
+
+        Ok(())
+    }
+
+    fn display_mem_opcodes<W, B>(
+        &self,
+        bounds: B,
+        mut output: W,
+        hex: bool,
+    ) -> Fallible<()>
+    where
+        B: IntoIterator<Item = u8>,
+        W: Write,
+    {
+        let mem = &self.core().mem;
+        let mut needs_operand = false;
+
+        for addr in bounds {
+            if hex {
+                write!(output, "{:02X} = {:02X}", addr, mem[addr as usize])?
+            } else {
+                write!(output, "{:03} = {:03}", addr, mem[addr as usize])?
+            }
+
+            if needs_operand {
+                needs_operand = false;
+            } else {
+                needs_operand = Self::debug_mnemonic(
+                    &mut output,
+                    mem[addr as usize],
+                    mem[addr.wrapping_add(1) as usize],
+                    hex,
+                )?;
+            }
+
+            writeln!(output)?;
+        }
+
+        Ok(())
+    }
+
+    fn display_registers<W>(&self, mut output: W, hex: bool) -> Fallible<()>
+    where
+        W: Write,
+    {
+        let regs = Self::Register::ALL
+            .iter()
+            .map(|&reg| (reg.name(), self.register(reg)));
+        let flags = Self::Flag::ALL
+            .iter()
+            .map(|&flag| (flag.name(), self.flag(flag) as u8));
+
+        for (name, data) in regs.chain(flags) {
+            if hex {
+                writeln!(output, "{:<2} = {:02X}", name, data)?;
+            } else {
+                writeln!(output, "{:<2} = {:03}", name, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn display_stats<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        writeln!(output, "cycles = {}", self.core().cycles)?;
+        writeln!(output, "accesses = {}", self.core().accesses)?;
+        if let Some(addr) = self.core().break_hit {
+            writeln!(output, "breakpoint = {:02X}h", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Values the `unchanged` parts of each expression compare against.
+fn baselines<M>(vm: &M, exprs: &[Expr]) -> Vec<Vec<i64>>
+where
+    M: Machine,
+{
+    exprs.iter().map(|expr| expr.baseline(vm)).collect()
+}
+
+/// Saves expressions into a state file, as text.
+fn save_exprs<W>(mut output: W, exprs: &[Expr]) -> Fallible<()>
+where
+    W: Write,
+{
+    output.write_all(&(exprs.len() as u16).to_le_bytes())?;
+    for expr in exprs {
+        let text = expr.to_string();
+        output.write_all(&(text.len() as u16).to_le_bytes())?;
+        output.write_all(text.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Loads expressions of the given machine from a state file.
+fn load_exprs<M, R>(mut input: R) -> Fallible<Vec<Expr>>
+where
+    M: Machine,
+    R: Read,
+{
+    let mut buf = [0; 2];
+    input.read_exact(&mut buf)?;
+    let count = u16::from_le_bytes(buf);
+
+    let mut exprs = Vec::new();
+    for _ in 0 .. count {
+        input.read_exact(&mut buf)?;
+        let mut text = vec![0; u16::from_le_bytes(buf) as usize];
+        input.read_exact(&mut text)?;
+        let text = String::from_utf8(text).map_err(|_| InvalidFile)?;
+        exprs.push(Expr::parse::<M>(&text, None)?);
+    }
+    Ok(exprs)
+}
+
+/// Saves watchpoints into a state file, as a count followed by the first
+/// and last address and the kind of each.
+fn save_watchpoints<W>(
+    mut output: W,
+    watchpoints: &[Watchpoint],
+) -> Fallible<()>
+where
+    W: Write,
+{
+    output.write_all(&(watchpoints.len() as u16).to_le_bytes())?;
+    for watch in watchpoints {
+        let kind = match watch.kind {
+            WatchKind::Read => 0,
+            WatchKind::Write => 1,
+            WatchKind::Change => 2,
+        };
+        output.write_all(&[watch.start, watch.end, kind])?;
+    }
+    Ok(())
+}
+
+/// Loads watchpoints from a state file.
+fn load_watchpoints<R>(mut input: R) -> Fallible<Vec<Watchpoint>>
+where
+    R: Read,
+{
+    let mut buf = [0; 3];
+    input.read_exact(&mut buf[.. 2])?;
+    let count = u16::from_le_bytes([buf[0], buf[1]]);
+
+    let mut watchpoints = Vec::new();
+    for _ in 0 .. count {
+        input.read_exact(&mut buf)?;
+        let kind = match buf[2] {
+            0 => WatchKind::Read,
+            1 => WatchKind::Write,
+            2 => WatchKind::Change,
+            _ => Err(InvalidFile)?,
+        };
+        watchpoints.push(Watchpoint { start: buf[0], end: buf[1], kind });
+    }
+    Ok(watchpoints)
+}
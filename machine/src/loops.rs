@@ -0,0 +1,80 @@
+use crate::Machine;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::Hasher,
+};
+
+/// A loop a machine will never leave: its state repeats exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loop {
+    /// Program counter when the loop is entered.
+    pub entry: u8,
+    /// Number of cycles of each iteration of the loop.
+    pub period: u64,
+}
+
+/// Detects infinite loops by remembering a hash of the machine state after
+/// each cycle. A hash seen twice is confirmed by replaying the execution, so
+/// a collision never reports a false loop.
+#[derive(Debug, Clone)]
+pub struct LoopDetector<M>
+where
+    M: Machine,
+{
+    /// Machine at the start of the detection, used to confirm repetitions.
+    initial: M,
+    /// First cycle (relative to the start) in which each state hash was seen.
+    seen: HashMap<u64, u64>,
+    /// Cycles run since the start.
+    elapsed: u64,
+}
+
+impl<M> LoopDetector<M>
+where
+    M: Machine,
+{
+    /// Starts detecting loops from the current state of the machine.
+    pub fn new(vm: &M) -> Self {
+        let mut seen = HashMap::new();
+        seen.insert(state_hash(vm), 0);
+        Self { initial: vm.clone(), seen, elapsed: 0 }
+    }
+
+    /// Registers the state after a cycle, returning the loop the machine is
+    /// in, if its state has been seen before.
+    pub fn check(&mut self, vm: &M) -> Option<Loop> {
+        self.elapsed += 1;
+        let hash = state_hash(vm);
+
+        match self.seen.get(&hash) {
+            Some(&first) if self.replay(first).same_state(vm) => Some(Loop {
+                entry: vm.pc(),
+                period: self.elapsed - first,
+            }),
+            Some(_) => None,
+            None => {
+                self.seen.insert(hash, self.elapsed);
+                None
+            },
+        }
+    }
+
+    /// Machine after the given number of cycles from the start.
+    fn replay(&self, cycles: u64) -> M {
+        let mut vm = self.initial.clone();
+        for _ in 0 .. cycles {
+            vm.cycle();
+        }
+        vm
+    }
+}
+
+/// Hash of the architectural state of the machine.
+fn state_hash<M>(vm: &M) -> u64
+where
+    M: Machine,
+{
+    let mut hasher = DefaultHasher::new();
+    vm.hash_state(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,198 @@
+use crate::{Access, Machine, Step};
+use error::Fallible;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+};
+
+/// Maximum number of loops shown in a report.
+const MAX_LOOPS: usize = 10;
+
+/// Mnemonic of the jump to a subroutine, which does not close a loop.
+const CALL: &str = "JSR";
+
+/// How often a conditional jump jumped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// A loop, given by a jump back to an earlier address. Returns from Ramses
+/// subroutines placed after their callers look like loops too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopStats {
+    /// First address of the loop, the target of the jump.
+    pub start: u8,
+    /// Address of the jump back.
+    pub end: u8,
+    /// Number of times the jump back was taken.
+    pub iterations: u64,
+    /// Instructions executed between the start and the jump, inclusive.
+    pub cycles: u64,
+}
+
+/// Counts of what an execution did, per mnemonic and per address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Number of cycles profiled.
+    pub cycles: u64,
+    /// Instructions executed per mnemonic, `???` for undefined opcodes.
+    pub mnemonics: BTreeMap<&'static str, u64>,
+    /// Instructions executed per address.
+    pub execs: BTreeMap<u8, u64>,
+    /// Outcomes of each conditional jump, by address.
+    pub branches: BTreeMap<u8, Branch>,
+    /// Jumps taken back to an earlier address, as `(from, to)`.
+    pub back_jumps: BTreeMap<(u8, u8), u64>,
+    /// Data reads per address, not counting instruction fetches.
+    pub reads: BTreeMap<u8, u64>,
+    /// Writes per address.
+    pub writes: BTreeMap<u8, u64>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the given cycle, given the machine right after it.
+    pub fn record<M>(&mut self, vm: &M, step: &Step<M>)
+    where
+        M: Machine,
+    {
+        let instr = &step.instr;
+        let mnemonic = instr.mnemonic.unwrap_or("???");
+        self.cycles += 1;
+        *self.mnemonics.entry(mnemonic).or_insert(0) += 1;
+        *self.execs.entry(instr.addr).or_insert(0) += 1;
+
+        let next = instr.addr.wrapping_add(instr.size());
+        let taken = M::jump_taken(instr, &step.before);
+        if let Some(taken) = taken {
+            let branch = self.branches.entry(instr.addr).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+        let jumped = taken.unwrap_or(vm.pc() != next);
+        let is_jump = mnemonic.starts_with('J') && mnemonic != CALL;
+        if is_jump && jumped && vm.pc() <= instr.addr {
+            let jump = (instr.addr, vm.pc());
+            *self.back_jumps.entry(jump).or_insert(0) += 1;
+        }
+
+        for &access in step.data_accesses() {
+            match access {
+                Access::Read { addr, .. } => {
+                    *self.reads.entry(addr).or_insert(0) += 1
+                },
+                Access::Write { addr, .. } => {
+                    *self.writes.entry(addr).or_insert(0) += 1
+                },
+            }
+        }
+    }
+
+    /// Loops found by jumps back, hottest first: the ones that executed the
+    /// most instructions.
+    pub fn loops(&self) -> Vec<LoopStats> {
+        let mut loops = self
+            .back_jumps
+            .iter()
+            .map(|(&(end, start), &iterations)| LoopStats {
+                start,
+                end,
+                iterations,
+                cycles: self.execs.range(start ..= end).map(|(_, n)| n).sum(),
+            })
+            .collect::<Vec<_>>();
+        loops.sort_by_key(|stats| (Reverse(stats.cycles), stats.start));
+        loops
+    }
+
+    /// Writes the report: counts per mnemonic and per address, outcomes of
+    /// conditional jumps, data accesses per address and the hottest loops.
+    /// Instructions are disassembled from the memory of the given machine.
+    pub fn write_report<M, W>(&self, vm: &M, mut output: W) -> Fallible<()>
+    where
+        M: Machine,
+        W: Write,
+    {
+        let percent =
+            |count: u64| 100.0 * count as f64 / self.cycles.max(1) as f64;
+        let instr_at = |addr: u8| M::format_instr(&vm.decode(addr));
+
+        writeln!(output, "instructions by mnemonic:")?;
+        let mut mnemonics = self.mnemonics.iter().collect::<Vec<_>>();
+        mnemonics.sort_by_key(|&(mnemonic, &count)| (Reverse(count), mnemonic));
+        for (mnemonic, &count) in mnemonics {
+            writeln!(
+                output,
+                "  {:<5} {:>10}  {:>5.1}%",
+                mnemonic,
+                count,
+                percent(count)
+            )?;
+        }
+
+        writeln!(output, "instructions by address:")?;
+        for (&addr, &count) in &self.execs {
+            let instr = instr_at(addr);
+            writeln!(output, "  {:02X}  {:<14} {:>10}", addr, instr, count)?;
+        }
+
+        if !self.branches.is_empty() {
+            writeln!(output, "conditional jumps:")?;
+        }
+        for (&addr, branch) in &self.branches {
+            writeln!(
+                output,
+                "  {:02X}  {:<14} taken {:>8}  not taken {:>8}",
+                addr,
+                instr_at(addr),
+                branch.taken,
+                branch.not_taken
+            )?;
+        }
+
+        let addrs = self
+            .reads
+            .keys()
+            .chain(self.writes.keys())
+            .collect::<BTreeSet<_>>();
+        if !addrs.is_empty() {
+            writeln!(output, "data accesses by address:")?;
+        }
+        for &addr in addrs {
+            let reads = self.reads.get(&addr).map_or(0, |&n| n);
+            let writes = self.writes.get(&addr).map_or(0, |&n| n);
+            writeln!(
+                output,
+                "  {:02X}  reads {:>8}  writes {:>8}",
+                addr, reads, writes
+            )?;
+        }
+
+        let loops = self.loops();
+        if !loops.is_empty() {
+            writeln!(output, "hottest loops:")?;
+        }
+        for stats in loops.iter().take(MAX_LOOPS) {
+            writeln!(
+                output,
+                "  {:02X}..{:02X}  {:>8} iterations  {:>10} cycles  {:>5.1}%",
+                stats.start,
+                stats.end,
+                stats.iterations,
+                stats.cycles,
+                percent(stats.cycles)
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{Instr, Machine, Step};
+use std::{collections::BTreeMap, fmt};
+
+/// Writes into code that are not reported, because they are intended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmcAllow {
+    /// Writes into this address range, inclusive.
+    Range(u8, u8),
+    /// Writes by instructions with this mnemonic, such as `JSR`.
+    Mnemonic(&'static str),
+}
+
+impl SmcAllow {
+    /// Tests whether the given write by the given instruction is allowed.
+    pub fn allows(&self, instr: &Instr, addr: u8) -> bool {
+        match *self {
+            SmcAllow::Range(start, end) => start <= addr && addr <= end,
+            SmcAllow::Mnemonic(mnemonic) => instr.mnemonic == Some(mnemonic),
+        }
+    }
+}
+
+/// A write into memory fetched before as an opcode or operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmcHit {
+    /// The instruction that wrote.
+    pub instr: Instr,
+    /// Address written.
+    pub addr: u8,
+    /// Value overwritten.
+    pub old: u8,
+    /// Value written.
+    pub new: u8,
+    /// Address of the instruction whose byte was overwritten, the last time
+    /// it was fetched.
+    pub code: u8,
+}
+
+impl fmt::Display for SmcHit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "self-modifying code at pc = {:02X}h: wrote {:02X}h over {:02X}h \
+             at {:02X}h, code of the instruction at {:02X}h",
+            self.instr.addr, self.new, self.old, self.addr, self.code
+        )
+    }
+}
+
+impl SmcHit {
+    /// Same as the display, followed by the text of the overwritten
+    /// instruction as it now reads in the given machine, such as `, now LDA
+    /// 80`.
+    pub fn describe<M>(&self, vm: &M) -> String
+    where
+        M: Machine,
+    {
+        format!("{}, now {}", self, M::format_instr(&vm.decode(self.code)))
+    }
+}
+
+/// Detects writes into memory fetched before as instructions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SmcDetector {
+    /// Writes not reported.
+    pub allow: Vec<SmcAllow>,
+    /// Address of the instruction of each byte fetched, the last time it was
+    /// fetched.
+    code: BTreeMap<u8, u8>,
+    /// Write of the last cycle into code, if any.
+    hit: Option<SmcHit>,
+}
+
+impl SmcDetector {
+    pub fn new(allow: Vec<SmcAllow>) -> Self {
+        Self { allow, code: BTreeMap::new(), hit: None }
+    }
+
+    /// Records the bytes fetched by the given cycle, then checks its writes,
+    /// including into its own bytes.
+    pub fn record<M>(&mut self, step: &Step<M>)
+    where
+        M: Machine,
+    {
+        let instr = step.instr;
+        for access in step.fetches() {
+            self.code.insert(access.addr(), instr.addr);
+        }
+
+        self.hit = step.writes().find_map(|(addr, old, new)| {
+            let code = *self.code.get(&addr)?;
+            if self.allow.iter().any(|allow| allow.allows(&instr, addr)) {
+                return None;
+            }
+            Some(SmcHit { instr, addr, old, new, code })
+        });
+    }
+
+    /// Write into code by the last cycle recorded, unless allowed.
+    pub fn hit(&self) -> Option<SmcHit> {
+        self.hit
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{Machine, Register as _};
+use std::fmt;
+
+/// Static information about an opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstrInfo {
+    pub mnemonic: &'static str,
+    pub operand: bool,
+}
+
+/// An instruction decoded from memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instr {
+    /// Address of the opcode.
+    pub addr: u8,
+    /// The opcode byte.
+    pub opcode: u8,
+    /// The operand byte, if the instruction has one.
+    pub operand: Option<u8>,
+    /// Mnemonic of the instruction, or `None` if the opcode is undefined.
+    pub mnemonic: Option<&'static str>,
+}
+
+impl Instr {
+    /// Number of bytes of this instruction.
+    pub fn size(&self) -> u8 {
+        if self.operand.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Raw bytes of this instruction.
+    pub fn bytes(&self) -> Vec<u8> {
+        Some(self.opcode).into_iter().chain(self.operand).collect()
+    }
+}
+
+/// A memory access performed during a cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Data was read from the address, including instruction fetches.
+    Read { addr: u8, data: u8 },
+    /// Data at the address was overwritten.
+    Write { addr: u8, old: u8, new: u8 },
+}
+
+impl Access {
+    /// Address accessed.
+    pub fn addr(self) -> u8 {
+        match self {
+            Access::Read { addr, .. } | Access::Write { addr, .. } => addr,
+        }
+    }
+}
+
+/// Values of every register and flag of a machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regs<M>
+where
+    M: Machine,
+{
+    pub regs: Vec<(M::Register, u8)>,
+    pub flags: Vec<(M::Flag, bool)>,
+}
+
+impl<M> Regs<M>
+where
+    M: Machine,
+{
+    /// Value of the given register.
+    pub fn get(&self, reg: M::Register) -> u8 {
+        self.regs
+            .iter()
+            .find(|&&(r, _)| r == reg)
+            .map_or(0, |&(_, v)| v)
+    }
+
+    /// Value of the given flag.
+    pub fn flag(&self, flag: M::Flag) -> bool {
+        self.flags.iter().any(|&(f, v)| f == flag && v)
+    }
+}
+
+/// Record of a single cycle: what was executed and what it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step<M>
+where
+    M: Machine,
+{
+    /// Number of the cycle, counting from the last reset of statistics.
+    pub cycle: u64,
+    /// The instruction executed.
+    pub instr: Instr,
+    /// Effective address of the operand, if any.
+    pub addr: Option<u8>,
+    /// Registers and flags before the cycle.
+    pub before: Regs<M>,
+    /// Registers and flags after the cycle.
+    pub after: Regs<M>,
+    /// Memory accesses, in order.
+    pub accesses: Vec<Access>,
+}
+
+impl<M> Step<M>
+where
+    M: Machine,
+{
+    /// Memory writes of this cycle, as `(addr, old, new)`.
+    pub fn writes(&self) -> impl Iterator<Item = (u8, u8, u8)> + '_ {
+        self.accesses.iter().filter_map(|&access| match access {
+            Access::Write { addr, old, new } => Some((addr, old, new)),
+            Access::Read { .. } => None,
+        })
+    }
+
+    /// Memory reads of this cycle, including fetches, as `(addr, data)`.
+    pub fn reads(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.accesses.iter().filter_map(|&access| match access {
+            Access::Read { addr, data } => Some((addr, data)),
+            Access::Write { .. } => None,
+        })
+    }
+
+    /// Accesses that fetched the bytes of the instruction, at the start of
+    /// the cycle.
+    pub fn fetches(&self) -> &[Access] {
+        &self.accesses[.. self.fetch_count()]
+    }
+
+    /// Accesses other than the fetches of the bytes of the instruction.
+    pub fn data_accesses(&self) -> &[Access] {
+        &self.accesses[self.fetch_count() ..]
+    }
+
+    fn fetch_count(&self) -> usize {
+        let addrs =
+            (0 .. self.instr.size()).map(|i| self.instr.addr.wrapping_add(i));
+        self.accesses
+            .iter()
+            .zip(addrs)
+            .take_while(|&(&access, addr)| {
+                matches!(access, Access::Read { .. }) && access.addr() == addr
+            })
+            .count()
+    }
+
+    /// Registers changed by this cycle, as `(register, before, after)`.
+    pub fn regs_changed(&self) -> Vec<(M::Register, u8, u8)> {
+        self.before
+            .regs
+            .iter()
+            .zip(&self.after.regs)
+            .filter(|(before, after)| before.1 != after.1)
+            .map(|(&(reg, before), &(_, after))| (reg, before, after))
+            .collect()
+    }
+
+    /// Flags changed by this cycle, as `(flag, before, after)`.
+    pub fn flags_changed(&self) -> Vec<(M::Flag, bool, bool)> {
+        self.before
+            .flags
+            .iter()
+            .zip(&self.after.flags)
+            .filter(|(before, after)| before.1 != after.1)
+            .map(|(&(flag, before), &(_, after))| (flag, before, after))
+            .collect()
+    }
+}
+
+/// Shows a cycle as its instruction followed by what it changed, such as
+/// `00  STA 80  ; pc = 02, [80] = 05`.
+impl<M> fmt::Display for Step<M>
+where
+    M: Machine,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let changes = self
+            .regs_changed()
+            .into_iter()
+            .map(|(reg, _, new)| format!("{} = {:02X}", reg.name(), new))
+            .chain(self.writes().map(|(addr, _, new)| {
+                format!("[{:02X}] = {:02X}", addr, new)
+            }))
+            .collect::<Vec<_>>();
+
+        let instr = M::format_instr(&self.instr);
+        write!(fmt, "{:02X}  {}", self.instr.addr, instr)?;
+        if !changes.is_empty() {
+            write!(fmt, "  ; {}", changes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Why an execution stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A HLT instruction was executed.
+    Halt,
+    /// The maximum number of cycles was reached before a HLT.
+    CycleLimit,
+    /// The program counter reached a breakpoint, at the given address.
+    Breakpoint(u8),
+    /// An instruction accessed memory under a watchpoint.
+    Watch(crate::WatchHit),
+    /// The condition with the given index held before fetching.
+    Condition(usize),
+    /// The invariant with the given index was broken by the last cycle.
+    Invariant(usize),
+    /// The machine state repeated, so it will never halt.
+    Loop(crate::Loop),
+    /// An instruction wrote into memory fetched before as an instruction.
+    SelfModify(crate::SmcHit),
+    /// The program counter reached an undefined instruction, in a strict
+    /// execution.
+    Undefined(Instr),
+    /// The execution was stopped from outside, such as by a debugger
+    /// client.
+    Interrupt,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Halt => write!(fmt, "halted"),
+            StopReason::CycleLimit => write!(fmt, "cycle limit reached"),
+            StopReason::Breakpoint(addr) => {
+                write!(fmt, "stopped at breakpoint {:02X}h", addr)
+            },
+            StopReason::Watch(hit) => write!(fmt, "{}", hit),
+            StopReason::SelfModify(hit) => write!(fmt, "{}", hit),
+            StopReason::Undefined(instr) => write!(
+                fmt,
+                "undefined instruction {:02X}h at pc = {:02X}h",
+                instr.opcode, instr.addr
+            ),
+            StopReason::Interrupt => write!(fmt, "interrupted"),
+            StopReason::Condition(index) => {
+                write!(fmt, "stopped at condition #{}", index)
+            },
+            StopReason::Invariant(index) => {
+                write!(fmt, "invariant #{} broken", index)
+            },
+            StopReason::Loop(found) => write!(
+                fmt,
+                "infinite loop from {:02X}h every {} cycles",
+                found.entry, found.period
+            ),
+        }
+    }
+}
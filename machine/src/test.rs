@@ -0,0 +1,1586 @@
+use crate::{
+    cli,
+    coverage::CoverageFormat,
+    dap::{self, DapServer},
+    debugger::Debugger,
+    diff::{self, Comparison, DiffOptions},
+    gdb::GdbStub,
+    trace::{TraceFormat, Tracer},
+    tui::{KeyCode, Style, Tui},
+    Access,
+    Core,
+    Coverage,
+    Expr,
+    FileKind,
+    History,
+    Instr,
+    InstrInfo,
+    Loop,
+    Machine,
+    Profile,
+    Regs,
+    SmcAllow,
+    StopReason,
+    WatchKind,
+    Watchpoint,
+};
+use error::Fallible;
+use std::{
+    env,
+    fs,
+    io::{Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    thread,
+};
+use structopt::StructOpt;
+
+// A minimal accumulator machine to test the shared code with, independent of
+// the instruction sets of the real machines.
+
+/// Opcode of NOP
+pub const NOP: u8 = 0x0;
+/// Opcode of LDA _addr_
+pub const LDA: u8 = 0x10;
+/// Opcode of STA _addr_
+pub const STA: u8 = 0x20;
+/// Opcode of ADD _addr_
+pub const ADD: u8 = 0x30;
+/// Opcode of NOT
+pub const NOT: u8 = 0x40;
+/// Opcode of JMP _addr_
+pub const JMP: u8 = 0x50;
+/// Opcode of JZ _addr_
+pub const JZ: u8 = 0x60;
+/// Opcode of HLT
+pub const HLT: u8 = 0xF0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Ac,
+    Pc,
+}
+
+impl crate::Register for Register {
+    const ALL: &'static [Self] = &[Register::Ac, Register::Pc];
+
+    fn name(self) -> &'static str {
+        match self {
+            Register::Ac => "ac",
+            Register::Pc => "pc",
+        }
+    }
+}
+
+/// Flags computed from the accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    N,
+    Z,
+}
+
+impl crate::Flag for Flag {
+    const ALL: &'static [Self] = &[Flag::N, Flag::Z];
+
+    fn name(self) -> &'static str {
+        match self {
+            Flag::N => "n",
+            Flag::Z => "z",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Toy {
+    pub core: Core,
+    pub ac: u8,
+}
+
+impl Toy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A machine with the given code at address 0.
+    pub fn with_code(code: &[u8]) -> Self {
+        let mut vm = Self::new();
+        vm.core.mem[.. code.len()].copy_from_slice(code);
+        vm
+    }
+}
+
+/// Program that keeps incrementing the byte at 80h.
+pub fn counter() -> Toy {
+    let mut vm = Toy::with_code(&[LDA, 0x80, ADD, 0x81, STA, 0x80, JMP, 0x0]);
+    vm.core.mem[0x81] = 1;
+    vm
+}
+
+impl Machine for Toy {
+    type Register = Register;
+    type Flag = Flag;
+
+    const NAME: &'static str = "toy";
+    const TITLE: &'static str = "Toy";
+    const MEM_HEADER: [u8; 4] = [0x03, 0x54, 0x4F, 0x59];
+    const STATE_HEADER: [u8; 4] = [0x04, 0x54, 0x4F, 0x59];
+
+    fn core(&self) -> &Core {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut Core {
+        &mut self.core
+    }
+
+    fn decode_exec(&mut self) {
+        let opcode = self.core.ri;
+        if Self::instr_info(opcode).is_some_and(|info| info.operand) {
+            self.fetch();
+        }
+        let addr = self.core.ri;
+        match opcode {
+            LDA => self.ac = self.read(addr),
+            STA => self.write(addr, self.ac),
+            ADD => self.ac = self.ac.wrapping_add(self.read(addr)),
+            NOT => self.ac = !self.ac,
+            JMP => self.core.pc = addr,
+            JZ if self.ac == 0 => self.core.pc = addr,
+            HLT => self.core.cycling = false,
+            _ => (),
+        }
+    }
+
+    fn register(&self, reg: Register) -> u8 {
+        match reg {
+            Register::Ac => self.ac,
+            Register::Pc => self.core.pc,
+        }
+    }
+
+    fn set_register(&mut self, reg: Register, data: u8) {
+        match reg {
+            Register::Ac => self.ac = data,
+            Register::Pc => self.core.pc = data,
+        }
+    }
+
+    fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::N => self.ac & 0x80 != 0,
+            Flag::Z => self.ac == 0,
+        }
+    }
+
+    fn set_flag(&mut self, _flag: Flag, _value: bool) {}
+
+    fn save_regs<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        output.write_all(&[self.ac])?;
+        Ok(())
+    }
+
+    fn load_regs<R>(&mut self, mut input: R) -> Fallible<()>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 1];
+        input.read_exact(&mut buf)?;
+        self.ac = buf[0];
+        Ok(())
+    }
+
+    fn instr_info(opcode: u8) -> Option<InstrInfo> {
+        let (mnemonic, operand) = match opcode {
+            NOP => ("NOP", false),
+            LDA => ("LDA", true),
+            STA => ("STA", true),
+            ADD => ("ADD", true),
+            NOT => ("NOT", false),
+            JMP => ("JMP", true),
+            JZ => ("JZ", true),
+            HLT => ("HLT", false),
+            _ => return None,
+        };
+        Some(InstrInfo { mnemonic, operand })
+    }
+
+    fn jump_taken(instr: &Instr, before: &Regs<Self>) -> Option<bool> {
+        match instr.opcode {
+            JZ => Some(before.flag(Flag::Z)),
+            _ => None,
+        }
+    }
+
+    fn debug_mnemonic<W>(
+        mut output: W,
+        instruction: u8,
+        _operand: u8,
+        _hex: bool,
+    ) -> Fallible<bool>
+    where
+        W: Write,
+    {
+        match Self::instr_info(instruction) {
+            Some(info) => {
+                write!(output, "  {}", info.mnemonic)?;
+                Ok(info.operand)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+#[test]
+fn cycle_limit() {
+    let mut vm = Toy::with_code(&[JMP, 0x0]);
+    vm.set_max_cycles(Some(50));
+
+    assert_eq!(vm.execute(), StopReason::CycleLimit);
+    assert_eq!(vm.core.cycles, 50);
+
+    vm.core.mem[0x0] = HLT;
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.core.cycles, 51);
+}
+
+#[test]
+fn detect_loops() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, ADD, 0x81, JMP, 0x2]);
+    vm.core.mem[0x81] = 1;
+    vm.set_detect_loops(true);
+
+    assert_eq!(
+        vm.execute(),
+        StopReason::Loop(Loop { entry: 0x2, period: 512 })
+    );
+    assert_eq!(vm.core.cycles, 513);
+}
+
+#[test]
+fn detect_loops_halt() {
+    let mut vm = Toy::with_code(&[HLT, JMP, 0x0]);
+    vm.core.pc = 0x1;
+    vm.set_detect_loops(true);
+
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn cycle_step() {
+    let mut vm = Toy::with_code(&[STA, 0x80]);
+    vm.core.mem[0x80] = 0x7;
+    vm.ac = 0x90;
+
+    let step = vm.cycle();
+
+    assert_eq!(step.cycle, 1);
+    assert_eq!(
+        step.instr,
+        Instr {
+            addr: 0x0,
+            opcode: STA,
+            operand: Some(0x80),
+            mnemonic: Some("STA"),
+        }
+    );
+    assert_eq!(Toy::format_instr(&step.instr), "STA 80");
+    assert_eq!(step.addr, Some(0x80));
+    assert_eq!(step.before.get(Register::Pc), 0x0);
+    assert_eq!(step.after.get(Register::Pc), 0x2);
+    assert_eq!(step.regs_changed(), vec![(Register::Pc, 0x0, 0x2)]);
+    assert!(step.flags_changed().is_empty());
+    assert_eq!(
+        step.accesses,
+        vec![
+            Access::Read { addr: 0x0, data: STA },
+            Access::Read { addr: 0x1, data: 0x80 },
+            Access::Write { addr: 0x80, old: 0x7, new: 0x90 },
+        ]
+    );
+    assert_eq!(step.writes().collect::<Vec<_>>(), vec![(0x80, 0x7, 0x90)]);
+}
+
+#[test]
+fn cycle_step_flags() {
+    let mut vm = Toy::with_code(&[NOT]);
+
+    let step = vm.cycle();
+
+    assert_eq!(step.addr, None);
+    assert_eq!(step.regs_changed()[0], (Register::Ac, 0x0, 0xFF));
+    assert_eq!(
+        step.flags_changed(),
+        vec![(Flag::N, false, true), (Flag::Z, true, false)]
+    );
+    assert_eq!(step.writes().count(), 0);
+}
+
+#[test]
+fn run_summary() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, HLT]);
+
+    let mut last = None;
+    let reason = vm.execute_with(|_, step| last = Some(step.instr));
+
+    assert_eq!(
+        cli::summary(&vm, reason, last.as_ref()),
+        "halted after 2 cycles, 4 accesses (last: 02 HLT)"
+    );
+}
+
+#[test]
+fn breakpoints() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, ADD, 0x80, HLT]);
+    vm.core.mem[0x80] = 3;
+    vm.add_breakpoint(0x2);
+
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x2));
+    assert_eq!(vm.core.pc, 0x2);
+    assert_eq!(vm.core.cycles, 1);
+    assert_eq!(vm.core.break_hit, Some(0x2));
+
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.ac, 6);
+    assert_eq!(vm.core.break_hit, None);
+}
+
+#[test]
+fn watchpoints() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, STA, 0x81, STA, 0x82, HLT]);
+    vm.core.mem[0x80] = 5;
+    vm.core.mem[0x81] = 5;
+    vm.add_watchpoint(Watchpoint {
+        start: 0x81,
+        end: 0x82,
+        kind: WatchKind::Change,
+    });
+
+    let hit = match vm.execute() {
+        StopReason::Watch(hit) => hit,
+        reason => panic!("unexpected stop: {:?}", reason),
+    };
+    assert_eq!(hit.instr.addr, 0x4);
+    assert_eq!(hit.access, Access::Write { addr: 0x82, old: 0, new: 5 });
+    assert_eq!(vm.core.pc, 0x6);
+
+    vm.core.pc = 0x0;
+    vm.core.watchpoints[0].kind = WatchKind::Write;
+    let reason = vm.execute();
+    assert!(matches!(reason, StopReason::Watch(hit) if hit.instr.addr == 0x2));
+
+    vm.core.watchpoints[0] =
+        Watchpoint { start: 0x80, end: 0x80, kind: WatchKind::Read };
+    vm.core.pc = 0x0;
+    let reason = vm.execute();
+    assert!(matches!(reason, StopReason::Watch(hit) if hit.instr.addr == 0x0));
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn conditions() {
+    let mut vm = Toy::with_code(&[ADD, 0x80, JMP, 0x0]);
+    vm.core.mem[0x80] = 3;
+    let cond = Expr::parse::<Toy>("pc == 0 && ac > 10h - 8", None);
+    vm.add_condition(cond.unwrap());
+
+    assert_eq!(vm.execute(), StopReason::Condition(0));
+    assert_eq!(vm.ac, 9);
+    assert_eq!(vm.core.break_hit, Some(0x0));
+
+    assert_eq!(vm.execute(), StopReason::Condition(0));
+    assert_eq!(vm.ac, 12);
+}
+
+#[test]
+fn invariants() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, STA, 0x81, STA, 0x82, HLT]);
+    vm.core.mem[0x80] = 7;
+    let inv = Expr::parse::<Toy>("mem[81h] unchanged || !z", None);
+    vm.add_invariant(inv.unwrap());
+    let inv = Expr::parse::<Toy>("mem[0x82] unchanged", None);
+    vm.add_invariant(inv.unwrap());
+
+    assert_eq!(vm.execute(), StopReason::Invariant(1));
+    assert_eq!(vm.core.pc, 0x6);
+}
+
+#[test]
+fn parse_exprs() {
+    let parse = |src| Expr::parse::<Toy>(src, None).map(|e| e.to_string());
+
+    assert_eq!(
+        parse("ac + 1 == 2 || n && mem[80h] != ~0x3").unwrap(),
+        "(((ac + 1) == 2) || (n && (mem[128] != ~3)))"
+    );
+    assert_eq!(parse("-(pc) unchanged").unwrap(), "-(pc unchanged)");
+    assert!(parse("rx == 0").is_err());
+    assert!(parse("LOOP").is_err());
+    assert!(parse("mem[80h").is_err());
+    assert!(parse("ac ==").is_err());
+    assert!(parse("ac 1").is_err());
+    assert!(parse("ac @ 1").is_err());
+
+    let mut info = debuginfo::DebugInfo::new();
+    let label = Some("LOOP".to_owned());
+    info.insert(0x8, debuginfo::SrcLine { file: "a".into(), line: 1, label });
+    let expr = Expr::parse::<Toy>("pc == LOOP", Some(&info)).unwrap();
+    assert_eq!(expr.to_string(), "(pc == 8)");
+}
+
+#[test]
+fn self_modifying_code() {
+    let code = [LDA, 0x80, STA, 0x1, LDA, 0x80, STA, 0x7, HLT];
+    let mut vm = Toy::with_code(&code);
+    vm.core.mem[0x80] = 0x80;
+    let start = vm.clone();
+
+    vm.set_smc_detection(Some(Vec::new()));
+    let hit = match vm.execute() {
+        StopReason::SelfModify(hit) => hit,
+        reason => panic!("unexpected {:?}", reason),
+    };
+    assert_eq!((hit.instr.addr, hit.addr, hit.code), (0x2, 0x1, 0x0));
+    assert_eq!((hit.old, hit.new), (0x80, 0x80));
+    assert_eq!(
+        hit.to_string(),
+        "self-modifying code at pc = 02h: wrote 80h over 80h at 01h, code of \
+         the instruction at 00h"
+    );
+    assert_eq!(
+        cli::summary(&vm, StopReason::SelfModify(hit), None),
+        "self-modifying code at pc = 02h: wrote 80h over 80h at 01h, code of \
+         the instruction at 00h, now LDA 80 after 2 cycles, 6 accesses"
+    );
+
+    let hit = match vm.execute() {
+        StopReason::SelfModify(hit) => hit,
+        reason => panic!("unexpected {:?}", reason),
+    };
+    assert_eq!((hit.instr.addr, hit.addr, hit.code), (0x6, 0x7, 0x6));
+    assert_eq!(vm.execute(), StopReason::Halt);
+
+    let mut vm = start.clone();
+    vm.set_smc_detection(Some(vec![SmcAllow::Range(0x0, 0x7)]));
+    assert_eq!(vm.execute(), StopReason::Halt);
+
+    let mut vm = start;
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn strict_execution() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, 0x70, ADD, 0x80, HLT]);
+    vm.core.mem[0x80] = 3;
+    let start = vm.clone();
+
+    vm.set_strict(true);
+    assert_eq!(vm.strict_stop(), None);
+    let reason = vm.execute();
+    assert_eq!(reason, StopReason::Undefined(vm.decode(0x2)));
+    assert_eq!(vm.strict_stop(), Some(reason));
+    assert_eq!(reason.to_string(), "undefined instruction 70h at pc = 02h");
+    assert_eq!((vm.pc(), vm.core.cycles), (0x2, 1));
+    assert_eq!(vm.execute(), reason);
+
+    let mut vm = start;
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.ac, 6);
+}
+
+/// Length of a state file as saved before breakpoints were added: header,
+/// ri and pc, registers, cycling flag, cycle and access counts and the
+/// memory, two bytes per address.
+fn old_state_len(vm: &Toy) -> usize {
+    let mut regs = Vec::new();
+    vm.save_regs(&mut regs).unwrap();
+    Toy::STATE_HEADER.len() + 2 + regs.len() + 1 + 8 + 8 + 2 * 256
+}
+
+#[test]
+fn save_load_mem() {
+    let mut vm = Toy::new();
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
+    let mut buf = Vec::new();
+    vm.save_mem(&mut buf).unwrap();
+    let mut vm2 = Toy::new();
+    vm2.load_mem(&mut &buf[..]).unwrap();
+    assert_eq!(&vm.core.mem as &[_], &vm2.core.mem as &[_]);
+}
+
+#[test]
+fn save_load_state() {
+    let mut vm = Toy::new();
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
+    vm.core.pc = 0x5;
+    vm.ac = 203;
+    vm.core.ri = 0x12;
+    vm.core.cycles = 2;
+    vm.core.accesses = 6;
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Toy::new();
+    vm2.load_state(&mut &buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+}
+
+#[test]
+fn display_registers() {
+    let mut vm = Toy::new();
+    vm.ac = 0x80;
+    vm.core.pc = 0x1F;
+    let mut buf = Vec::new();
+    vm.display_registers(&mut buf, true).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "ac = 80\npc = 1F\nn  = 01\nz  = 00\n"
+    );
+}
+
+#[test]
+fn file_kind() {
+    assert_eq!(Toy::file_kind(Toy::MEM_HEADER), Some(FileKind::Mem));
+    assert_eq!(Toy::file_kind(Toy::STATE_HEADER), Some(FileKind::State));
+    assert_eq!(Toy::file_kind([0x03, 0x52, 0x4D, 0x53]), None);
+    assert!(FileKind::Mem.check_path("prog.mem").is_ok());
+    assert!(FileKind::Mem.check_path("prog").is_ok());
+    assert!(FileKind::Mem.check_path("prog.state").is_err());
+}
+
+#[test]
+fn save_load_breakpoints() {
+    let mut vm = Toy::with_code(&[JMP, 0x0]);
+    vm.add_breakpoint(0x0);
+    vm.add_breakpoint(0x9);
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x0));
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Toy::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+
+    let mut old = Toy::new();
+    old.load_state(&buf[.. old_state_len(&vm)]).unwrap();
+    assert!(old.core.breakpoints.is_empty());
+    assert_eq!(old.core.break_hit, None);
+    assert_eq!(old.core.mem, vm.core.mem);
+    assert_eq!(old.core.cycles, vm.core.cycles);
+}
+
+#[test]
+fn save_load_exprs() {
+    let mut vm = Toy::new();
+    vm.add_condition(Expr::parse::<Toy>("ac > 3", None).unwrap());
+    vm.add_invariant(Expr::parse::<Toy>("mem[1] unchanged", None).unwrap());
+    vm.set_strict(true);
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Toy::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+    assert!(vm2.core.strict);
+
+    vm2.load_state(&buf[.. buf.len() - 1]).unwrap();
+    assert!(!vm2.core.strict);
+}
+
+#[test]
+fn save_load_undo() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    for _ in 0 .. 6 {
+        vm.cycle();
+    }
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Toy::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+    assert_eq!(vm.core.undo, vm2.core.undo);
+
+    assert_eq!(vm2.step_back(6), 6);
+    assert!(vm2.same_state(&counter()));
+}
+
+#[test]
+fn step_back() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    let start = vm.clone();
+    for _ in 0 .. 10 {
+        vm.cycle();
+    }
+    let middle = vm.clone();
+    for _ in 0 .. 3 {
+        vm.cycle();
+    }
+
+    assert_eq!(vm.step_back(3), 3);
+    assert!(vm.same_state(&middle));
+    assert_eq!(vm.core.cycles, 10);
+    assert_eq!(vm.step_back(20), 10);
+    assert!(vm.same_state(&start));
+    assert_eq!(vm.core.cycles, 0);
+    assert_eq!(vm.step_back(1), 0);
+}
+
+#[test]
+fn step_back_snapshots() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    if let Some(log) = vm.core.undo.as_mut() {
+        log.capacity = 4;
+        log.interval = 8;
+        log.max_snapshots = 2;
+    }
+    let mut states = vec![vm.clone()];
+    for _ in 0 .. 30 {
+        vm.cycle();
+        states.push(vm.clone());
+    }
+
+    let log = vm.core.undo.as_ref().unwrap();
+    assert_eq!(log.available(), 14);
+    assert_eq!(vm.step_back(5), 5);
+    assert!(vm.same_state(&states[25]));
+    assert_eq!(vm.step_back(6), 6);
+    assert!(vm.same_state(&states[19]));
+    assert_eq!(vm.core.accesses, states[19].core.accesses);
+    assert_eq!(vm.step_back(10), 3);
+    assert!(vm.same_state(&states[16]));
+}
+
+#[test]
+fn reverse_execute() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    vm.set_max_cycles(Some(20));
+    assert_eq!(vm.execute(), StopReason::CycleLimit);
+    assert_eq!(vm.core.mem[0x80], 5);
+
+    vm.set_max_cycles(None);
+    vm.add_watchpoint(Watchpoint {
+        start: 0x80,
+        end: 0x80,
+        kind: WatchKind::Change,
+    });
+    match vm.reverse_execute() {
+        Some(StopReason::Watch(hit)) => assert_eq!(hit.instr.addr, 0x4),
+        reason => panic!("unexpected reason {:?}", reason),
+    }
+    assert_eq!(vm.core.mem[0x80], 4);
+    assert_eq!(vm.pc(), 0x4);
+
+    vm.core.watchpoints.clear();
+    vm.add_breakpoint(0x2);
+    assert_eq!(vm.reverse_execute(), Some(StopReason::Breakpoint(0x2)));
+    assert_eq!(vm.core.cycles, 17);
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x2));
+    assert_eq!(vm.core.cycles, 21);
+
+    vm.core.breakpoints.clear();
+    assert_eq!(vm.reverse_execute(), None);
+    assert_eq!(vm.core.cycles, 0);
+}
+
+#[test]
+fn debugger_back() {
+    let mut debugger = Debugger::new(counter(), "prog.mem".into(), None);
+    let mut output = Vec::new();
+    debugger.exec("step 4", Vec::new()).unwrap();
+    debugger.exec("back 2", &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "06  JMP 00  ; undone\n04  STA 80  ; undone\n"
+    );
+    assert_eq!(debugger.vm.core.mem[0x80], 0);
+
+    let mut output = Vec::new();
+    debugger.exec("b 2", Vec::new()).unwrap();
+    debugger.exec("rc", &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("stopped at breakpoint 02h after 1 cycles"));
+
+    let mut output = Vec::new();
+    debugger.exec("rc", &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("reached the start of the recorded history"));
+}
+
+#[test]
+fn execute_until() {
+    let mut vm = counter();
+    let inv = Expr::parse::<Toy>("mem[81h] unchanged", None).unwrap();
+    vm.add_invariant(inv);
+    let mut count = 0;
+    let reason = vm.execute_until(|_, _| {
+        count += 1;
+        count == 10
+    });
+    assert_eq!(reason, StopReason::Interrupt);
+    assert_eq!(vm.core.cycles, 10);
+
+    vm.core.mem[0x0] = HLT;
+    vm.core.pc = 0x0;
+    assert_eq!(vm.execute_until(|_, _| true), StopReason::Halt);
+}
+
+#[test]
+fn debugger_save_undo() {
+    let dir = temp_dir("debugger-save-undo");
+    let path = dir.join("counter.state");
+    let mut debugger = Debugger::new(counter(), path.clone(), None);
+    debugger.exec("step 4", Vec::new()).unwrap();
+    debugger.exec("save", Vec::new()).unwrap();
+    assert!(debugger.vm.core.undo.is_some());
+    let mut vm = Toy::new();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!(vm, debugger.vm);
+    assert!(vm.core.undo.is_none());
+
+    vm.set_recording(true);
+    let mut debugger = Debugger::new(vm, path.clone(), None);
+    debugger.exec("step 2", Vec::new()).unwrap();
+    debugger.exec("save", Vec::new()).unwrap();
+    let mut vm = Toy::new();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!(vm.core.undo, debugger.vm.core.undo);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn debugger_continue_limit() {
+    let mut vm = counter();
+    vm.set_max_cycles(Some(8));
+    let mut debugger = Debugger::new(vm, "prog.mem".into(), None);
+    debugger.exec("continue", Vec::new()).unwrap();
+    assert_eq!(debugger.vm.core.cycles, 8);
+    assert_eq!(debugger.vm.core.max_cycles, Some(8));
+
+    assert_eq!(cli::continue_limit(None), cli::DEFAULT_MAX_CYCLES);
+    assert_eq!(cli::continue_limit(Some(8)), 8);
+    assert_eq!(
+        cli::continue_limit(Some(u64::MAX)),
+        cli::DEFAULT_MAX_CYCLES
+    );
+}
+
+#[test]
+fn history_queries() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    for _ in 0 .. 12 {
+        vm.cycle();
+    }
+
+    let history = History::of(&vm).unwrap();
+    assert_eq!(history.len(), 12);
+    assert!(history.start.same_state(&counter()));
+
+    let writes = history.writes(0x80);
+    assert_eq!(writes.len(), 3);
+    assert_eq!(writes[2].cycle, 11);
+    assert_eq!(writes[2].pc, 0x4);
+    assert_eq!((writes[2].old, writes[2].new), (2, 3));
+
+    let expr = Expr::parse::<Toy>("mem[80h]", None).unwrap();
+    let values = history
+        .values(&expr)
+        .iter()
+        .map(|event| (event.cycle, event.pc, event.value))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        values,
+        [(0, None, 0), (3, Some(0x4), 1), (7, Some(0x4), 2), (11, Some(0x4), 3)]
+    );
+
+    let expr = Expr::parse::<Toy>("mem[80h] == 2", None).unwrap();
+    let event = history.first_became(&expr).unwrap();
+    assert_eq!((event.cycle, event.pc), (7, Some(0x4)));
+    let expr = Expr::parse::<Toy>("mem[80h] > 3", None).unwrap();
+    assert_eq!(history.first_became(&expr), None);
+
+    assert!(History::of(&counter()).is_none());
+}
+
+#[test]
+fn history_replay() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    if let Some(log) = vm.core.undo.as_mut() {
+        log.capacity = 4;
+        log.interval = 8;
+    }
+    let mut states = Vec::new();
+    let mut steps = Vec::new();
+    for _ in 0 .. 30 {
+        let step = vm.cycle();
+        steps.push((step.instr, step.accesses));
+        states.push(vm.clone());
+    }
+
+    // Only the last 4 cycles are kept in full, so the older ones are run
+    // again from the snapshots.
+    let log = vm.core.undo.as_ref().unwrap();
+    assert_eq!(log.available(), 30);
+    let history = History::of(&vm).unwrap();
+    assert_eq!(history.len(), 30);
+    for (undo, (instr, accesses)) in history.steps.iter().zip(&steps) {
+        assert_eq!(&undo.instr, instr);
+        assert_eq!(&undo.accesses, accesses);
+    }
+    let mut count = 0;
+    history.replay(|undo, state| {
+        assert!(state.same_state(&states[count]));
+        assert_eq!(state.core.cycles, states[count].core.cycles);
+        assert_eq!(undo.context.cycles, count as u64);
+        count += 1;
+    });
+    assert_eq!(count, 30);
+    assert_eq!(history.writes(0x80).len(), 7);
+}
+
+#[test]
+fn debugger_session() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, STA, 0x81, HLT]);
+    vm.core.mem[0x80] = 5;
+
+    let mut debugger = Debugger::new(vm, "prog.mem".into(), None);
+    let script = "step\nb 4\ncontinue\nset ac 7\nset mem[80h] 3\nfoo\nq\n";
+    let mut output = Vec::new();
+    debugger.run(script.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("00  LDA 80  ; ac = 05, pc = 02\n"));
+    assert!(output.contains("stopped at breakpoint 04h after 2 cycles"));
+    assert!(output.contains("Unknown command foo"));
+    assert_eq!(debugger.vm.ac, 7);
+    assert_eq!(debugger.vm.core.mem[0x80], 3);
+    assert_eq!(debugger.vm.core.mem[0x81], 5);
+
+    let mut output = Vec::new();
+    debugger.exec("disas 3", &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "   02  STA 81\n=> 04  HLT\n   05  NOP\n"
+    );
+    assert!(debugger.exec("step x", Vec::new()).is_err());
+    assert!(!debugger.exec("quit", Vec::new()).unwrap());
+}
+
+#[test]
+fn trace_formats() {
+    let vm = counter();
+    let traces = [TraceFormat::Text, TraceFormat::Csv, TraceFormat::JsonLines]
+        .iter()
+        .map(|&format| {
+            let mut vm = vm.clone();
+            let mut tracer = Tracer::new(Vec::new(), format);
+            for _ in 0 .. 3 {
+                tracer.write_step(&vm.cycle()).unwrap();
+            }
+            String::from_utf8(tracer.finish().unwrap()).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        traces[0].lines().nth(2).unwrap(),
+        "     3  04  20 80  STA 80          @80  ac=01 pc=06  n=0 z=0  \
+         [80]=01"
+    );
+    assert_eq!(
+        traces[1].lines().take(2).collect::<Vec<_>>(),
+        [
+            "cycle,pc,bytes,mnemonic,operand,addr,ac,pc,n,z,writes",
+            "1,00,10 80,LDA,80,80,00,02,0,1,"
+        ]
+    );
+    assert_eq!(
+        traces[1].lines().nth(3).unwrap(),
+        "3,04,20 80,STA,80,80,01,06,0,0,80:00:01"
+    );
+    let record: serde_json::Value =
+        serde_json::from_str(traces[2].lines().nth(2).unwrap()).unwrap();
+    assert_eq!(
+        record,
+        serde_json::json!({
+            "cycle": 3,
+            "pc": 4,
+            "bytes": [32, 128],
+            "mnemonic": "STA",
+            "operand": 128,
+            "addr": 128,
+            "regs": { "ac": 1, "pc": 6 },
+            "flags": { "n": false, "z": false },
+            "writes": [{ "addr": 128, "old": 0, "new": 1 }],
+        })
+    );
+    assert_eq!(
+        traces[2].lines().next().unwrap(),
+        "{\"addr\":128,\"bytes\":[16,128],\"cycle\":1,\
+         \"flags\":{\"n\":false,\"z\":true},\"mnemonic\":\"LDA\",\
+         \"operand\":128,\"pc\":0,\"regs\":{\"ac\":0,\"pc\":2},\
+         \"writes\":[]}"
+    );
+}
+
+#[test]
+fn lockstep_diff() {
+    let code = [LDA, 0x80, ADD, 0x81, STA, 0x82, HLT];
+    let mut reference = Toy::with_code(&code);
+    reference.core.mem[0x80] = 2;
+    reference.core.mem[0x81] = 3;
+
+    let mut student = reference.clone();
+    student.core.mem[0x3] = 0x80;
+    let options = DiffOptions { context: 1, ..DiffOptions::default() };
+    let divergence = match diff::lockstep(
+        &mut student.clone(),
+        &mut reference.clone(),
+        &options,
+    ) {
+        Comparison::Diverged(divergence) => divergence,
+        other => panic!("unexpected comparison {:?}", other),
+    };
+    assert_eq!(divergence.cycle, 2);
+    assert_eq!(
+        divergence.to_string(),
+        "first divergence at cycle 2:\n  ac: 04 vs 05\ncycle 1\n< 00  LDA 80  \
+         ; ac = 02, pc = 02\n> 00  LDA 80  ; ac = 02, pc = 02\ncycle 2\n< 02  \
+         ADD 80  ; ac = 04, pc = 04\n> 02  ADD 81  ; ac = 05, pc = 04\n"
+    );
+
+    reference.core.mem[0x5] = 0x91;
+    let mut student = reference.clone();
+    student.core.mem[0x5] = 0x90;
+    let mut options = DiffOptions::default();
+    let comparison =
+        diff::lockstep(&mut student.clone(), &mut reference.clone(), &options);
+    match comparison {
+        Comparison::Diverged(divergence) => assert_eq!(
+            divergence.differences,
+            ["writes: [90] = 05 vs [91] = 05"]
+        ),
+        other => panic!("unexpected comparison {:?}", other),
+    }
+
+    options.ignore.push((0x90, 0x9F));
+    let comparison = diff::lockstep(&mut student, &mut reference, &options);
+    assert_eq!(comparison, Comparison::Same(4));
+}
+
+#[test]
+fn diff_ignore_reg() {
+    let dir = temp_dir("diff-ignore-reg");
+    let reference = dir.join("reference.mem");
+    let student = dir.join("student.mem");
+    let mut vm = Toy::with_code(&[LDA, 0x80, HLT]);
+    vm.core.mem[0x80] = 2;
+    vm.core.mem[0x81] = 1;
+    vm.save_at_path(&reference).unwrap();
+    vm.core.mem[0x1] = 0x81;
+    vm.save_at_path(&student).unwrap();
+    let (student, reference) = (student.display(), reference.display());
+    let args = format!("diff -i {} -r {}", student, reference);
+
+    let error = run(&args).unwrap_err();
+    assert_eq!(cli::exit_code(&error), cli::EXIT_DIVERGED);
+    run(&format!("{} --ignore-reg ac", args)).unwrap();
+    let error = run(&format!("{} --ignore-reg xy", args)).unwrap_err();
+    assert_eq!(error.to_string(), "Unknown register xy");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Sends a packet to a GDB stub.
+fn gdb_send(stream: &mut TcpStream, packet: &str) {
+    let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+    let mut byte = [0];
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'+');
+}
+
+/// Receives a reply from a GDB stub.
+fn gdb_reply(stream: &mut TcpStream) -> String {
+    let mut byte = [0];
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'$');
+
+    let mut reply = Vec::new();
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'#' {
+            break;
+        }
+        reply.push(byte[0]);
+    }
+    stream.read_exact(&mut [0; 2]).unwrap();
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+/// Sends a packet to a GDB stub, returning its reply.
+fn gdb_request(stream: &mut TcpStream, packet: &str) -> String {
+    gdb_send(stream, packet);
+    gdb_reply(stream)
+}
+
+#[test]
+fn gdb_stub() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut vm = counter();
+        vm.core.mem[0x6] = HLT;
+        let mut stub = GdbStub::new(vm);
+        stub.serve(stream).unwrap();
+        stub.vm
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    assert_eq!(gdb_request(&mut stream, "?"), "S05");
+    assert_eq!(gdb_request(&mut stream, "g"), "00000001");
+    assert_eq!(gdb_request(&mut stream, "m0,4"), "10803081");
+    assert_eq!(gdb_request(&mut stream, "M80,1:04"), "OK");
+    assert_eq!(gdb_request(&mut stream, "s"), "S05");
+    assert_eq!(gdb_request(&mut stream, "g"), "04020000");
+    assert_eq!(gdb_request(&mut stream, "p1"), "02");
+    assert_eq!(gdb_request(&mut stream, "Z0,4,1"), "OK");
+    assert_eq!(gdb_request(&mut stream, "c"), "S05");
+    assert_eq!(gdb_request(&mut stream, "p1"), "04");
+    assert_eq!(gdb_request(&mut stream, "P0=10"), "OK");
+    assert_eq!(gdb_request(&mut stream, "z0,4,1"), "OK");
+    assert_eq!(gdb_request(&mut stream, "m100,1"), "E01");
+    assert_eq!(gdb_request(&mut stream, "vMustReplyEmpty"), "");
+    assert_eq!(gdb_request(&mut stream, "c"), "W00");
+    assert_eq!(gdb_request(&mut stream, "D"), "OK");
+
+    let vm = server.join().unwrap();
+    assert_eq!(vm.core.mem[0x80], 0x10);
+    assert!(!vm.core.cycling);
+}
+
+#[test]
+fn gdb_target_interrupt() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut stub = GdbStub::new(counter());
+        stub.serve(stream).unwrap();
+        stub.vm
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let supported = gdb_request(&mut stream, "qSupported:xmlRegisters=i386");
+    assert!(supported.contains("qXfer:features:read+"));
+
+    let mut xml = String::new();
+    loop {
+        let offset = xml.len();
+        let packet = format!("qXfer:features:read:target.xml:{:x},10", offset);
+        let reply = gdb_request(&mut stream, &packet);
+        xml.push_str(&reply[1 ..]);
+        if reply.starts_with('l') {
+            break;
+        }
+        assert!(reply.starts_with('m'));
+        assert_eq!(reply.len(), 0x11);
+    }
+    assert!(xml.starts_with("<?xml"));
+    assert!(xml.contains("<reg name=\"ac\" bitsize=\"8\" type=\"uint8\"/>"));
+    assert!(xml.contains("<reg name=\"pc\" bitsize=\"8\" type=\"code_ptr\"/>"));
+    assert!(xml.contains("<reg name=\"z\" bitsize=\"8\" type=\"bool\"/>"));
+    assert!(xml.ends_with("</target>\n"));
+    let packet = "qXfer:features:read:other.xml:0,10";
+    assert_eq!(gdb_request(&mut stream, packet), "E00");
+
+    gdb_send(&mut stream, "c");
+    stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb_reply(&mut stream), "S02");
+    assert_eq!(gdb_request(&mut stream, "D"), "OK");
+
+    let vm = server.join().unwrap();
+    assert!(vm.core.cycles < 1000000);
+    assert!(vm.core.cycling);
+}
+
+/// Messages sent by the server in answer to the given requests, each the
+/// command and arguments of a request.
+fn dap_messages(
+    server: &mut DapServer<Toy>,
+    requests: &[String],
+) -> Vec<serde_json::Value> {
+    let mut input = Vec::new();
+    for (seq, request) in (1 ..).zip(requests) {
+        let body = format!(
+            r#"{{"seq":{},"type":"request","command":{}}}"#,
+            seq, request
+        );
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+        input.extend(header.bytes().chain(body.bytes()));
+    }
+
+    let mut output = Vec::new();
+    server.serve(Cursor::new(input), &mut output).unwrap();
+    let mut output = Cursor::new(output);
+    let mut messages = Vec::new();
+    while let Some(message) = dap::read_message(&mut output).unwrap() {
+        messages.push(message);
+    }
+    messages
+}
+
+#[test]
+fn dap_server() {
+    let dir = std::env::temp_dir().join("machine-dap-server");
+    fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("counter.mem");
+    let mut vm = counter();
+    vm.core.mem[0x6] = HLT;
+    vm.save_at_path(&program).unwrap();
+    let mut info = debuginfo::DebugInfo::new();
+    for (line, addr) in (1 ..).zip(&[0x0, 0x2, 0x4, 0x6]) {
+        let file = "counter.asm".into();
+        info.insert(*addr, debuginfo::SrcLine { file, line, label: None });
+    }
+    info.save_at_path(&debuginfo::sidecar_path(&program)).unwrap();
+
+    let source = dir.join("counter.asm");
+    let requests = [
+        r#""initialize","arguments":{}"#.to_owned(),
+        format!(
+            r#""launch","arguments":{{"program":{:?},"stopOnEntry":true}}"#,
+            program.display().to_string()
+        ),
+        format!(
+            r#""setBreakpoints","arguments":{{"source":{{"path":{:?}}},
+            "breakpoints":[{{"line":3}},{{"line":9}}]}}"#,
+            source.display().to_string()
+        ),
+        r#""configurationDone""#.to_owned(),
+        r#""continue","arguments":{"threadId":1}"#.to_owned(),
+        r#""stackTrace","arguments":{"threadId":1}"#.to_owned(),
+        r#""variables","arguments":{"variablesReference":1}"#.to_owned(),
+        r#""evaluate","arguments":{"expression":"ac + 1"}"#.to_owned(),
+        r#""next","arguments":{"threadId":1}"#.to_owned(),
+        r#""stepBack","arguments":{"threadId":1}"#.to_owned(),
+        r#""readMemory","arguments":{"memoryReference":"0x80","count":2}"#
+            .to_owned(),
+        r#""writeMemory","arguments":{"memoryReference":"0x81","data":"Ag=="}"#
+            .to_owned(),
+        r#""setInstructionBreakpoints","arguments":{"breakpoints":[]}"#
+            .to_owned(),
+        r#""continue","arguments":{"threadId":1}"#.to_owned(),
+        r#""frobnicate""#.to_owned(),
+        r#""disconnect""#.to_owned(),
+    ];
+    let mut server = DapServer::<Toy>::new();
+    let messages = dap_messages(&mut server, &requests);
+    let response = |seq: u64| {
+        let message = messages.iter().find(|m| m["request_seq"] == seq);
+        message.unwrap()["body"].clone()
+    };
+    let events = messages
+        .iter()
+        .filter(|m| m["type"] == "event")
+        .map(|m| (m["event"].as_str().unwrap(), m["body"]["reason"].clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(events.len(), 7);
+    assert_eq!(events[0].0, "initialized");
+    assert_eq!(events[1], ("stopped", "entry".into()));
+    assert_eq!(events[2], ("stopped", "breakpoint".into()));
+    assert_eq!(events[3], ("stopped", "step".into()));
+    assert_eq!(events[4], ("stopped", "step".into()));
+    assert_eq!(events[5].0, "exited");
+    assert_eq!(events[6].0, "terminated");
+
+    let breakpoints = response(3)["breakpoints"].clone();
+    assert_eq!(breakpoints[0]["id"], 1);
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[0]["instructionReference"], "0x04");
+    assert_eq!(breakpoints[1]["verified"], false);
+    let frame = response(6)["stackFrames"][0].clone();
+    assert_eq!(frame["instructionPointerReference"], "0x04");
+    assert_eq!(frame["line"], 3);
+    assert_eq!(frame["source"]["path"], source.display().to_string());
+    let variables = response(7)["variables"].clone();
+    assert_eq!(variables[0]["name"], "ac");
+    assert_eq!(variables[0]["value"], "01h");
+    assert_eq!(response(8)["result"], "2");
+    assert_eq!(response(11)["data"], "AAE=");
+    assert_eq!(response(12)["bytesWritten"], 1);
+    let failed = messages.iter().find(|m| m["request_seq"] == 15).unwrap();
+    assert_eq!(failed["success"], false);
+
+    assert_eq!(server.vm.core.mem[0x80], 0x1);
+    assert_eq!(server.vm.core.mem[0x81], 0x2);
+    assert!(!server.vm.core.cycling);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dap_step_back() {
+    let mut server = DapServer::<Toy>::new();
+    server.vm = counter();
+    server.vm.core.cycling = true;
+    server.vm.set_recording(true);
+    let requests = [
+        r#""next","arguments":{"threadId":1}"#.to_owned(),
+        r#""stepBack","arguments":{"threadId":1}"#.to_owned(),
+        r#""stepBack","arguments":{"threadId":1}"#.to_owned(),
+    ];
+    let messages = dap_messages(&mut server, &requests);
+    let events = messages
+        .iter()
+        .filter(|m| m["type"] == "event")
+        .map(|m| (m["event"].as_str().unwrap(), m["body"].clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].0, "stopped");
+    assert_eq!(events[1].0, "stopped");
+    assert_eq!(events[2].0, "output");
+    assert_eq!(events[2].1["category"], "console");
+    assert_eq!(
+        events[2].1["output"],
+        "reached the start of the recorded history at 0 cycles\n"
+    );
+    assert_eq!(server.vm.core.cycles, 0);
+}
+
+#[test]
+fn dap_requests() {
+    let mut server = DapServer::<Toy>::new();
+    server.vm = Toy::with_code(&[LDA, 0x80, HLT]);
+    let read = |count| {
+        format!(
+            r#""readMemory","arguments":{{"memoryReference":"0x0",
+            "count":{}}}"#,
+            count
+        )
+    };
+    let write = |data| {
+        format!(
+            r#""writeMemory","arguments":{{"memoryReference":"0x80",
+            "data":{:?}}}"#,
+            data
+        )
+    };
+    let mut requests = vec![read(0), read(1), read(2), read(3)];
+    for data in &["AQID", "BAU=", "Bg==", "Bg", "B===", "Bh==", "B=A=", "!A=="]
+    {
+        requests.push(write(data));
+    }
+    requests.push(
+        r#""setInstructionBreakpoints","arguments":{"breakpoints":[
+        {"instructionReference":"0x2"},{"instructionReference":"0xFF",
+        "offset":1}]}"#
+            .to_owned(),
+    );
+    requests.push(r#""pause","arguments":{"threadId":1}"#.to_owned());
+    let messages = dap_messages(&mut server, &requests);
+    let response = |seq: u64| {
+        messages.iter().find(|m| m["request_seq"] == seq).unwrap().clone()
+    };
+
+    let data = (1 ..= 4)
+        .map(|seq| response(seq)["body"]["data"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(data, ["", "EA==", "EIA=", "EIDw"]);
+    assert_eq!(response(5)["body"]["bytesWritten"], 3);
+    assert_eq!(response(6)["body"]["bytesWritten"], 2);
+    assert_eq!(response(7)["body"]["bytesWritten"], 1);
+    assert_eq!(server.vm.core.mem[0x80 .. 0x83], [6, 5, 3]);
+    for seq in 8 ..= 12 {
+        assert_eq!(response(seq)["success"], false);
+    }
+
+    let breakpoints = response(13)["body"]["breakpoints"].clone();
+    assert_eq!(breakpoints[0]["id"], 1);
+    assert_eq!(breakpoints[1]["id"], 2);
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+    assert_eq!(server.vm.core.breakpoints.len(), 1);
+    let pause = response(14);
+    assert_eq!(pause["success"], false);
+    assert!(pause["message"].as_str().unwrap().contains("not supported"));
+}
+
+#[test]
+fn tui_keys() {
+    let mut tui = Tui::new(counter(), "counter.mem".into(), None);
+    let text = |line: &Vec<crate::tui::Span>| {
+        line.iter().map(|span| &*span.text).collect::<String>()
+    };
+
+    for _ in 0 .. 8 {
+        tui.press(KeyCode::Down);
+    }
+    for key in &[KeyCode::Char('e'), KeyCode::Char('7'), KeyCode::Enter] {
+        tui.press(*key);
+    }
+    assert_eq!(tui.vm.core.mem[0x80], 7);
+
+    tui.press(KeyCode::Up);
+    tui.press(KeyCode::Char('s'));
+    assert_eq!(tui.vm.ac, 7);
+    let screen = tui.render();
+    assert!(screen.iter().all(|line| text(line).len() <= 80));
+    let row = &screen[10];
+    assert!(text(row).contains("80 07 01 00"));
+    assert!(row.iter().any(|s| s.text == "07" && s.style == Style::Read));
+
+    tui.press(KeyCode::Char('u'));
+    assert_eq!(tui.vm.ac, 0);
+    tui.press(KeyCode::Char('p'));
+    for _ in 0 .. 4 {
+        tui.press(KeyCode::Right);
+    }
+    tui.press(KeyCode::Char('b'));
+    tui.press(KeyCode::Char('r'));
+    assert_eq!(tui.vm.pc(), 4);
+
+    let screen = tui.render().iter().map(text).collect::<Vec<_>>();
+    assert!(screen.iter().any(|line| line.contains("=> 04  STA 80")));
+    let status = &screen[screen.len() - 2];
+    assert!(status.contains("cycles 2"));
+    assert!(status.contains("stopped at breakpoint 04h"));
+    assert!(!tui.press(KeyCode::Char('q')));
+}
+
+#[test]
+fn strict_stepping() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, 0x70, HLT]);
+    vm.core.pc = 0x2;
+    vm.set_strict(true);
+    let stop = "undefined instruction 70h at pc = 02h";
+
+    let mut debugger = Debugger::new(vm.clone(), "prog.mem".into(), None);
+    let mut output = Vec::new();
+    debugger.exec("step 2", &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", stop));
+    assert_eq!(debugger.vm.core.cycles, 0);
+
+    let mut tui = Tui::new(vm.clone(), "prog.mem".into(), None);
+    tui.press(KeyCode::Char('s'));
+    assert_eq!(tui.vm.core.cycles, 0);
+    let screen = tui.render();
+    let status = &screen[screen.len() - 2];
+    assert!(status.iter().any(|span| span.text.contains(stop)));
+
+    let mut stub = GdbStub::new(vm.clone());
+    assert_eq!(stub.handle("s").unwrap(), "S04");
+    assert_eq!(stub.handle("c").unwrap(), "S04");
+    assert_eq!(stub.vm.core.cycles, 0);
+
+    let mut server = DapServer::<Toy>::new();
+    server.vm = vm;
+    let next = r#""next","arguments":{"threadId":1}"#.to_owned();
+    let messages = dap_messages(&mut server, &[next]);
+    assert_eq!(messages[1]["body"]["reason"], "exception");
+    assert_eq!(messages[1]["body"]["description"], stop);
+    assert_eq!(server.vm.core.cycles, 0);
+}
+
+#[test]
+fn profile() {
+    let code = [LDA, 0x80, ADD, 0x81, STA, 0x80, JZ, 0xA, JMP, 0x0, HLT];
+    let mut vm = Toy::with_code(&code);
+    vm.core.mem[0x80] = 3;
+    vm.core.mem[0x81] = 0xFF;
+
+    let mut profile = Profile::new();
+    let reason = vm.execute_with(|vm, step| profile.record(vm, step));
+    assert_eq!(reason, StopReason::Halt);
+    assert_eq!(profile.cycles, 15);
+    assert_eq!(profile.mnemonics["LDA"], 3);
+    assert_eq!(profile.mnemonics["JMP"], 2);
+    assert_eq!(profile.mnemonics["HLT"], 1);
+    assert_eq!(profile.execs[&0x6], 3);
+    let branch = profile.branches[&0x6];
+    assert_eq!((branch.taken, branch.not_taken), (1, 2));
+    assert_eq!(profile.branches.len(), 1);
+    assert_eq!(profile.reads[&0x80], 3);
+    assert_eq!(profile.reads[&0x81], 3);
+    assert_eq!(profile.writes[&0x80], 3);
+    assert!(!profile.reads.contains_key(&0x0));
+
+    let loops = profile.loops();
+    assert_eq!(loops.len(), 1);
+    assert_eq!((loops[0].start, loops[0].end), (0x0, 0x8));
+    assert_eq!((loops[0].iterations, loops[0].cycles), (2, 14));
+
+    let mut report = Vec::new();
+    profile.write_report(&vm, &mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.contains("  LDA            3   20.0%"));
+    assert!(report.contains("taken        1  not taken        2"));
+    assert!(report.contains("  80  reads        3  writes        3"));
+    assert!(report.contains("  00..08         2 iterations"));
+
+    let mut vm = Toy::with_code(&[JZ, 0x2, NOT, JZ, 0x5, HLT]);
+    let mut profile = Profile::new();
+    vm.execute_with(|vm, step| profile.record(vm, step));
+    let branch = profile.branches[&0x0];
+    assert_eq!((branch.taken, branch.not_taken), (1, 0));
+    let branch = profile.branches[&0x3];
+    assert_eq!((branch.taken, branch.not_taken), (0, 1));
+}
+
+#[test]
+fn coverage() {
+    let mut vm = Toy::with_code(&[LDA, 0x80, ADD, 0x81, STA, 0x82, HLT]);
+    vm.core.mem[0x80] = 3;
+
+    let mut coverage = Coverage::new();
+    vm.execute_with(|_, step| coverage.record(step));
+    assert_eq!(&coverage.executed[.. 8], &[1, 1, 1, 1, 1, 1, 1, 0]);
+    assert_eq!((coverage.read[0x80], coverage.read[0x81]), (1, 1));
+    assert_eq!((coverage.read[0x1], coverage.written[0x82]), (0, 1));
+    assert!(coverage.untouched(0x7));
+    assert!(!coverage.untouched(0x82));
+
+    let mut info = debuginfo::DebugInfo::new();
+    for (line, addr) in (1 ..).zip(&[0x0, 0x2, 0x4, 0x6, 0x83]) {
+        let file = "sum.asm".into();
+        info.insert(*addr, debuginfo::SrcLine { file, line, label: None });
+    }
+    let mut text = Vec::new();
+    let format = CoverageFormat::Text;
+    coverage.write_report(format, Some(&info), &mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("    0   1   2   3   4"));
+    assert!(lines[0].ends_with("D   E   F"));
+    assert!(lines[1].starts_with("00  x-- x-- x-- x-- x-- x-- x-- ---"));
+    assert!(lines[9].starts_with("80  -r- -r- --w ---"));
+    assert_eq!(lines[17], "executed 7, read 2, written 1, untouched 246");
+    assert_eq!(lines[18], "written but never read: 82");
+    assert_eq!(&lines[19 ..], &["never touched:", "  83  sum.asm:5"]);
+
+    let mut json = Vec::new();
+    coverage.write_report(CoverageFormat::Json, None, &mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with(r#"{"executed":[1,1,1,1,1,1,1,0,"#));
+
+    let mut ppm = Vec::new();
+    coverage.write_report(CoverageFormat::Ppm, None, &mut ppm).unwrap();
+    let header = b"P6\n256 256\n255\n";
+    assert_eq!(&ppm[.. header.len()], header);
+    assert_eq!(ppm.len(), header.len() + 256 * 256 * 3);
+    assert_eq!(&ppm[header.len() ..][.. 3], &[0, 0, 255]);
+    assert_eq!(&ppm[ppm.len() - 3 ..], &[0, 0, 0]);
+}
+
+/// Empty directory for the files of the test with the given name.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("machine-cli-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the command line given as a string on the toy machine.
+fn run(args: &str) -> Fallible<()> {
+    let args = Some("toy").into_iter().chain(args.split_whitespace());
+    cli::run::<Toy>(cli::Command::from_iter(args))
+}
+
+#[test]
+fn step_until_break_limit() {
+    let dir = temp_dir("step-until-break-limit");
+    let path = dir.join("counter.state");
+    counter().save_at_path(&path).unwrap();
+    let args = format!("step -i {} --until-break", path.display());
+
+    let error = run(&args).unwrap_err();
+    assert_eq!(cli::exit_code(&error), cli::EXIT_CYCLE_LIMIT);
+    let mut vm = Toy::new();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!(vm.core.cycles, cli::DEFAULT_MAX_CYCLES);
+
+    let args = format!("step -i {} --until-break -n 10", path.display());
+    assert!(run(&args).is_err());
+    vm.load_from_path(&path).unwrap();
+    assert_eq!(vm.core.cycles, cli::DEFAULT_MAX_CYCLES + 10);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn run_detect_smc() {
+    let dir = temp_dir("run-detect-smc");
+    let path = dir.join("smc.mem");
+    let mut vm = Toy::with_code(&[LDA, 0x80, STA, 0x1, HLT]);
+    vm.core.mem[0x80] = 0x81;
+    vm.save_at_path(&path).unwrap();
+    let output = dir.join("smc.state");
+    let args = format!(
+        "run -i {} -o {} --detect-smc",
+        path.display(),
+        output.display()
+    );
+
+    let error = run(&args).unwrap_err();
+    assert_eq!(cli::exit_code(&error), cli::EXIT_SELF_MODIFYING);
+    assert_eq!(
+        error.to_string(),
+        "Self-modifying code: the instruction at 02h wrote into code at 01h, \
+         now LDA 81 (state saved)"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn step_strict() {
+    let dir = temp_dir("step-strict");
+    let path = dir.join("undefined.state");
+    Toy::with_code(&[LDA, 0x80, 0x70, HLT]).save_at_path(&path).unwrap();
+
+    let error = run(&format!("step -i {} -n 5 --strict", path.display()));
+    assert_eq!(cli::exit_code(&error.unwrap_err()), cli::EXIT_UNDEFINED);
+    let mut vm = Toy::new();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!((vm.pc(), vm.core.cycles), (0x2, 1));
+    assert!(vm.core.strict);
+
+    let error = run(&format!("step -i {}", path.display()));
+    assert_eq!(cli::exit_code(&error.unwrap_err()), cli::EXIT_UNDEFINED);
+    vm.load_from_path(&path).unwrap();
+    assert_eq!(vm.core.cycles, 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn step_back_to_saved_watchpoint() {
+    let dir = temp_dir("step-back-to-saved-watchpoint");
+    let path = dir.join("counter.state");
+    counter().save_at_path(&path).unwrap();
+    let input = format!("-i {}", path.display());
+
+    run(&format!("run {} --record --watch-change 80h", input)).unwrap();
+    run(&format!("run {}", input)).unwrap();
+    let mut vm = Toy::new();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!((vm.core.cycles, vm.core.mem[0x80]), (7, 2));
+    assert_eq!(vm.core.watchpoints.len(), 1);
+
+    run(&format!("step {} --back --until-break", input)).unwrap();
+    vm.load_from_path(&path).unwrap();
+    assert_eq!((vm.core.cycles, vm.core.mem[0x80]), (6, 1));
+    assert_eq!(vm.pc(), 0x4);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,216 @@
+use crate::{Flag, Machine, Register, Step};
+use error::Fallible;
+use serde_json::{json, Map};
+use std::{io::Write, path::Path};
+
+/// Format of an execution trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Aligned columns, one cycle per line, easy to diff.
+    Text,
+    /// Comma-separated values with a header line.
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+impl TraceFormat {
+    /// Format given by the extension of the path: `.csv` for CSV, `.jsonl`
+    /// or `.json` for JSON Lines, text otherwise.
+    pub fn from_path<P>(path: &P) -> Self
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => TraceFormat::Csv,
+            Some("jsonl") | Some("json") => TraceFormat::JsonLines,
+            _ => TraceFormat::Text,
+        }
+    }
+}
+
+/// Writes a record of every cycle of an execution.
+#[derive(Debug)]
+pub struct Tracer<W>
+where
+    W: Write,
+{
+    output: W,
+    format: TraceFormat,
+    started: bool,
+}
+
+impl<W> Tracer<W>
+where
+    W: Write,
+{
+    pub fn new(output: W, format: TraceFormat) -> Self {
+        Self { output, format, started: false }
+    }
+
+    /// Writes the record of the given cycle.
+    pub fn write_step<M>(&mut self, step: &Step<M>) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        match self.format {
+            TraceFormat::Text => self.write_text(step),
+            TraceFormat::Csv => {
+                if !self.started {
+                    self.write_csv_header::<M>()?;
+                }
+                self.write_csv(step)
+            },
+            TraceFormat::JsonLines => self.write_json(step),
+        }?;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Flushes the output, giving it back.
+    pub fn finish(mut self) -> Fallible<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    /// Writes a line such as
+    /// `     3  04  10 81  STA 81          @81  ac=05 pc=06  n=0 z=0  [81]=05`.
+    fn write_text<M>(&mut self, step: &Step<M>) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        let instr = &step.instr;
+        let bytes = hex_bytes(&instr.bytes());
+        let addr = step.addr.map_or(String::new(), |a| format!("@{:02X}", a));
+        write!(
+            self.output,
+            "{:>6}  {:02X}  {:<5}  {:<14}  {:<3}",
+            step.cycle,
+            instr.addr,
+            bytes,
+            M::format_instr(instr),
+            addr
+        )?;
+
+        let regs = step
+            .after
+            .regs
+            .iter()
+            .map(|(reg, data)| format!("{}={:02X}", reg.name(), data))
+            .collect::<Vec<_>>();
+        let flags = step
+            .after
+            .flags
+            .iter()
+            .map(|(flag, value)| format!("{}={}", flag.name(), *value as u8))
+            .collect::<Vec<_>>();
+        write!(self.output, "  {}  {}", regs.join(" "), flags.join(" "))?;
+
+        for (addr, _, new) in step.writes() {
+            write!(self.output, "  [{:02X}]={:02X}", addr, new)?;
+        }
+        writeln!(self.output)?;
+        Ok(())
+    }
+
+    fn write_csv_header<M>(&mut self) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        write!(self.output, "cycle,pc,bytes,mnemonic,operand,addr")?;
+        for reg in M::Register::ALL {
+            write!(self.output, ",{}", reg.name())?;
+        }
+        for flag in M::Flag::ALL {
+            write!(self.output, ",{}", flag.name())?;
+        }
+        writeln!(self.output, ",writes")?;
+        Ok(())
+    }
+
+    /// Writes a row such as `3,04,10 81,STA,81,81,05,06,0,0,81:00:05`, with
+    /// writes given as `addr:old:new` separated by spaces.
+    fn write_csv<M>(&mut self, step: &Step<M>) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        let instr = &step.instr;
+        let hex = |byte: Option<u8>| byte.map_or(String::new(), hex_byte);
+        write!(
+            self.output,
+            "{},{:02X},{},{},{},{}",
+            step.cycle,
+            instr.addr,
+            hex_bytes(&instr.bytes()),
+            instr.mnemonic.unwrap_or(""),
+            hex(instr.operand),
+            hex(step.addr)
+        )?;
+        for (_, data) in &step.after.regs {
+            write!(self.output, ",{:02X}", data)?;
+        }
+        for (_, value) in &step.after.flags {
+            write!(self.output, ",{}", *value as u8)?;
+        }
+
+        let writes = step
+            .writes()
+            .map(|(addr, old, new)| {
+                format!("{:02X}:{:02X}:{:02X}", addr, old, new)
+            })
+            .collect::<Vec<_>>();
+        writeln!(self.output, ",{}", writes.join(" "))?;
+        Ok(())
+    }
+
+    /// Writes an object such as `{"addr":129,"bytes":[16,129],"cycle":3,
+    /// "flags":{"n":false,"z":false},"mnemonic":"STA","operand":129,
+    /// "pc":4,"regs":{"ac":5,"pc":6},"writes":[{"addr":129,"new":5,
+    /// "old":0}]}`, with numbers in decimal.
+    fn write_json<M>(&mut self, step: &Step<M>) -> Fallible<()>
+    where
+        M: Machine,
+    {
+        let instr = &step.instr;
+        let regs = step
+            .after
+            .regs
+            .iter()
+            .map(|(reg, data)| (reg.name().to_owned(), json!(data)))
+            .collect::<Map<_, _>>();
+        let flags = step
+            .after
+            .flags
+            .iter()
+            .map(|(flag, value)| (flag.name().to_owned(), json!(value)))
+            .collect::<Map<_, _>>();
+        let writes = step
+            .writes()
+            .map(|(addr, old, new)| {
+                json!({ "addr": addr, "old": old, "new": new })
+            })
+            .collect::<Vec<_>>();
+        let record = json!({
+            "cycle": step.cycle,
+            "pc": instr.addr,
+            "bytes": instr.bytes(),
+            "mnemonic": instr.mnemonic,
+            "operand": instr.operand,
+            "addr": step.addr,
+            "regs": regs,
+            "flags": flags,
+            "writes": writes,
+        });
+        writeln!(self.output, "{}", record)?;
+        Ok(())
+    }
+}
+
+fn hex_byte(byte: u8) -> String {
+    format!("{:02X}", byte)
+}
+
+/// Bytes in hexadecimal, separated by spaces.
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| hex_byte(byte)).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,426 @@
+use crate::{
+    cli,
+    debugger::{addrs_around_pc, save_session},
+    Access,
+    Flag as _,
+    Machine,
+    Register as _,
+};
+pub use crossterm::event::KeyCode;
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyEventKind, KeyModifiers},
+    execute,
+    queue,
+    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, Clear, ClearType},
+};
+use debuginfo::DebugInfo;
+use error::Fallible;
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// Width of the column with registers, flags and code.
+const LEFT_WIDTH: usize = 26;
+
+/// Number of lines taken by the memory grid and its header, which is also
+/// the height of the left column.
+const BODY_HEIGHT: usize = 17;
+
+/// Key bindings, shown at the bottom of the screen.
+const KEYS: &str =
+    "s step  u back  r run  b break  e edit  p to pc  w save  q quit";
+
+/// How a piece of the screen is highlighted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    /// Titles of panes.
+    Title,
+    /// The program counter, in the code and in memory.
+    Pc,
+    /// Code at a breakpoint.
+    Breakpoint,
+    /// Memory read by the last cycle.
+    Read,
+    /// Memory written by the last cycle.
+    Write,
+    /// Memory selected for editing.
+    Cursor,
+    /// The status line.
+    Status,
+}
+
+impl Style {
+    /// Foreground and background colors.
+    fn colors(self) -> (Color, Color) {
+        match self {
+            Style::Plain => (Color::Reset, Color::Reset),
+            Style::Title => (Color::Cyan, Color::Reset),
+            Style::Pc => (Color::Black, Color::Yellow),
+            Style::Breakpoint => (Color::Red, Color::Reset),
+            Style::Read => (Color::Black, Color::Green),
+            Style::Write => (Color::White, Color::Red),
+            Style::Cursor => (Color::Black, Color::Cyan),
+            Style::Status => (Color::Black, Color::White),
+        }
+    }
+}
+
+/// Text shown with a single style.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Span {
+    fn new<S>(text: S, style: Style) -> Self
+    where
+        S: Into<String>,
+    {
+        Self { text: text.into(), style }
+    }
+}
+
+/// A line of the screen.
+pub type Line = Vec<Span>;
+
+/// Full-screen terminal interface over a machine: registers, flags and code
+/// around the program counter on the left, and memory on the right, with
+/// the accesses of the last cycle highlighted.
+#[derive(Debug)]
+pub struct Tui<M>
+where
+    M: Machine,
+{
+    pub vm: M,
+    /// File the machine was loaded from, where `w` saves.
+    pub path: PathBuf,
+    pub debug_info: Option<DebugInfo>,
+    /// Memory accesses of the last cycle run.
+    accesses: Vec<Access>,
+    /// Address selected in the memory grid.
+    cursor: u8,
+    /// Hexadecimal digits typed while editing the selected byte.
+    editing: Option<String>,
+    /// Outcome of the last key pressed.
+    message: String,
+    /// Whether `w` saves the undo log, since the machine was already
+    /// recording when loaded.
+    record: bool,
+}
+
+impl<M> Tui<M>
+where
+    M: Machine,
+{
+    /// Creates an interface over the machine, recording its cycles so they
+    /// can be undone. The record is saved only if the machine was already
+    /// recording.
+    pub fn new(
+        mut vm: M,
+        path: PathBuf,
+        debug_info: Option<DebugInfo>,
+    ) -> Self {
+        let record = vm.core().undo.is_some();
+        vm.set_recording(true);
+        Self {
+            vm,
+            path,
+            debug_info,
+            accesses: Vec::new(),
+            cursor: 0,
+            editing: None,
+            message: String::new(),
+            record,
+        }
+    }
+
+    /// Takes over the terminal, drawing the screen and handling keys until
+    /// `q`, escape or control-C is pressed.
+    pub fn run(&mut self) -> Fallible<()> {
+        let mut output = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(output, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let result = self.event_loop(&mut output);
+        execute!(output, cursor::Show, terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        result
+    }
+
+    fn event_loop<W>(&mut self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        loop {
+            self.draw(&mut output)?;
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                    if ctrl && key.code == KeyCode::Char('c') {
+                        break;
+                    }
+                    if !self.press(key.code) {
+                        break;
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn draw<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        for (row, line) in self.render().iter().enumerate() {
+            queue!(output, cursor::MoveTo(0, row as u16))?;
+            for span in line {
+                let (fg, bg) = span.style.colors();
+                queue!(
+                    output,
+                    SetForegroundColor(fg),
+                    SetBackgroundColor(bg),
+                    Print(&span.text)
+                )?;
+            }
+            queue!(output, ResetColor, Clear(ClearType::UntilNewLine))?;
+        }
+        queue!(output, Clear(ClearType::FromCursorDown))?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Handles a key, returning whether the interface should go on.
+    pub fn press(&mut self, key: KeyCode) -> bool {
+        if let Some(digits) = &mut self.editing {
+            match key {
+                KeyCode::Char(ch)
+                    if ch.is_ascii_hexdigit() && digits.len() < 2 =>
+                {
+                    digits.push(ch.to_ascii_uppercase());
+                },
+                KeyCode::Backspace => {
+                    digits.pop();
+                },
+                KeyCode::Enter => {
+                    if let Ok(data) = u8::from_str_radix(digits, 16) {
+                        self.vm.write_raw(self.cursor, data);
+                        self.message =
+                            format!("[{:02X}] = {:02X}", self.cursor, data);
+                    }
+                    self.editing = None;
+                },
+                KeyCode::Esc => self.editing = None,
+                _ => (),
+            }
+            return true;
+        }
+
+        match key {
+            KeyCode::Char('s') | KeyCode::Char(' ') => {
+                match self.vm.strict_stop() {
+                    Some(reason) => {
+                        self.message = reason.to_string();
+                        self.accesses.clear();
+                    },
+                    None => {
+                        let step = self.vm.cycle();
+                        self.message = step.to_string();
+                        self.accesses = step.accesses;
+                    },
+                }
+            },
+
+            KeyCode::Char('u') => {
+                self.accesses.clear();
+                self.message = if self.vm.step_back(1) == 0 {
+                    cli::history_start(&self.vm)
+                } else {
+                    let instr = self.vm.decode(self.vm.pc());
+                    let text = M::format_instr(&instr);
+                    format!("{:02X}  {}  ; undone", instr.addr, text)
+                };
+            },
+
+            KeyCode::Char('r') => {
+                let max_cycles = self.vm.core().max_cycles;
+                self.vm.set_max_cycles(Some(cli::continue_limit(max_cycles)));
+                let mut last = None;
+                let reason = self.vm.execute_with(|_, step| {
+                    last = Some((step.instr, step.accesses.clone()))
+                });
+                self.vm.set_max_cycles(max_cycles);
+                let instr = last.as_ref().map(|(instr, _)| instr);
+                self.message = cli::summary(&self.vm, reason, instr);
+                self.accesses =
+                    last.map_or(Vec::new(), |(_, accesses)| accesses);
+            },
+
+            KeyCode::Char('b') => {
+                let addr = self.cursor;
+                self.message = if self.vm.remove_breakpoint(addr) {
+                    format!("removed breakpoint at {:02X}h", addr)
+                } else {
+                    self.vm.add_breakpoint(addr);
+                    format!("breakpoint at {:02X}h", addr)
+                };
+            },
+
+            KeyCode::Char('e') | KeyCode::Enter => {
+                self.editing = Some(String::new());
+            },
+
+            KeyCode::Char('p') => self.cursor = self.vm.pc(),
+
+            KeyCode::Char('w') => {
+                let saved = save_session(&mut self.vm, &self.path, self.record);
+                self.message = match saved {
+                    Ok(()) => format!("saved to {}", self.path.display()),
+                    Err(error) => error.to_string(),
+                };
+            },
+
+            KeyCode::Left | KeyCode::Char('h') => {
+                self.cursor = self.cursor.wrapping_sub(1)
+            },
+            KeyCode::Right | KeyCode::Char('l') => {
+                self.cursor = self.cursor.wrapping_add(1)
+            },
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.cursor = self.cursor.wrapping_sub(16)
+            },
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.cursor = self.cursor.wrapping_add(16)
+            },
+
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+
+            _ => (),
+        }
+        true
+    }
+
+    /// Lines of the screen: a title, the panes, the status line and the
+    /// key bindings.
+    pub fn render(&self) -> Vec<Line> {
+        let title = format!(" {}  {}", M::TITLE, self.path.display());
+        let mut lines = vec![vec![Span::new(title, Style::Title)]];
+
+        let left = self.left_pane();
+        let right = self.mem_pane();
+        for (mut line, mem) in left.into_iter().zip(right) {
+            let width = line.iter().map(|span| span.text.len()).sum::<usize>();
+            let pad = LEFT_WIDTH.saturating_sub(width) + 2;
+            line.push(Span::new(" ".repeat(pad), Style::Plain));
+            line.extend(mem);
+            lines.push(line);
+        }
+
+        let core = self.vm.core();
+        let status = match &self.editing {
+            Some(digits) => format!(
+                " editing [{:02X}]: {:_<2}  (enter writes, esc cancels)",
+                self.cursor, digits
+            ),
+            None => format!(
+                " cycles {}  accesses {}  {}",
+                core.cycles, core.accesses, self.message
+            ),
+        };
+        lines.push(vec![Span::new(status, Style::Status)]);
+        lines.push(vec![Span::new(format!(" {}", KEYS), Style::Plain)]);
+        lines
+    }
+
+    /// Registers, flags and code, padded to the height of the memory grid.
+    fn left_pane(&self) -> Vec<Line> {
+        let mut lines = vec![vec![Span::new("Registers", Style::Title)]];
+        for &reg in M::Register::ALL {
+            let data = self.vm.register(reg);
+            let text = format!(" {:<3} {:02X}h {:>3}", reg.name(), data, data);
+            lines.push(vec![Span::new(text, Style::Plain)]);
+        }
+
+        lines.push(vec![Span::new("Flags", Style::Title)]);
+        let flags = M::Flag::ALL
+            .iter()
+            .map(|&flag| {
+                format!("{}={}", flag.name(), self.vm.flag(flag) as u8)
+            })
+            .collect::<Vec<_>>();
+        let flags = format!(" {}", flags.join(" "));
+        lines.push(vec![Span::new(flags, Style::Plain)]);
+
+        lines.push(vec![Span::new("Code", Style::Title)]);
+        let pc = self.vm.pc();
+        let count = BODY_HEIGHT.saturating_sub(lines.len());
+        for addr in addrs_around_pc(&self.vm, count) {
+            let instr = M::format_instr(&self.vm.decode(addr));
+            let (marker, style) = if addr == pc {
+                ("=>", Style::Pc)
+            } else if self.vm.core().breakpoints.contains(&addr) {
+                ("* ", Style::Breakpoint)
+            } else {
+                ("  ", Style::Plain)
+            };
+            let mut text = format!("{} {:02X}  {}", marker, addr, instr);
+            text.truncate(LEFT_WIDTH);
+            lines.push(vec![Span::new(text, style)]);
+        }
+
+        lines.resize(BODY_HEIGHT, Vec::new());
+        lines
+    }
+
+    /// Memory as a 16x16 grid of bytes in hexadecimal, with a header of
+    /// column numbers.
+    fn mem_pane(&self) -> Vec<Line> {
+        let mut header = String::from("  ");
+        for col in 0 .. 16 {
+            header.push_str(&format!(" {:X} ", col));
+        }
+        let mut lines = vec![vec![Span::new(header, Style::Title)]];
+
+        let mem = &self.vm.core().mem;
+        for row in 0 .. 16 {
+            let label = format!("{:X}0", row);
+            let mut line = vec![Span::new(label, Style::Title)];
+            for col in 0 .. 16 {
+                let addr = (row * 16 + col) as u8;
+                line.push(Span::new(" ", Style::Plain));
+                let text = match (&self.editing, addr == self.cursor) {
+                    (Some(digits), true) => format!("{:_<2}", digits),
+                    _ => format!("{:02X}", mem[addr as usize]),
+                };
+                line.push(Span::new(text, self.mem_style(addr)));
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    fn mem_style(&self, addr: u8) -> Style {
+        let accessed = |write| {
+            self.accesses.iter().any(|&access| {
+                access.addr() == addr
+                    && write == matches!(access, Access::Write { .. })
+            })
+        };
+        if addr == self.cursor {
+            Style::Cursor
+        } else if accessed(true) {
+            Style::Write
+        } else if accessed(false) {
+            Style::Read
+        } else if addr == self.vm.pc() {
+            Style::Pc
+        } else {
+            Style::Plain
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::{watch, Access, Flag, Instr, Machine, Register, StopReason};
+use error::{Fallible, InvalidFile};
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+};
+
+/// Registers, flags and statistics of a machine at some point: everything
+/// but the memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub ri: u8,
+    pub cycling: bool,
+    pub break_hit: Option<u8>,
+    pub cycles: u64,
+    pub accesses: u64,
+    /// Values of the registers, in display order.
+    pub regs: Vec<u8>,
+    /// Values of the flags, in display order.
+    pub flags: Vec<bool>,
+}
+
+impl Context {
+    /// Context of the given machine.
+    pub fn of<M>(vm: &M) -> Self
+    where
+        M: Machine,
+    {
+        let core = vm.core();
+        Self {
+            ri: core.ri,
+            cycling: core.cycling,
+            break_hit: core.break_hit,
+            cycles: core.cycles,
+            accesses: core.accesses,
+            regs: M::Register::ALL
+                .iter()
+                .map(|&reg| vm.register(reg))
+                .collect(),
+            flags: M::Flag::ALL.iter().map(|&flag| vm.flag(flag)).collect(),
+        }
+    }
+
+    /// Puts the given machine back into this context.
+    pub fn restore<M>(&self, vm: &mut M)
+    where
+        M: Machine,
+    {
+        for (&reg, &data) in M::Register::ALL.iter().zip(&self.regs) {
+            vm.set_register(reg, data);
+        }
+        for (&flag, &value) in M::Flag::ALL.iter().zip(&self.flags) {
+            vm.set_flag(flag, value);
+        }
+        let core = vm.core_mut();
+        core.ri = self.ri;
+        core.cycling = self.cycling;
+        core.break_hit = self.break_hit;
+        core.cycles = self.cycles;
+        core.accesses = self.accesses;
+    }
+}
+
+/// What a single cycle overwrote: everything needed to undo it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Undo {
+    /// Context before the cycle.
+    pub context: Context,
+    /// The instruction executed.
+    pub instr: Instr,
+    /// Memory accesses of the cycle, with the bytes overwritten by writes.
+    pub accesses: Vec<Access>,
+}
+
+/// Full copy of a machine, taken before some cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of cycles recorded before it.
+    pub position: u64,
+    pub context: Context,
+    pub mem: Vec<u8>,
+}
+
+/// Log of the last executed cycles, so they can be undone. Only the most
+/// recent cycles are kept in full; older points are reached by going back to
+/// a periodic snapshot of the machine and running forward from it. Changes
+/// made outside of cycles, such as setting a register, are not recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoLog {
+    /// Undo records of the most recent cycles, oldest first.
+    records: VecDeque<Undo>,
+    /// Snapshots, oldest first.
+    snapshots: VecDeque<Snapshot>,
+    /// Number of cycles recorded, which is the position of the current
+    /// state.
+    position: u64,
+    /// Maximum number of undo records kept.
+    pub capacity: usize,
+    /// Number of cycles between snapshots.
+    pub interval: u64,
+    /// Maximum number of snapshots kept.
+    pub max_snapshots: usize,
+}
+
+impl Default for UndoLog {
+    fn default() -> Self {
+        Self {
+            records: VecDeque::new(),
+            snapshots: VecDeque::new(),
+            position: 0,
+            capacity: 10000,
+            interval: 1000,
+            max_snapshots: 100,
+        }
+    }
+}
+
+impl UndoLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of cycles that can be undone.
+    pub fn available(&self) -> u64 {
+        let oldest = self.position - self.records.len() as u64;
+        let oldest = self
+            .snapshots
+            .front()
+            .map_or(oldest, |snapshot| snapshot.position.min(oldest));
+        self.position - oldest
+    }
+
+    /// Takes a snapshot before a cycle, given the context of the machine,
+    /// if one is due.
+    pub(crate) fn snapshot_if_due<M>(&mut self, vm: &M, context: &Context)
+    where
+        M: Machine,
+    {
+        let due = self.position % self.interval.max(1) == 0
+            && self
+                .snapshots
+                .back()
+                .map_or(true, |snapshot| snapshot.position < self.position);
+        if due {
+            self.snapshots.push_back(Snapshot {
+                position: self.position,
+                context: context.clone(),
+                mem: vm.core().mem.to_vec(),
+            });
+            while self.snapshots.len() > self.max_snapshots {
+                self.snapshots.pop_front();
+            }
+        }
+    }
+
+    pub(crate) fn push(&mut self, undo: Undo) {
+        self.records.push_back(undo);
+        self.position += 1;
+        while self.records.len() > self.capacity {
+            self.records.pop_front();
+        }
+    }
+
+    pub fn save<W>(&self, mut output: W) -> Fallible<()>
+    where
+        W: Write,
+    {
+        output.write_all(&self.position.to_le_bytes())?;
+        output.write_all(&(self.capacity as u64).to_le_bytes())?;
+        output.write_all(&self.interval.to_le_bytes())?;
+        output.write_all(&(self.max_snapshots as u64).to_le_bytes())?;
+
+        output.write_all(&(self.snapshots.len() as u64).to_le_bytes())?;
+        for snapshot in &self.snapshots {
+            output.write_all(&snapshot.position.to_le_bytes())?;
+            save_context(&mut output, &snapshot.context)?;
+            output.write_all(&snapshot.mem)?;
+        }
+
+        output.write_all(&(self.records.len() as u64).to_le_bytes())?;
+        for undo in &self.records {
+            save_context(&mut output, &undo.context)?;
+            let instr = &undo.instr;
+            output.write_all(&[
+                instr.addr,
+                instr.opcode,
+                instr.operand.is_some() as u8,
+                instr.operand.unwrap_or(0),
+            ])?;
+            output.write_all(&[undo.accesses.len() as u8])?;
+            for access in &undo.accesses {
+                match *access {
+                    Access::Read { addr, data } => {
+                        output.write_all(&[0, addr, data, data])?
+                    },
+                    Access::Write { addr, old, new } => {
+                        output.write_all(&[1, addr, old, new])?
+                    },
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads an undo log of the given machine.
+    pub fn load<M, R>(mut input: R) -> Fallible<Self>
+    where
+        M: Machine,
+        R: Read,
+    {
+        let mut this = Self::new();
+        this.position = read_u64(&mut input)?;
+        this.capacity = read_u64(&mut input)? as usize;
+        this.interval = read_u64(&mut input)?;
+        this.max_snapshots = read_u64(&mut input)? as usize;
+
+        for _ in 0 .. read_u64(&mut input)? {
+            let position = read_u64(&mut input)?;
+            let context = load_context(&mut input)?;
+            let mut mem = vec![0; 256];
+            input.read_exact(&mut mem)?;
+            if position > this.position {
+                Err(InvalidFile)?;
+            }
+            this.snapshots.push_back(Snapshot { position, context, mem });
+        }
+
+        let mut buf = [0; 4];
+        for _ in 0 .. read_u64(&mut input)? {
+            let context = load_context(&mut input)?;
+
+            input.read_exact(&mut buf)?;
+            let [addr, opcode, has_operand, operand] = buf;
+            let instr = Instr {
+                addr,
+                opcode,
+                operand: Some(operand).filter(|_| has_operand != 0),
+                mnemonic: M::instr_info(opcode).map(|info| info.mnemonic),
+            };
+
+            input.read_exact(&mut buf[.. 1])?;
+            let mut accesses = Vec::new();
+            for _ in 0 .. buf[0] {
+                input.read_exact(&mut buf)?;
+                accesses.push(match buf {
+                    [0, addr, data, _] => Access::Read { addr, data },
+                    [_, addr, old, new] => Access::Write { addr, old, new },
+                });
+            }
+
+            this.records.push_back(Undo { context, instr, accesses });
+        }
+
+        if this.records.len() as u64 > this.position {
+            Err(InvalidFile)?;
+        }
+        Ok(this)
+    }
+}
+
+/// Undoes the last cycle of the machine, returning its record, or `None` if
+/// there is nothing to undo. Past the kept records, the machine goes back to
+/// the latest snapshot and runs forward again to rebuild them.
+pub(crate) fn undo_cycle<M>(vm: &mut M) -> Option<Undo>
+where
+    M: Machine,
+{
+    let log = vm.core_mut().undo.as_mut()?;
+    if log.records.is_empty() {
+        let target = log.position.checked_sub(1)?;
+        while log.snapshots.back().is_some_and(|s| s.position > target) {
+            log.snapshots.pop_back();
+        }
+        let snapshot = log.snapshots.back()?.clone();
+        let cycles = log.position - snapshot.position;
+        log.position = snapshot.position;
+
+        snapshot.context.restore(vm);
+        vm.core_mut().mem.copy_from_slice(&snapshot.mem);
+        for _ in 0 .. cycles {
+            vm.cycle();
+        }
+    }
+
+    let log = vm.core_mut().undo.as_mut()?;
+    let undo = log.records.pop_back()?;
+    log.position -= 1;
+
+    for access in undo.accesses.iter().rev() {
+        if let Access::Write { addr, old, .. } = *access {
+            vm.core_mut().mem[addr as usize] = old;
+        }
+    }
+    undo.context.restore(vm);
+    Some(undo)
+}
+
+/// Runs cycles backwards until the previous breakpoint, watchpoint hit or
+/// holding condition, or until the given number of cycles is undone.
+/// Returns `None` at the start of the recorded history.
+pub(crate) fn reverse_execute<M>(
+    vm: &mut M,
+    max_cycles: Option<u64>,
+) -> Option<StopReason>
+where
+    M: Machine,
+{
+    let conditions = vm.core().conditions.clone();
+    let baselines = conditions
+        .iter()
+        .map(|cond| cond.baseline(vm))
+        .collect::<Vec<_>>();
+    let mut count = 0;
+
+    loop {
+        if max_cycles.is_some_and(|max| count >= max) {
+            break Some(StopReason::CycleLimit);
+        }
+        let undo = undo_cycle(vm)?;
+        count += 1;
+
+        let core = vm.core();
+        if let Some(hit) =
+            watch::find_hit(&core.watchpoints, undo.instr, &undo.accesses)
+        {
+            break Some(StopReason::Watch(hit));
+        }
+        let pc = core.pc;
+        if core.breakpoints.contains(&pc) {
+            vm.core_mut().break_hit = Some(pc);
+            break Some(StopReason::Breakpoint(pc));
+        }
+        if let Some(index) = conditions
+            .iter()
+            .zip(&baselines)
+            .position(|(cond, baseline)| cond.holds(vm, baseline))
+        {
+            vm.core_mut().break_hit = Some(pc);
+            break Some(StopReason::Condition(index));
+        }
+    }
+}
+
+fn read_u64<R>(mut input: R) -> Fallible<u64>
+where
+    R: Read,
+{
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn save_context<W>(mut output: W, context: &Context) -> Fallible<()>
+where
+    W: Write,
+{
+    output.write_all(&[
+        context.ri,
+        context.cycling as u8,
+        context.break_hit.is_some() as u8,
+        context.break_hit.unwrap_or(0),
+    ])?;
+    output.write_all(&context.cycles.to_le_bytes())?;
+    output.write_all(&context.accesses.to_le_bytes())?;
+    output.write_all(&[context.regs.len() as u8])?;
+    output.write_all(&context.regs)?;
+    output.write_all(&[context.flags.len() as u8])?;
+    for &flag in &context.flags {
+        output.write_all(&[flag as u8])?;
+    }
+    Ok(())
+}
+
+fn load_context<R>(mut input: R) -> Fallible<Context>
+where
+    R: Read,
+{
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    let [ri, cycling, has_break, break_addr] = buf;
+    let cycles = read_u64(&mut input)?;
+    let accesses = read_u64(&mut input)?;
+
+    input.read_exact(&mut buf[.. 1])?;
+    let mut regs = vec![0; buf[0] as usize];
+    input.read_exact(&mut regs)?;
+    input.read_exact(&mut buf[.. 1])?;
+    let mut flags = vec![0; buf[0] as usize];
+    input.read_exact(&mut flags)?;
+
+    Ok(Context {
+        ri,
+        cycling: cycling != 0,
+        break_hit: Some(break_addr).filter(|_| has_break != 0),
+        cycles,
+        accesses,
+        regs,
+        flags: flags.into_iter().map(|flag| flag != 0).collect(),
+    })
+}
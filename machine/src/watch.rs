@@ -0,0 +1,106 @@
+use crate::{Access, Instr, Machine, Step};
+use std::fmt;
+
+/// Kind of memory access a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Any read, including instruction fetches.
+    Read,
+    /// Any write, even of the same value.
+    Write,
+    /// A write that changes the value.
+    Change,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(fmt, "read"),
+            WatchKind::Write => write!(fmt, "write"),
+            WatchKind::Change => write!(fmt, "change"),
+        }
+    }
+}
+
+/// Stops executions after an instruction accesses a range of addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// First address watched.
+    pub start: u8,
+    /// Last address watched, inclusive.
+    pub end: u8,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// Tests whether the given access triggers this watchpoint.
+    pub fn matches(&self, access: Access) -> bool {
+        let addr = access.addr();
+        if addr < self.start || addr > self.end {
+            return false;
+        }
+
+        match (self.kind, access) {
+            (WatchKind::Read, Access::Read { .. }) => true,
+            (WatchKind::Write, Access::Write { .. }) => true,
+            (WatchKind::Change, Access::Write { old, new, .. }) => old != new,
+            _ => false,
+        }
+    }
+}
+
+/// A watchpoint triggered by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watch: Watchpoint,
+    /// The instruction that accessed the memory.
+    pub instr: Instr,
+    /// The access that triggered the watchpoint.
+    pub access: Access,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} watchpoint hit at pc = {:02X}h: ",
+            self.watch.kind, self.instr.addr
+        )?;
+        match self.access {
+            Access::Read { addr, data } => {
+                write!(fmt, "read {:02X}h from {:02X}h", data, addr)
+            },
+            Access::Write { addr, old, new } => write!(
+                fmt,
+                "wrote {:02X}h over {:02X}h at {:02X}h",
+                new, old, addr
+            ),
+        }
+    }
+}
+
+/// First access of the cycle that triggers any of the watchpoints.
+pub fn first_hit<M>(
+    watchpoints: &[Watchpoint],
+    step: &Step<M>,
+) -> Option<WatchHit>
+where
+    M: Machine,
+{
+    find_hit(watchpoints, step.instr, &step.accesses)
+}
+
+/// First of the given accesses, made by the instruction, that triggers any
+/// of the watchpoints.
+pub fn find_hit(
+    watchpoints: &[Watchpoint],
+    instr: Instr,
+    accesses: &[Access],
+) -> Option<WatchHit> {
+    accesses.iter().find_map(|&access| {
+        watchpoints
+            .iter()
+            .find(|watch| watch.matches(access))
+            .map(|&watch| WatchHit { watch, instr, access })
+    })
+}
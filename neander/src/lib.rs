@@ -1,13 +1,9 @@
 #[cfg(test)]
 mod test;
 
-use error::{Fallible, InvalidFile, WithPath};
-use std::{
-    fmt,
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-};
+use error::Fallible;
+use machine::{Core, Machine as _};
+use std::io::{Read, Write};
 
 /// Opcode of NOP
 pub const NOP: u8 = 0x0;
@@ -32,21 +28,40 @@ pub const JZ: u8 = 0xA0;
 /// Opcode of HLT
 pub const HLT: u8 = 0xF0;
 
-const MEM_HEADER: [u8; 4] = [0x03, 0x4E, 0x44, 0x52];
-const STATE_HEADER: [u8; 4] = [0x04, 0x4E, 0x44, 0x52];
+/// Registers of Neander.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Ac,
+    Pc,
+}
+
+impl machine::Register for Register {
+    const ALL: &'static [Self] = &[Register::Ac, Register::Pc];
+
+    fn name(self) -> &'static str {
+        match self {
+            Register::Ac => "ac",
+            Register::Pc => "pc",
+        }
+    }
+}
 
-pub fn is_mem_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "mem")
+/// Flags of Neander, computed from the accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    N,
+    Z,
 }
 
-pub fn is_state_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "state")
+impl machine::Flag for Flag {
+    const ALL: &'static [Self] = &[Flag::N, Flag::Z];
+
+    fn name(self) -> &'static str {
+        match self {
+            Flag::N => "n",
+            Flag::Z => "z",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -74,15 +89,10 @@ impl InstrInfo {
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Machine {
-    ri: u8,
-    pc: u8,
+    core: Core,
     ac: u8,
-    mem: [u8; 256],
-    cycling: bool,
-    cycles: u64,
-    accesses: u64,
 }
 
 impl Machine {
@@ -90,86 +100,31 @@ impl Machine {
         Self::default()
     }
 
-    pub fn read(&mut self, addr: u8) -> u8 {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize]
-    }
-
-    pub fn write(&mut self, addr: u8, data: u8) {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize] = data;
-    }
-
-    pub fn set_pc(&mut self, data: u8) {
-        self.pc = data;
-        self.cycles = 0;
-        self.accesses = 0;
-    }
-
-    pub fn write_raw(&mut self, addr: u8, data: u8) {
-        self.mem[addr as usize] = data;
-    }
-
-    pub fn cycle(&mut self) {
-        self.cycles += 1;
-        self.fetch();
-        self.decode_exec();
-    }
-
-    pub fn execute(&mut self) {
-        self.cycling = true;
-        while self.cycling {
-            self.cycle();
-        }
-    }
-
-    pub fn fetch(&mut self) {
-        self.ri = self.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-    }
-
-    pub fn decode_exec(&mut self) {
-        match self.ri & 0xF0 {
-            NOP => self.exec_nop(),
-            STA => self.exec_sta(),
-            LDA => self.exec_lda(),
-            ADD => self.exec_add(),
-            OR => self.exec_or(),
-            AND => self.exec_and(),
-            NOT => self.exec_not(),
-            JMP => self.exec_jmp(),
-            JN => self.exec_jn(),
-            JZ => self.exec_jz(),
-            HLT => self.exec_hlt(),
-            _ => (),
-        }
-    }
-
     fn exec_nop(&mut self) {}
 
     fn exec_sta(&mut self) {
         self.fetch();
-        self.write(self.ri, self.ac);
+        self.write(self.core.ri, self.ac);
     }
 
     fn exec_lda(&mut self) {
         self.fetch();
-        self.ac = self.read(self.ri);
+        self.ac = self.read(self.core.ri);
     }
 
     fn exec_add(&mut self) {
         self.fetch();
-        self.ac = self.ac.wrapping_add(self.read(self.ri));
+        self.ac = self.ac.wrapping_add(self.read(self.core.ri));
     }
 
     fn exec_or(&mut self) {
         self.fetch();
-        self.ac |= self.read(self.ri);
+        self.ac |= self.read(self.core.ri);
     }
 
     fn exec_and(&mut self) {
         self.fetch();
-        self.ac &= self.read(self.ri);
+        self.ac &= self.read(self.core.ri);
     }
 
     fn exec_not(&mut self) {
@@ -178,289 +133,136 @@ impl Machine {
 
     fn exec_jmp(&mut self) {
         self.fetch();
-        self.pc = self.ri;
+        self.core.pc = self.core.ri;
     }
 
     fn exec_jn(&mut self) {
         self.fetch();
         if self.ac & 0x80 != 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_jz(&mut self) {
         self.fetch();
         if self.ac == 0 {
-            self.pc = self.ri;
+            self.core.pc = self.core.ri;
         }
     }
 
     fn exec_hlt(&mut self) {
-        self.cycling = false;
+        self.core.cycling = false;
     }
+}
 
-    pub fn save_mem<W>(&self, mut output: W) -> Fallible<()>
-    where
-        W: Write,
-    {
-        output.write_all(&MEM_HEADER)?;
+impl machine::Machine for Machine {
+    type Register = Register;
+    type Flag = Flag;
 
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
+    const NAME: &'static str = "neander";
+    const TITLE: &'static str = "Neander";
+    const MEM_HEADER: [u8; 4] = [0x03, 0x4E, 0x44, 0x52];
+    const STATE_HEADER: [u8; 4] = [0x04, 0x4E, 0x44, 0x52];
 
-        Ok(())
+    fn core(&self) -> &Core {
+        &self.core
     }
 
-    pub fn load_mem<R>(&mut self, mut input: R) -> Fallible<()>
-    where
-        R: Read,
-    {
-        let mut buf = [0; 4];
+    fn core_mut(&mut self) -> &mut Core {
+        &mut self.core
+    }
 
-        input.read_exact(&mut buf)?;
+    fn decode_exec(&mut self) {
+        match self.core.ri & 0xF0 {
+            NOP => self.exec_nop(),
+            STA => self.exec_sta(),
+            LDA => self.exec_lda(),
+            ADD => self.exec_add(),
+            OR => self.exec_or(),
+            AND => self.exec_and(),
+            NOT => self.exec_not(),
+            JMP => self.exec_jmp(),
+            JN => self.exec_jn(),
+            JZ => self.exec_jz(),
+            HLT => self.exec_hlt(),
+            _ => (),
+        }
+    }
 
-        if buf != MEM_HEADER {
-            Err(InvalidFile)?;
+    fn register(&self, reg: Register) -> u8 {
+        match reg {
+            Register::Ac => self.ac,
+            Register::Pc => self.core.pc,
         }
+    }
 
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
+    fn set_register(&mut self, reg: Register, data: u8) {
+        match reg {
+            Register::Ac => self.ac = data,
+            Register::Pc => self.core.pc = data,
         }
+    }
 
-        Ok(())
+    fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::N => self.ac & 0x80 != 0,
+            Flag::Z => self.ac == 0,
+        }
     }
 
-    pub fn save_state<W>(&self, mut output: W) -> Fallible<()>
+    fn set_flag(&mut self, _flag: Flag, _value: bool) {}
+
+    fn save_regs<W>(&self, mut output: W) -> Fallible<()>
     where
         W: Write,
     {
-        output.write_all(&STATE_HEADER)?;
-
-        output.write_all(&[self.ri, self.pc, self.ac])?;
-        output.write_all(&[if self.cycling { 1 } else { 0 }])?;
-        output.write_all(&self.cycles.to_le_bytes())?;
-        output.write_all(&self.accesses.to_le_bytes())?;
-
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
-
+        output.write_all(&[self.ac])?;
         Ok(())
     }
 
-    pub fn load_state<R>(&mut self, mut input: R) -> Fallible<()>
+    fn load_regs<R>(&mut self, mut input: R) -> Fallible<()>
     where
         R: Read,
     {
-        let mut buf = [0; 8];
-
-        input.read_exact(&mut buf)?;
-
-        if &buf[.. 4] != &STATE_HEADER {
-            Err(InvalidFile)?;
-        }
-
-        self.ri = buf[4];
-        self.pc = buf[5];
-        self.ac = buf[6];
-        self.cycling = buf[7] != 0;
-
+        let mut buf = [0; 1];
         input.read_exact(&mut buf)?;
-        self.cycles = u64::from_le_bytes(buf);
-
-        input.read_exact(&mut buf)?;
-        self.accesses = u64::from_le_bytes(buf);
-
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
-        }
-
+        self.ac = buf[0];
         Ok(())
     }
 
-    pub fn save_at_path<P>(&self, path: &P) -> Fallible<()>
-    where
-        P: AsRef<Path> + ?Sized,
-    {
-        let file = File::create(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.save_mem(file)
-        } else if is_state_file(path) {
-            self.save_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
-
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
-        })
-    }
-
-    pub fn load_from_path<P>(&mut self, path: &P) -> Fallible<()>
-    where
-        P: AsRef<Path> + ?Sized,
-    {
-        let file = File::open(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.load_mem(file)
-        } else if is_state_file(path) {
-            self.load_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
-
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
+    fn instr_info(opcode: u8) -> Option<machine::InstrInfo> {
+        InstrInfo::new(opcode).map(|info| machine::InstrInfo {
+            mnemonic: info.mnemonic,
+            operand: info.operand,
         })
     }
 
-    pub fn display_mem_data<W, B>(
-        &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        B: IntoIterator<Item = u8>,
-        W: Write,
-    {
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(
-                    output,
-                    "{:03} = {:03}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn display_mem_opcodes<W, B>(
-        &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        B: IntoIterator<Item = u8>,
-        W: Write,
-    {
-        let mut needs_operand = false;
-
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(output, "{:03} = {:03}", addr, self.mem[addr as usize])?
-            }
-
-            if needs_operand {
-                needs_operand = false;
-            } else {
-                if let Some(info) = InstrInfo::new(self.mem[addr as usize]) {
-                    needs_operand = info.operand;
-                    write!(output, "  {}", info.mnemonic)?
-                }
-            }
-
-            write!(output, "\n")?;
+    fn jump_taken(
+        instr: &machine::Instr,
+        before: &machine::Regs<Self>,
+    ) -> Option<bool> {
+        match instr.opcode & 0xF0 {
+            JN => Some(before.flag(Flag::N)),
+            JZ => Some(before.flag(Flag::Z)),
+            _ => None,
         }
-
-        Ok(())
     }
 
-    pub fn display_registers<W>(
-        &mut self,
+    fn debug_mnemonic<W>(
         mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        W: Write,
-    {
-        let flag_n = self.ac >> 7;
-        let flag_z = if self.ac == 0 { 1 } else { 0 };
-
-        if hex {
-            write!(output, "ac = {:02X}\n", self.ac)?;
-            write!(output, "pc = {:02X}\n", self.pc)?;
-            write!(output, "n  = {:02X}\n", flag_n)?;
-            write!(output, "z  = {:02X}\n", flag_z)?;
-        } else {
-            write!(output, "ac = {:03}\n", self.ac)?;
-            write!(output, "pc = {:03}\n", self.pc)?;
-            write!(output, "n  = {:03}\n", flag_n)?;
-            write!(output, "z  = {:03}\n", flag_z)?;
-        }
-
-        Ok(())
-    }
-
-    pub fn display_stats<W>(&mut self, mut output: W) -> Fallible<()>
+        instruction: u8,
+        _operand: u8,
+        _hex: bool,
+    ) -> Fallible<bool>
     where
         W: Write,
     {
-        write!(output, "cycles = {}\n", self.cycles)?;
-        write!(output, "accesses = {}\n", self.accesses)?;
-
-        Ok(())
-    }
-}
-
-impl fmt::Debug for Machine {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("neander::Machine")
-            .field("ri", &self.ri)
-            .field("pc", &self.pc)
-            .field("ac", &self.ac)
-            .field("mem", &(&self.mem as &[u8]))
-            .field("cycling", &self.cycling)
-            .field("cycles", &self.cycles)
-            .field("accesses", &self.accesses)
-            .finish()
-    }
-}
-
-impl Default for Machine {
-    fn default() -> Self {
-        Self {
-            ri: 0,
-            pc: 0,
-            ac: 0,
-            mem: [0; 256],
-            cycling: false,
-            cycles: 0,
-            accesses: 0,
+        match InstrInfo::new(instruction) {
+            Some(info) => {
+                write!(output, "  {}", info.mnemonic)?;
+                Ok(info.operand)
+            },
+            None => Ok(false),
         }
     }
 }
-
-impl PartialEq for Machine {
-    fn eq(&self, other: &Self) -> bool {
-        self.ri == other.ri
-            && self.pc == other.pc
-            && self.ac == other.ac
-            && self.cycling == other.cycling
-            && self.cycles == other.cycles
-            && self.accesses == other.accesses
-            && &self.mem as &[u8] == &other.mem as &[u8]
-    }
-}
-
-impl Eq for Machine {}
@@ -1,124 +1,1131 @@
 use super::*;
+use machine::{
+    cli,
+    coverage::CoverageFormat,
+    trace::{TraceFormat, Tracer},
+    tui::{KeyCode, Style, Tui},
+    debugger::Debugger,
+    diff::{self, Comparison, DiffOptions},
+    dap::{self, DapServer},
+    gdb::GdbStub,
+    Access,
+    Coverage,
+    Expr,
+    FileKind,
+    History,
+    Instr,
+    Loop,
+    Profile,
+    SmcAllow,
+    StopReason,
+    WatchKind,
+    Watchpoint,
+};
+use std::{
+    fs,
+    io::{Cursor, Read as _, Write as _},
+    net::{TcpListener, TcpStream},
+    thread,
+};
 
 #[test]
 fn sub_algo() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = LDA;
-    vm.mem[0x1] = 0x81;
-    vm.mem[0x2] = NOT;
-    vm.mem[0x3] = ADD;
-    vm.mem[0x4] = 0x83;
-    vm.mem[0x5] = ADD;
-    vm.mem[0x6] = 0x80;
-    vm.mem[0x7] = STA;
-    vm.mem[0x8] = 0x82;
-    vm.mem[0x9] = HLT;
-
-    vm.mem[0x80] = 150;
-    vm.mem[0x81] = 3;
-    vm.mem[0x83] = 1;
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x81;
+    vm.core.mem[0x2] = NOT;
+    vm.core.mem[0x3] = ADD;
+    vm.core.mem[0x4] = 0x83;
+    vm.core.mem[0x5] = ADD;
+    vm.core.mem[0x6] = 0x80;
+    vm.core.mem[0x7] = STA;
+    vm.core.mem[0x8] = 0x82;
+    vm.core.mem[0x9] = HLT;
+
+    vm.core.mem[0x80] = 150;
+    vm.core.mem[0x81] = 3;
+    vm.core.mem[0x83] = 1;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x82], 147);
-    assert_eq!(vm.cycles, 6);
-    assert_eq!(vm.accesses, 14);
+    assert_eq!(vm.core.mem[0x82], 147);
+    assert_eq!(vm.core.cycles, 6);
+    assert_eq!(vm.core.accesses, 14);
 }
 
 #[test]
 fn mul_algo() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = LDA;
-    vm.mem[0x1] = 0x85;
-    vm.mem[0x2] = STA;
-    vm.mem[0x3] = 0x82;
-    vm.mem[0x4] = LDA;
-    vm.mem[0x5] = 0x81;
-    vm.mem[0x6] = STA;
-    vm.mem[0x7] = 0x83;
-    vm.mem[0x8] = JZ;
-    vm.mem[0x9] = 0x18;
-    vm.mem[0xA] = ADD;
-    vm.mem[0xB] = 0x84;
-    vm.mem[0xC] = STA;
-    vm.mem[0xD] = 0x83;
-    vm.mem[0xE] = LDA;
-    vm.mem[0xF] = 0x80;
-    vm.mem[0x10] = ADD;
-    vm.mem[0x11] = 0x82;
-    vm.mem[0x12] = STA;
-    vm.mem[0x13] = 0x82;
-    vm.mem[0x14] = LDA;
-    vm.mem[0x15] = 0x83;
-    vm.mem[0x16] = JMP;
-    vm.mem[0x17] = 0x8;
-    vm.mem[0x18] = HLT;
-
-    vm.mem[0x80] = 5;
-    vm.mem[0x81] = 11;
-    vm.mem[0x84] = 255;
-    vm.mem[0x85] = 0;
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x85;
+    vm.core.mem[0x2] = STA;
+    vm.core.mem[0x3] = 0x82;
+    vm.core.mem[0x4] = LDA;
+    vm.core.mem[0x5] = 0x81;
+    vm.core.mem[0x6] = STA;
+    vm.core.mem[0x7] = 0x83;
+    vm.core.mem[0x8] = JZ;
+    vm.core.mem[0x9] = 0x18;
+    vm.core.mem[0xA] = ADD;
+    vm.core.mem[0xB] = 0x84;
+    vm.core.mem[0xC] = STA;
+    vm.core.mem[0xD] = 0x83;
+    vm.core.mem[0xE] = LDA;
+    vm.core.mem[0xF] = 0x80;
+    vm.core.mem[0x10] = ADD;
+    vm.core.mem[0x11] = 0x82;
+    vm.core.mem[0x12] = STA;
+    vm.core.mem[0x13] = 0x82;
+    vm.core.mem[0x14] = LDA;
+    vm.core.mem[0x15] = 0x83;
+    vm.core.mem[0x16] = JMP;
+    vm.core.mem[0x17] = 0x8;
+    vm.core.mem[0x18] = HLT;
+
+    vm.core.mem[0x80] = 5;
+    vm.core.mem[0x81] = 11;
+    vm.core.mem[0x84] = 255;
+    vm.core.mem[0x85] = 0;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x82], 55);
-    assert_eq!(vm.cycles, 94);
-    assert_eq!(vm.accesses, 257);
+    assert_eq!(vm.core.mem[0x82], 55);
+    assert_eq!(vm.core.cycles, 94);
+    assert_eq!(vm.core.accesses, 257);
 }
 
 #[test]
 fn is_pos() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = LDA;
-    vm.mem[0x1] = 0x80;
-    vm.mem[0x2] = NOT;
-    vm.mem[0x3] = JN;
-    vm.mem[0x4] = 0xA;
-    vm.mem[0x5] = LDA;
-    vm.mem[0x6] = 0x83;
-    vm.mem[0x7] = STA;
-    vm.mem[0x8] = 0x81;
-    vm.mem[0x9] = HLT;
-    vm.mem[0xA] = LDA;
-    vm.mem[0xB] = 0x82;
-    vm.mem[0xC] = STA;
-    vm.mem[0xD] = 0x81;
-    vm.mem[0xE] = HLT;
-
-    vm.mem[0x80] = 128;
-    vm.mem[0x82] = 1;
-    vm.mem[0x83] = 0;
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = NOT;
+    vm.core.mem[0x3] = JN;
+    vm.core.mem[0x4] = 0xA;
+    vm.core.mem[0x5] = LDA;
+    vm.core.mem[0x6] = 0x83;
+    vm.core.mem[0x7] = STA;
+    vm.core.mem[0x8] = 0x81;
+    vm.core.mem[0x9] = HLT;
+    vm.core.mem[0xA] = LDA;
+    vm.core.mem[0xB] = 0x82;
+    vm.core.mem[0xC] = STA;
+    vm.core.mem[0xD] = 0x81;
+    vm.core.mem[0xE] = HLT;
+
+    vm.core.mem[0x80] = 128;
+    vm.core.mem[0x82] = 1;
+    vm.core.mem[0x83] = 0;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x81], 0);
+    assert_eq!(vm.core.mem[0x81], 0);
 }
 
 #[test]
 fn save_load_mem() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = 42;
-    vm.mem[0xB5] = 220;
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
     let mut buf = Vec::new();
     vm.save_mem(&mut buf).unwrap();
     let mut vm2 = Machine::new();
     vm2.load_mem(&mut &buf[..]).unwrap();
-    assert_eq!(&vm.mem as &[_], &vm2.mem as &[_]);
+    assert_eq!(&vm.core.mem as &[_], &vm2.core.mem as &[_]);
 }
 
 #[test]
 fn save_load_state() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = 42;
-    vm.mem[0xB5] = 220;
-    vm.pc = 0x5;
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
+    vm.core.pc = 0x5;
     vm.ac = 203;
-    vm.ri = 0x12;
-    vm.cycles = 2;
-    vm.accesses = 6;
+    vm.core.ri = 0x12;
+    vm.core.cycles = 2;
+    vm.core.accesses = 6;
     let mut buf = Vec::new();
     vm.save_state(&mut buf).unwrap();
     let mut vm2 = Machine::new();
     vm2.load_state(&mut &buf[..]).unwrap();
     assert_eq!(vm, vm2);
 }
+
+#[test]
+fn display_registers() {
+    let mut vm = Machine::new();
+    vm.ac = 0x80;
+    vm.core.pc = 0x1F;
+    let mut buf = Vec::new();
+    vm.display_registers(&mut buf, true).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "ac = 80\npc = 1F\nn  = 01\nz  = 00\n"
+    );
+}
+
+#[test]
+fn file_kind() {
+    assert_eq!(
+        Machine::file_kind([0x03, 0x4E, 0x44, 0x52]),
+        Some(FileKind::Mem)
+    );
+    assert_eq!(
+        Machine::file_kind([0x04, 0x4E, 0x44, 0x52]),
+        Some(FileKind::State)
+    );
+    assert_eq!(Machine::file_kind([0x03, 0x52, 0x4D, 0x53]), None);
+    assert!(FileKind::Mem.check_path("prog.mem").is_ok());
+    assert!(FileKind::Mem.check_path("prog").is_ok());
+    assert!(FileKind::Mem.check_path("prog.state").is_err());
+}
+
+#[test]
+fn cycle_limit() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = JMP;
+    vm.core.mem[0x1] = 0x0;
+    vm.set_max_cycles(Some(50));
+
+    assert_eq!(vm.execute(), StopReason::CycleLimit);
+    assert_eq!(vm.core.cycles, 50);
+
+    vm.core.mem[0x0] = HLT;
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.core.cycles, 51);
+}
+
+#[test]
+fn detect_loops() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = ADD;
+    vm.core.mem[0x3] = 0x81;
+    vm.core.mem[0x4] = JMP;
+    vm.core.mem[0x5] = 0x2;
+
+    vm.core.mem[0x81] = 1;
+
+    vm.set_detect_loops(true);
+
+    assert_eq!(
+        vm.execute(),
+        StopReason::Loop(Loop { entry: 0x2, period: 512 })
+    );
+    assert_eq!(vm.core.cycles, 513);
+}
+
+#[test]
+fn detect_loops_halt() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = HLT;
+    vm.core.mem[0x1] = JMP;
+    vm.core.mem[0x2] = 0x0;
+    vm.core.pc = 0x1;
+
+    vm.set_detect_loops(true);
+
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn cycle_step() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = STA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x80] = 0x7;
+    vm.ac = 0x90;
+
+    let step = vm.cycle();
+
+    assert_eq!(step.cycle, 1);
+    assert_eq!(
+        step.instr,
+        Instr {
+            addr: 0x0,
+            opcode: STA,
+            operand: Some(0x80),
+            mnemonic: Some("STA"),
+        }
+    );
+    assert_eq!(Machine::format_instr(&step.instr), "STA 80");
+    assert_eq!(step.addr, Some(0x80));
+    assert_eq!(step.before.get(Register::Pc), 0x0);
+    assert_eq!(step.after.get(Register::Pc), 0x2);
+    assert_eq!(step.regs_changed(), vec![(Register::Pc, 0x0, 0x2)]);
+    assert!(step.flags_changed().is_empty());
+    assert_eq!(
+        step.accesses,
+        vec![
+            Access::Read { addr: 0x0, data: STA },
+            Access::Read { addr: 0x1, data: 0x80 },
+            Access::Write { addr: 0x80, old: 0x7, new: 0x90 },
+        ]
+    );
+    assert_eq!(step.writes().collect::<Vec<_>>(), vec![(0x80, 0x7, 0x90)]);
+}
+
+#[test]
+fn cycle_step_flags() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = NOT;
+
+    let step = vm.cycle();
+
+    assert_eq!(step.addr, None);
+    assert_eq!(step.regs_changed()[0], (Register::Ac, 0x0, 0xFF));
+    assert_eq!(
+        step.flags_changed(),
+        vec![(Flag::N, false, true), (Flag::Z, true, false)]
+    );
+    assert_eq!(step.writes().count(), 0);
+}
+
+#[test]
+fn run_summary() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = HLT;
+
+    let mut last = None;
+    let reason = vm.execute_with(|_, step| last = Some(step.instr));
+
+    assert_eq!(
+        cli::summary(&vm, reason, last.as_ref()),
+        "halted after 2 cycles, 4 accesses (last: 02 HLT)"
+    );
+}
+
+#[test]
+fn breakpoints() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = ADD;
+    vm.core.mem[0x3] = 0x80;
+    vm.core.mem[0x4] = HLT;
+    vm.core.mem[0x80] = 3;
+
+    vm.add_breakpoint(0x2);
+
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x2));
+    assert_eq!(vm.core.pc, 0x2);
+    assert_eq!(vm.core.cycles, 1);
+    assert_eq!(vm.core.break_hit, Some(0x2));
+
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.ac, 6);
+    assert_eq!(vm.core.break_hit, None);
+}
+
+#[test]
+fn save_load_breakpoints() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = JMP;
+    vm.core.mem[0x1] = 0x0;
+    vm.add_breakpoint(0x0);
+    vm.add_breakpoint(0x9);
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x0));
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Machine::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+
+    let mut regs = Vec::new();
+    vm.save_regs(&mut regs).unwrap();
+    // Header, ri and pc, registers, cycling flag, cycle and access counts
+    // and the memory, two bytes per address: a file from before breakpoints.
+    let old_len = Machine::STATE_HEADER.len() + 2 + regs.len() + 1 + 16 + 512;
+    let mut old = Machine::new();
+    old.load_state(&buf[.. old_len]).unwrap();
+    assert!(old.core.breakpoints.is_empty());
+    assert_eq!(old.core.break_hit, None);
+    assert_eq!(old.core.mem, vm.core.mem);
+}
+
+#[test]
+fn watchpoints() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = STA;
+    vm.core.mem[0x3] = 0x81;
+    vm.core.mem[0x4] = STA;
+    vm.core.mem[0x5] = 0x82;
+    vm.core.mem[0x6] = HLT;
+    vm.core.mem[0x80] = 5;
+    vm.core.mem[0x81] = 5;
+
+    vm.add_watchpoint(Watchpoint {
+        start: 0x81,
+        end: 0x82,
+        kind: WatchKind::Change,
+    });
+
+    let hit = match vm.execute() {
+        StopReason::Watch(hit) => hit,
+        reason => panic!("unexpected stop: {:?}", reason),
+    };
+    assert_eq!(hit.instr.addr, 0x4);
+    assert_eq!(hit.access, Access::Write { addr: 0x82, old: 0, new: 5 });
+    assert_eq!(vm.core.pc, 0x6);
+
+    vm.core.pc = 0x0;
+    vm.core.watchpoints[0].kind = WatchKind::Write;
+    let reason = vm.execute();
+    assert!(matches!(reason, StopReason::Watch(hit) if hit.instr.addr == 0x2));
+
+    vm.core.watchpoints[0] =
+        Watchpoint { start: 0x80, end: 0x80, kind: WatchKind::Read };
+    vm.core.pc = 0x0;
+    let reason = vm.execute();
+    assert!(matches!(reason, StopReason::Watch(hit) if hit.instr.addr == 0x0));
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn conditions() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = ADD;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = JMP;
+    vm.core.mem[0x3] = 0x0;
+    vm.core.mem[0x80] = 3;
+
+    let cond = Expr::parse::<Machine>("pc == 0 && ac > 10h - 8", None);
+    vm.add_condition(cond.unwrap());
+
+    assert_eq!(vm.execute(), StopReason::Condition(0));
+    assert_eq!(vm.ac, 9);
+    assert_eq!(vm.core.break_hit, Some(0x0));
+
+    assert_eq!(vm.execute(), StopReason::Condition(0));
+    assert_eq!(vm.ac, 12);
+}
+
+#[test]
+fn invariants() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = STA;
+    vm.core.mem[0x3] = 0x81;
+    vm.core.mem[0x4] = STA;
+    vm.core.mem[0x5] = 0x82;
+    vm.core.mem[0x6] = HLT;
+    vm.core.mem[0x80] = 7;
+
+    let inv = Expr::parse::<Machine>("mem[81h] unchanged || !z", None);
+    vm.add_invariant(inv.unwrap());
+    let inv = Expr::parse::<Machine>("mem[0x82] unchanged", None);
+    vm.add_invariant(inv.unwrap());
+
+    assert_eq!(vm.execute(), StopReason::Invariant(1));
+    assert_eq!(vm.core.pc, 0x6);
+}
+
+#[test]
+fn parse_exprs() {
+    let parse = |src| Expr::parse::<Machine>(src, None).map(|e| e.to_string());
+
+    assert_eq!(
+        parse("ac + 1 == 2 || n && mem[80h] != ~0x3").unwrap(),
+        "(((ac + 1) == 2) || (n && (mem[128] != ~3)))"
+    );
+    assert_eq!(parse("-(pc) unchanged").unwrap(), "-(pc unchanged)");
+    assert!(parse("rx == 0").is_err());
+    assert!(parse("LOOP").is_err());
+    assert!(parse("mem[80h").is_err());
+    assert!(parse("ac ==").is_err());
+    assert!(parse("ac 1").is_err());
+    assert!(parse("ac @ 1").is_err());
+
+    let mut info = debuginfo::DebugInfo::new();
+    let label = Some("LOOP".to_owned());
+    info.insert(0x8, debuginfo::SrcLine { file: "a".into(), line: 1, label });
+    let expr = Expr::parse::<Machine>("pc == LOOP", Some(&info)).unwrap();
+    assert_eq!(expr.to_string(), "(pc == 8)");
+}
+
+#[test]
+fn save_load_exprs() {
+    let mut vm = Machine::new();
+    vm.add_condition(Expr::parse::<Machine>("ac > 3", None).unwrap());
+    vm.add_invariant(Expr::parse::<Machine>("mem[1] unchanged", None).unwrap());
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Machine::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+}
+
+#[test]
+fn debugger_session() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = STA;
+    vm.core.mem[0x3] = 0x81;
+    vm.core.mem[0x4] = HLT;
+    vm.core.mem[0x80] = 5;
+
+    let mut debugger = Debugger::new(vm, "prog.mem".into(), None);
+    let script = "step\nb 4\ncontinue\nset ac 7\nset mem[80h] 3\nfoo\nq\n";
+    let mut output = Vec::new();
+    debugger.run(script.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("00  LDA 80  ; ac = 05, pc = 02\n"));
+    assert!(output.contains("stopped at breakpoint 04h after 2 cycles"));
+    assert!(output.contains("Unknown command foo"));
+    assert_eq!(debugger.vm.ac, 7);
+    assert_eq!(debugger.vm.core.mem[0x80], 3);
+    assert_eq!(debugger.vm.core.mem[0x81], 5);
+
+    let mut output = Vec::new();
+    debugger.exec("disas 3", &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "   02  STA 81\n=> 04  HLT\n   05  NOP\n"
+    );
+    assert!(debugger.exec("step x", Vec::new()).is_err());
+    assert!(!debugger.exec("quit", Vec::new()).unwrap());
+}
+
+/// Program that keeps incrementing the byte at 80h.
+fn counter() -> Machine {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDA;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = ADD;
+    vm.core.mem[0x3] = 0x81;
+    vm.core.mem[0x4] = STA;
+    vm.core.mem[0x5] = 0x80;
+    vm.core.mem[0x6] = JMP;
+    vm.core.mem[0x7] = 0x0;
+    vm.core.mem[0x81] = 1;
+    vm
+}
+
+#[test]
+fn step_back() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    let start = vm.clone();
+    for _ in 0 .. 10 {
+        vm.cycle();
+    }
+    let middle = vm.clone();
+    for _ in 0 .. 3 {
+        vm.cycle();
+    }
+
+    assert_eq!(vm.step_back(3), 3);
+    assert!(vm.same_state(&middle));
+    assert_eq!(vm.core.cycles, 10);
+    assert_eq!(vm.step_back(20), 10);
+    assert!(vm.same_state(&start));
+    assert_eq!(vm.core.cycles, 0);
+    assert_eq!(vm.step_back(1), 0);
+}
+
+#[test]
+fn step_back_snapshots() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    if let Some(log) = vm.core.undo.as_mut() {
+        log.capacity = 4;
+        log.interval = 8;
+        log.max_snapshots = 2;
+    }
+    let mut states = vec![vm.clone()];
+    for _ in 0 .. 30 {
+        vm.cycle();
+        states.push(vm.clone());
+    }
+
+    let log = vm.core.undo.as_ref().unwrap();
+    assert_eq!(log.available(), 14);
+    assert_eq!(vm.step_back(5), 5);
+    assert!(vm.same_state(&states[25]));
+    assert_eq!(vm.step_back(6), 6);
+    assert!(vm.same_state(&states[19]));
+    assert_eq!(vm.core.accesses, states[19].core.accesses);
+    assert_eq!(vm.step_back(10), 3);
+    assert!(vm.same_state(&states[16]));
+}
+
+#[test]
+fn reverse_execute() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    vm.set_max_cycles(Some(20));
+    assert_eq!(vm.execute(), StopReason::CycleLimit);
+    assert_eq!(vm.core.mem[0x80], 5);
+
+    vm.set_max_cycles(None);
+    vm.add_watchpoint(Watchpoint {
+        start: 0x80,
+        end: 0x80,
+        kind: WatchKind::Change,
+    });
+    match vm.reverse_execute() {
+        Some(StopReason::Watch(hit)) => assert_eq!(hit.instr.addr, 0x4),
+        reason => panic!("unexpected reason {:?}", reason),
+    }
+    assert_eq!(vm.core.mem[0x80], 4);
+    assert_eq!(vm.pc(), 0x4);
+
+    vm.core.watchpoints.clear();
+    vm.add_breakpoint(0x2);
+    assert_eq!(vm.reverse_execute(), Some(StopReason::Breakpoint(0x2)));
+    assert_eq!(vm.core.cycles, 17);
+    assert_eq!(vm.execute(), StopReason::Breakpoint(0x2));
+    assert_eq!(vm.core.cycles, 21);
+
+    vm.core.breakpoints.clear();
+    assert_eq!(vm.reverse_execute(), None);
+    assert_eq!(vm.core.cycles, 0);
+}
+
+#[test]
+fn save_load_undo() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    for _ in 0 .. 6 {
+        vm.cycle();
+    }
+
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+    let mut vm2 = Machine::new();
+    vm2.load_state(&buf[..]).unwrap();
+    assert_eq!(vm, vm2);
+
+    assert_eq!(vm2.step_back(6), 6);
+    assert!(vm2.same_state(&counter()));
+}
+
+#[test]
+fn debugger_back() {
+    let mut debugger = Debugger::new(counter(), "prog.mem".into(), None);
+    let mut output = Vec::new();
+    debugger.exec("step 4", Vec::new()).unwrap();
+    debugger.exec("back 2", &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "06  JMP 00  ; undone\n04  STA 80  ; undone\n"
+    );
+    assert_eq!(debugger.vm.core.mem[0x80], 0);
+
+    let mut output = Vec::new();
+    debugger.exec("b 2", Vec::new()).unwrap();
+    debugger.exec("rc", &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("stopped at breakpoint 02h after 1 cycles"));
+
+    let mut output = Vec::new();
+    debugger.exec("rc", &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("reached the start of the recorded history"));
+}
+
+#[test]
+fn history_queries() {
+    let mut vm = counter();
+    vm.set_recording(true);
+    for _ in 0 .. 12 {
+        vm.cycle();
+    }
+
+    let history = History::of(&vm).unwrap();
+    assert_eq!(history.len(), 12);
+    assert!(history.start.same_state(&counter()));
+
+    let writes = history.writes(0x80);
+    assert_eq!(writes.len(), 3);
+    assert_eq!(writes[2].cycle, 11);
+    assert_eq!(writes[2].pc, 0x4);
+    assert_eq!((writes[2].old, writes[2].new), (2, 3));
+
+    let expr = Expr::parse::<Machine>("mem[80h]", None).unwrap();
+    let values = history
+        .values(&expr)
+        .iter()
+        .map(|event| (event.cycle, event.pc, event.value))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        values,
+        [(0, None, 0), (3, Some(0x4), 1), (7, Some(0x4), 2), (11, Some(0x4), 3)]
+    );
+
+    let expr = Expr::parse::<Machine>("mem[80h] == 2", None).unwrap();
+    let event = history.first_became(&expr).unwrap();
+    assert_eq!((event.cycle, event.pc), (7, Some(0x4)));
+    let expr = Expr::parse::<Machine>("mem[80h] > 3", None).unwrap();
+    assert_eq!(history.first_became(&expr), None);
+
+    assert!(History::of(&counter()).is_none());
+}
+
+#[test]
+fn trace_formats() {
+    let vm = counter();
+    let traces = [TraceFormat::Text, TraceFormat::Csv, TraceFormat::JsonLines]
+        .iter()
+        .map(|&format| {
+            let mut vm = vm.clone();
+            let mut tracer = Tracer::new(Vec::new(), format);
+            for _ in 0 .. 3 {
+                tracer.write_step(&vm.cycle()).unwrap();
+            }
+            String::from_utf8(tracer.finish().unwrap()).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        traces[0].lines().nth(2).unwrap(),
+        "     3  04  10 80  STA 80          @80  ac=01 pc=06  n=0 z=0  \
+         [80]=01"
+    );
+    assert_eq!(
+        traces[1].lines().take(2).collect::<Vec<_>>(),
+        [
+            "cycle,pc,bytes,mnemonic,operand,addr,ac,pc,n,z,writes",
+            "1,00,20 80,LDA,80,80,00,02,0,1,"
+        ]
+    );
+    assert_eq!(
+        traces[1].lines().nth(3).unwrap(),
+        "3,04,10 80,STA,80,80,01,06,0,0,80:00:01"
+    );
+    assert_eq!(
+        traces[2].lines().nth(2).unwrap(),
+        "{\"addr\":128,\"bytes\":[16,128],\"cycle\":3,\
+         \"flags\":{\"n\":false,\"z\":false},\"mnemonic\":\"STA\",\
+         \"operand\":128,\"pc\":4,\"regs\":{\"ac\":1,\"pc\":6},\
+         \"writes\":[{\"addr\":128,\"new\":1,\"old\":0}]}"
+    );
+}
+
+#[test]
+fn lockstep_diff() {
+    let mut reference = Machine::new();
+    reference.core.mem[0x0] = LDA;
+    reference.core.mem[0x1] = 0x80;
+    reference.core.mem[0x2] = ADD;
+    reference.core.mem[0x3] = 0x81;
+    reference.core.mem[0x4] = STA;
+    reference.core.mem[0x5] = 0x82;
+    reference.core.mem[0x6] = HLT;
+    reference.core.mem[0x80] = 2;
+    reference.core.mem[0x81] = 3;
+
+    let mut student = reference.clone();
+    student.core.mem[0x3] = 0x80;
+    let options = DiffOptions { context: 1, ..DiffOptions::default() };
+    let divergence = match diff::lockstep(
+        &mut student.clone(),
+        &mut reference.clone(),
+        &options,
+    ) {
+        Comparison::Diverged(divergence) => divergence,
+        other => panic!("unexpected comparison {:?}", other),
+    };
+    assert_eq!(divergence.cycle, 2);
+    assert_eq!(
+        divergence.to_string(),
+        "first divergence at cycle 2:\n  ac: 04 vs 05\ncycle 1\n< 00  LDA 80  \
+         ; ac = 02, pc = 02\n> 00  LDA 80  ; ac = 02, pc = 02\ncycle 2\n< 02  \
+         ADD 80  ; ac = 04, pc = 04\n> 02  ADD 81  ; ac = 05, pc = 04\n"
+    );
+
+    reference.core.mem[0x5] = 0x91;
+    let mut student = reference.clone();
+    student.core.mem[0x5] = 0x90;
+    let mut options = DiffOptions::default();
+    let comparison =
+        diff::lockstep(&mut student.clone(), &mut reference.clone(), &options);
+    match comparison {
+        Comparison::Diverged(divergence) => assert_eq!(
+            divergence.differences,
+            ["writes: [90] = 05 vs [91] = 05"]
+        ),
+        other => panic!("unexpected comparison {:?}", other),
+    }
+
+    options.ignore.push((0x90, 0x9F));
+    let comparison = diff::lockstep(&mut student, &mut reference, &options);
+    assert_eq!(comparison, Comparison::Same(4));
+}
+
+/// Sends a packet to a GDB stub, returning its reply.
+fn gdb_request(stream: &mut TcpStream, packet: &str) -> String {
+    let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+
+    let mut byte = [0];
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'+');
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], b'$');
+
+    let mut reply = Vec::new();
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'#' {
+            break;
+        }
+        reply.push(byte[0]);
+    }
+    stream.read_exact(&mut [0; 2]).unwrap();
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn gdb_stub() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut vm = counter();
+        vm.core.mem[0x6] = HLT;
+        let mut stub = GdbStub::new(vm);
+        stub.serve(stream).unwrap();
+        stub.vm
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    assert_eq!(gdb_request(&mut stream, "?"), "S05");
+    assert_eq!(gdb_request(&mut stream, "g"), "00000001");
+    assert_eq!(gdb_request(&mut stream, "m0,4"), "20803081");
+    assert_eq!(gdb_request(&mut stream, "M80,1:04"), "OK");
+    assert_eq!(gdb_request(&mut stream, "s"), "S05");
+    assert_eq!(gdb_request(&mut stream, "g"), "04020000");
+    assert_eq!(gdb_request(&mut stream, "p1"), "02");
+    assert_eq!(gdb_request(&mut stream, "Z0,4,1"), "OK");
+    assert_eq!(gdb_request(&mut stream, "c"), "S05");
+    assert_eq!(gdb_request(&mut stream, "p1"), "04");
+    assert_eq!(gdb_request(&mut stream, "P0=10"), "OK");
+    assert_eq!(gdb_request(&mut stream, "z0,4,1"), "OK");
+    assert_eq!(gdb_request(&mut stream, "m100,1"), "E01");
+    assert_eq!(gdb_request(&mut stream, "vMustReplyEmpty"), "");
+    assert_eq!(gdb_request(&mut stream, "c"), "W00");
+    assert_eq!(gdb_request(&mut stream, "D"), "OK");
+
+    let vm = server.join().unwrap();
+    assert_eq!(vm.core.mem[0x80], 0x10);
+    assert!(!vm.core.cycling);
+}
+
+#[test]
+fn dap_server() {
+    let dir = std::env::temp_dir().join("neander-dap-server");
+    fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("counter.mem");
+    let mut vm = counter();
+    vm.core.mem[0x6] = HLT;
+    vm.save_at_path(&program).unwrap();
+    let mut info = debuginfo::DebugInfo::new();
+    for (line, addr) in (1 ..).zip(&[0x0, 0x2, 0x4, 0x6]) {
+        let file = "counter.asm".into();
+        info.insert(*addr, debuginfo::SrcLine { file, line, label: None });
+    }
+    info.save_at_path(&debuginfo::sidecar_path(&program)).unwrap();
+
+    let source = dir.join("counter.asm");
+    let requests = [
+        r#""initialize","arguments":{}"#.to_owned(),
+        format!(
+            r#""launch","arguments":{{"program":{:?},"stopOnEntry":true}}"#,
+            program.display().to_string()
+        ),
+        format!(
+            r#""setBreakpoints","arguments":{{"source":{{"path":{:?}}},
+            "breakpoints":[{{"line":3}},{{"line":9}}]}}"#,
+            source.display().to_string()
+        ),
+        r#""configurationDone""#.to_owned(),
+        r#""continue","arguments":{"threadId":1}"#.to_owned(),
+        r#""stackTrace","arguments":{"threadId":1}"#.to_owned(),
+        r#""variables","arguments":{"variablesReference":1}"#.to_owned(),
+        r#""evaluate","arguments":{"expression":"ac + 1"}"#.to_owned(),
+        r#""next","arguments":{"threadId":1}"#.to_owned(),
+        r#""stepBack","arguments":{"threadId":1}"#.to_owned(),
+        r#""readMemory","arguments":{"memoryReference":"0x80","count":2}"#
+            .to_owned(),
+        r#""writeMemory","arguments":{"memoryReference":"0x81","data":"Ag=="}"#
+            .to_owned(),
+        r#""setInstructionBreakpoints","arguments":{"breakpoints":[]}"#
+            .to_owned(),
+        r#""continue","arguments":{"threadId":1}"#.to_owned(),
+        r#""frobnicate""#.to_owned(),
+        r#""disconnect""#.to_owned(),
+    ];
+    let mut input = Vec::new();
+    for (seq, request) in (1 ..).zip(&requests) {
+        let body = format!(
+            r#"{{"seq":{},"type":"request","command":{}}}"#,
+            seq, request
+        );
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+        input.extend(header.bytes().chain(body.bytes()));
+    }
+
+    let mut server = DapServer::<Machine>::new();
+    let mut output = Vec::new();
+    server.serve(Cursor::new(input), &mut output).unwrap();
+    let mut output = Cursor::new(output);
+    let mut messages = Vec::new();
+    while let Some(message) = dap::read_message(&mut output).unwrap() {
+        messages.push(message);
+    }
+    let response = |seq: u64| {
+        let message = messages.iter().find(|m| m["request_seq"] == seq);
+        message.unwrap()["body"].clone()
+    };
+    let events = messages
+        .iter()
+        .filter(|m| m["type"] == "event")
+        .map(|m| (m["event"].as_str().unwrap(), m["body"]["reason"].clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(events.len(), 7);
+    assert_eq!(events[0].0, "initialized");
+    assert_eq!(events[1], ("stopped", "entry".into()));
+    assert_eq!(events[2], ("stopped", "breakpoint".into()));
+    assert_eq!(events[3], ("stopped", "step".into()));
+    assert_eq!(events[4], ("stopped", "step".into()));
+    assert_eq!(events[5].0, "exited");
+    assert_eq!(events[6].0, "terminated");
+
+    let breakpoints = response(3)["breakpoints"].clone();
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[0]["instructionReference"], "0x04");
+    assert_eq!(breakpoints[1]["verified"], false);
+    let frame = response(6)["stackFrames"][0].clone();
+    assert_eq!(frame["instructionPointerReference"], "0x04");
+    assert_eq!(frame["line"], 3);
+    assert_eq!(frame["source"]["path"], source.display().to_string());
+    let variables = response(7)["variables"].clone();
+    assert_eq!(variables[0]["name"], "ac");
+    assert_eq!(variables[0]["value"], "01h");
+    assert_eq!(response(8)["result"], "2");
+    assert_eq!(response(11)["data"], "AAE=");
+    assert_eq!(response(12)["bytesWritten"], 1);
+    let failed = messages.iter().find(|m| m["request_seq"] == 15).unwrap();
+    assert_eq!(failed["success"], false);
+
+    assert_eq!(server.vm.core.mem[0x80], 0x1);
+    assert_eq!(server.vm.core.mem[0x81], 0x2);
+    assert!(!server.vm.core.cycling);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tui_keys() {
+    let mut tui = Tui::new(counter(), "counter.mem".into(), None);
+    let text = |line: &Vec<machine::tui::Span>| {
+        line.iter().map(|span| &*span.text).collect::<String>()
+    };
+
+    for _ in 0 .. 8 {
+        tui.press(KeyCode::Down);
+    }
+    for key in &[KeyCode::Char('e'), KeyCode::Char('7'), KeyCode::Enter] {
+        tui.press(*key);
+    }
+    assert_eq!(tui.vm.core.mem[0x80], 7);
+
+    tui.press(KeyCode::Up);
+    tui.press(KeyCode::Char('s'));
+    assert_eq!(tui.vm.ac, 7);
+    let screen = tui.render();
+    assert!(screen.iter().all(|line| text(line).len() <= 80));
+    let row = &screen[10];
+    assert!(text(row).contains("80 07 01 00"));
+    assert!(row.iter().any(|s| s.text == "07" && s.style == Style::Read));
+
+    tui.press(KeyCode::Char('u'));
+    assert_eq!(tui.vm.ac, 0);
+    tui.press(KeyCode::Char('p'));
+    for _ in 0 .. 4 {
+        tui.press(KeyCode::Right);
+    }
+    tui.press(KeyCode::Char('b'));
+    tui.press(KeyCode::Char('r'));
+    assert_eq!(tui.vm.pc(), 4);
+
+    let screen = tui.render().iter().map(text).collect::<Vec<_>>();
+    assert!(screen.iter().any(|line| line.contains("=> 04  STA 80")));
+    let status = &screen[screen.len() - 2];
+    assert!(status.contains("cycles 2"));
+    assert!(status.contains("stopped at breakpoint 04h"));
+    assert!(!tui.press(KeyCode::Char('q')));
+}
+
+#[test]
+fn profile() {
+    let mut vm = Machine::new();
+    let code = [LDA, 0x80, ADD, 0x81, STA, 0x80, JZ, 0xA, JMP, 0x0, HLT];
+    vm.core.mem[.. code.len()].copy_from_slice(&code);
+    vm.core.mem[0x80] = 3;
+    vm.core.mem[0x81] = 0xFF;
+
+    let mut profile = Profile::new();
+    let reason = vm.execute_with(|vm, step| profile.record(vm, step));
+    assert_eq!(reason, StopReason::Halt);
+    assert_eq!(profile.cycles, 15);
+    assert_eq!(profile.mnemonics["LDA"], 3);
+    assert_eq!(profile.mnemonics["JMP"], 2);
+    assert_eq!(profile.mnemonics["HLT"], 1);
+    assert_eq!(profile.execs[&0x6], 3);
+    let branch = profile.branches[&0x6];
+    assert_eq!((branch.taken, branch.not_taken), (1, 2));
+    assert_eq!(profile.branches.len(), 1);
+    assert_eq!(profile.reads[&0x80], 3);
+    assert_eq!(profile.reads[&0x81], 3);
+    assert_eq!(profile.writes[&0x80], 3);
+    assert!(!profile.reads.contains_key(&0x0));
+
+    let loops = profile.loops();
+    assert_eq!(loops.len(), 1);
+    assert_eq!((loops[0].start, loops[0].end), (0x0, 0x8));
+    assert_eq!((loops[0].iterations, loops[0].cycles), (2, 14));
+
+    let mut report = Vec::new();
+    profile.write_report(&vm, &mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.contains("  LDA            3   20.0%"));
+    assert!(report.contains("taken        1  not taken        2"));
+    assert!(report.contains("  80  reads        3  writes        3"));
+    assert!(report.contains("  00..08         2 iterations"));
+
+    let mut vm = Machine::new();
+    let code = [JZ, 0x2, NOT, JN, 0x5, JZ, 0x7, HLT];
+    vm.core.mem[.. code.len()].copy_from_slice(&code);
+    let mut profile = Profile::new();
+    vm.execute_with(|vm, step| profile.record(vm, step));
+    let outcomes = profile
+        .branches
+        .iter()
+        .map(|(&addr, branch)| (addr, branch.taken, branch.not_taken))
+        .collect::<Vec<_>>();
+    assert_eq!(outcomes, [(0x0, 1, 0), (0x3, 1, 0), (0x5, 0, 1)]);
+}
+
+#[test]
+fn coverage() {
+    let mut vm = Machine::new();
+    let code = [LDA, 0x80, ADD, 0x81, STA, 0x82, HLT];
+    vm.core.mem[.. code.len()].copy_from_slice(&code);
+    vm.core.mem[0x80] = 3;
+
+    let mut coverage = Coverage::new();
+    vm.execute_with(|_, step| coverage.record(step));
+    assert_eq!(&coverage.executed[.. 8], &[1, 1, 1, 1, 1, 1, 1, 0]);
+    assert_eq!((coverage.read[0x80], coverage.read[0x81]), (1, 1));
+    assert_eq!((coverage.read[0x1], coverage.written[0x82]), (0, 1));
+    assert!(coverage.untouched(0x7));
+    assert!(!coverage.untouched(0x82));
+
+    let mut info = debuginfo::DebugInfo::new();
+    for (line, addr) in (1 ..).zip(&[0x0, 0x2, 0x4, 0x6, 0x83]) {
+        let file = "sum.asm".into();
+        info.insert(*addr, debuginfo::SrcLine { file, line, label: None });
+    }
+    let mut text = Vec::new();
+    let format = CoverageFormat::Text;
+    coverage.write_report(format, Some(&info), &mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("    0   1   2   3   4"));
+    assert!(lines[0].ends_with("D   E   F"));
+    assert!(lines[1].starts_with("00  x-- x-- x-- x-- x-- x-- x-- ---"));
+    assert!(lines[9].starts_with("80  -r- -r- --w ---"));
+    assert_eq!(lines[17], "executed 7, read 2, written 1, untouched 246");
+    assert_eq!(lines[18], "written but never read: 82");
+    assert_eq!(&lines[19 ..], &["never touched:", "  83  sum.asm:5"]);
+
+    let mut json = Vec::new();
+    coverage.write_report(CoverageFormat::Json, None, &mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with(r#"{"executed":[1,1,1,1,1,1,1,0,"#));
+
+    let mut ppm = Vec::new();
+    coverage.write_report(CoverageFormat::Ppm, None, &mut ppm).unwrap();
+    let header = b"P6\n256 256\n255\n";
+    assert_eq!(&ppm[.. header.len()], header);
+    assert_eq!(ppm.len(), header.len() + 256 * 256 * 3);
+    assert_eq!(&ppm[header.len() ..][.. 3], &[0, 0, 255]);
+    assert_eq!(&ppm[ppm.len() - 3 ..], &[0, 0, 0]);
+}
+
+#[test]
+fn self_modifying_code() {
+    let mut vm = Machine::new();
+    let code = [LDA, 0x80, STA, 0x1, LDA, 0x80, STA, 0x7, HLT];
+    vm.core.mem[.. code.len()].copy_from_slice(&code);
+    vm.core.mem[0x80] = 0x80;
+    let start = vm.clone();
+
+    vm.set_smc_detection(Some(Vec::new()));
+    let hit = match vm.execute() {
+        StopReason::SelfModify(hit) => hit,
+        reason => panic!("unexpected {:?}", reason),
+    };
+    assert_eq!((hit.instr.addr, hit.addr, hit.code), (0x2, 0x1, 0x0));
+    assert_eq!((hit.old, hit.new), (0x80, 0x80));
+    assert_eq!(
+        hit.to_string(),
+        "self-modifying code at pc = 02h: wrote 80h over 80h at 01h, code of \
+         the instruction at 00h"
+    );
+    assert_eq!(
+        cli::summary(&vm, StopReason::SelfModify(hit), None),
+        "self-modifying code at pc = 02h: wrote 80h over 80h at 01h, code of \
+         the instruction at 00h, now LDA 80 after 2 cycles, 6 accesses"
+    );
+
+    let hit = match vm.execute() {
+        StopReason::SelfModify(hit) => hit,
+        reason => panic!("unexpected {:?}", reason),
+    };
+    assert_eq!((hit.instr.addr, hit.addr, hit.code), (0x6, 0x7, 0x6));
+    assert_eq!(vm.execute(), StopReason::Halt);
+
+    let mut vm = start.clone();
+    vm.set_smc_detection(Some(vec![SmcAllow::Range(0x0, 0x7)]));
+    assert_eq!(vm.execute(), StopReason::Halt);
+
+    let mut vm = start;
+    assert_eq!(vm.execute(), StopReason::Halt);
+}
+
+#[test]
+fn strict_execution() {
+    let mut vm = Machine::new();
+    let code = [LDA, 0x80, 0x70, ADD, 0x80, HLT];
+    vm.core.mem[.. code.len()].copy_from_slice(&code);
+    vm.core.mem[0x80] = 3;
+    let start = vm.clone();
+
+    vm.set_strict(true);
+    let reason = vm.execute();
+    assert_eq!(reason, StopReason::Undefined(vm.decode(0x2)));
+    assert_eq!(reason.to_string(), "undefined instruction 70h at pc = 02h");
+    assert_eq!((vm.pc(), vm.core.cycles), (0x2, 1));
+    assert_eq!(vm.execute(), reason);
+
+    let mut vm = start;
+    assert_eq!(vm.execute(), StopReason::Halt);
+    assert_eq!(vm.ac, 6);
+}
@@ -0,0 +1,65 @@
+use error::{
+    Fallible,
+    MissingMachine,
+    UnknownHeader,
+    UnknownMachine,
+    WithPath,
+};
+use machine::{
+    cli::{self, Command},
+    Machine,
+};
+use std::process;
+use structopt::StructOpt;
+
+/// Names of the machines niahu knows.
+const MACHINES: &[&str] =
+    &[neander::Machine::NAME, ahmes::Machine::NAME, ramses::Machine::NAME];
+
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("{}", e);
+        process::exit(cli::exit_code(&e));
+    }
+}
+
+fn try_main() -> Fallible<()> {
+    let matches = Command::clap()
+        .name("niahu")
+        .about("Multiplatform Simulator of UFRGS Hypothetical Machines")
+        .get_matches();
+    let command = Command::from_clap(&matches);
+
+    let name = match (&command, command.input()) {
+        (_, Some(input)) => {
+            let header = machine::read_header(input)?;
+            machine_of_header(header).map_err(|error| WithPath {
+                path: input.into(),
+                error,
+            })?
+        },
+        (Command::New { machine: Some(name), .. }, None)
+        | (Command::Dap { machine: Some(name), .. }, None) => name.clone(),
+        (_, None) => Err(MissingMachine { known: MACHINES.to_vec() })?,
+    };
+
+    match &*name {
+        neander::Machine::NAME => cli::run::<neander::Machine>(command),
+        ahmes::Machine::NAME => cli::run::<ahmes::Machine>(command),
+        ramses::Machine::NAME => cli::run::<ramses::Machine>(command),
+        _ => Err(UnknownMachine { name, known: MACHINES.to_vec() })?,
+    }
+}
+
+/// Finds the name of the machine whose memory or state header is given.
+fn machine_of_header(header: [u8; 4]) -> Fallible<String> {
+    if neander::Machine::file_kind(header).is_some() {
+        Ok(neander::Machine::NAME.into())
+    } else if ahmes::Machine::file_kind(header).is_some() {
+        Ok(ahmes::Machine::NAME.into())
+    } else if ramses::Machine::file_kind(header).is_some() {
+        Ok(ramses::Machine::NAME.into())
+    } else {
+        Err(UnknownHeader { header })?
+    }
+}
@@ -1,13 +1,9 @@
 #[cfg(test)]
 mod test;
 
-use error::{Fallible, InvalidFile, WithPath};
-use std::{
-    fmt,
-    fs::File,
-    io::{Read, Write},
-    path::Path,
-};
+use error::Fallible;
+use machine::{Core, Machine as _};
+use std::io::{Read, Write};
 
 /// Opcode of NOP
 pub const NOP: u8 = 0x0;
@@ -45,27 +41,58 @@ pub const HLT: u8 = 0xF0;
 pub const REG_A: u8 = 0x0;
 pub const REG_B: u8 = 0x1;
 pub const REG_X: u8 = 0x2;
+/// Register field of no register, read as zero and never written.
+pub const REG_NONE: u8 = 0x3;
 
 pub const MODE_DIRECT: u8 = 0x0;
 pub const MODE_INDIRECT: u8 = 0x1;
 pub const MODE_IMMEDIATE: u8 = 0x2;
 pub const MODE_INDEXED: u8 = 0x3;
 
-const MEM_HEADER: [u8; 4] = [0x03, 0x52, 0x4D, 0x53];
-const STATE_HEADER: [u8; 4] = [0x04, 0x52, 0x4D, 0x53];
+/// Header of version 4 `.state` files, which did not save rb and rx.
+pub const STATE_HEADER_V4: [u8; 4] = [0x04, 0x52, 0x4D, 0x53];
 
-pub fn is_mem_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "mem")
+/// Registers of Ramses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Ra,
+    Rb,
+    Rx,
+    Pc,
 }
 
-pub fn is_state_file<P>(path: &P) -> bool
-where
-    P: AsRef<Path> + ?Sized,
-{
-    path.as_ref().extension().map_or(false, |ext| ext == "state")
+impl machine::Register for Register {
+    const ALL: &'static [Self] =
+        &[Register::Ra, Register::Rb, Register::Rx, Register::Pc];
+
+    fn name(self) -> &'static str {
+        match self {
+            Register::Ra => "ra",
+            Register::Rb => "rb",
+            Register::Rx => "rx",
+            Register::Pc => "pc",
+        }
+    }
+}
+
+/// Flags of Ramses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    N,
+    Z,
+    C,
+}
+
+impl machine::Flag for Flag {
+    const ALL: &'static [Self] = &[Flag::N, Flag::Z, Flag::C];
+
+    fn name(self) -> &'static str {
+        match self {
+            Flag::N => "n",
+            Flag::Z => "z",
+            Flag::C => "c",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -97,6 +124,9 @@ impl InstrInfo {
             NOT => {
                 Some(Self { mnemonic: "NOT", register: true, operand: false })
             },
+            SUB => {
+                Some(Self { mnemonic: "SUB", register: true, operand: true })
+            },
             JMP => {
                 Some(Self { mnemonic: "JMP", register: false, operand: true })
             },
@@ -120,6 +150,8 @@ impl InstrInfo {
     }
 }
 
+/// Writes the name of the register, or `R3` for the register field 3, which
+/// names no register.
 fn debug_reg_name<W>(mut output: W, reg: u8) -> Fallible<()>
 where
     W: Write,
@@ -128,7 +160,7 @@ where
         REG_A => write!(output, "A")?,
         REG_B => write!(output, "B")?,
         REG_X => write!(output, "X")?,
-        _ => write!(output, "?")?,
+        _ => write!(output, "R{}", reg)?,
     }
 
     Ok(())
@@ -186,28 +218,26 @@ where
             write!(output, " ")?;
             debug_mode(&mut output, instruction & 0x3, operand, hex)?;
         }
+        if info.register && instruction >> 2 & 0x3 == REG_NONE {
+            write!(output, " (undefined)")?;
+        }
         Ok(info.operand)
     } else {
         Ok(false)
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Machine {
-    ri: u8,
-    pc: u8,
+    core: Core,
     rr: u8,
     rm: u8,
     ra: u8,
     rb: u8,
     rx: u8,
-    mem: [u8; 256],
     negative: bool,
     zero: bool,
     carry: bool,
-    cycling: bool,
-    cycles: u64,
-    accesses: u64,
 }
 
 impl Machine {
@@ -215,16 +245,6 @@ impl Machine {
         Self::default()
     }
 
-    pub fn read(&mut self, addr: u8) -> u8 {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize]
-    }
-
-    pub fn write(&mut self, addr: u8, data: u8) {
-        self.accesses = self.accesses.saturating_add(1);
-        self.mem[addr as usize] = data;
-    }
-
     pub fn update_flags(&mut self, byte: u8) {
         self.zero = byte == 0;
         self.negative = byte & 0x80 != 0;
@@ -255,74 +275,21 @@ impl Machine {
 
     fn fetch_mode_addr(&mut self) -> u8 {
         let addr = match self.rm {
-            MODE_DIRECT => self.read(self.pc),
+            MODE_DIRECT => self.read(self.core.pc),
             MODE_INDIRECT => {
-                let addr = self.read(self.pc);
+                let addr = self.read(self.core.pc);
                 self.read(addr)
             },
-            MODE_IMMEDIATE => self.pc,
-            MODE_INDEXED => self.read(self.pc).wrapping_add(self.rx),
+            MODE_IMMEDIATE => self.core.pc,
+            MODE_INDEXED => self.read(self.core.pc).wrapping_add(self.rx),
             _ => panic!("Invalid mode, but no space for the user to give it"),
         };
 
-        self.pc = self.pc.wrapping_add(1);
+        self.core.pc = self.core.pc.wrapping_add(1);
 
         addr
     }
 
-    pub fn set_pc(&mut self, data: u8) {
-        self.pc = data;
-        self.cycles = 0;
-        self.accesses = 0;
-    }
-
-    pub fn write_raw(&mut self, addr: u8, data: u8) {
-        self.mem[addr as usize] = data;
-    }
-
-    pub fn cycle(&mut self) {
-        self.cycles += 1;
-        self.fetch();
-        self.decode_exec();
-    }
-
-    pub fn execute(&mut self) {
-        self.cycling = true;
-        while self.cycling {
-            self.cycle();
-        }
-    }
-
-    pub fn fetch(&mut self) {
-        self.ri = self.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-    }
-
-    pub fn decode_exec(&mut self) {
-        self.rm = self.ri & 0x3;
-        self.rr = self.ri >> 2 & 0x3;
-
-        match self.ri & 0xF0 {
-            NOP => self.exec_nop(),
-            STR => self.exec_str(),
-            LDR => self.exec_ldr(),
-            ADD => self.exec_add(),
-            OR => self.exec_or(),
-            AND => self.exec_and(),
-            NOT => self.exec_not(),
-            SUB => self.exec_sub(),
-            JMP => self.exec_jmp(),
-            JN => self.exec_jn(),
-            JZ => self.exec_jz(),
-            JC => self.exec_jc(),
-            JSR => self.exec_jsr(),
-            NEG => self.exec_neg(),
-            SHR => self.exec_shr(),
-            HLT => self.exec_hlt(),
-            _ => (),
-        }
-    }
-
     fn exec_nop(&mut self) {}
 
     fn exec_str(&mut self) {
@@ -375,34 +342,34 @@ impl Machine {
     }
 
     fn exec_jmp(&mut self) {
-        self.pc = self.fetch_mode_addr();
+        self.core.pc = self.fetch_mode_addr();
     }
 
     fn exec_jn(&mut self) {
         let addr = self.fetch_mode_addr();
         if self.negative {
-            self.pc = addr;
+            self.core.pc = addr;
         }
     }
 
     fn exec_jz(&mut self) {
         let addr = self.fetch_mode_addr();
         if self.zero {
-            self.pc = addr;
+            self.core.pc = addr;
         }
     }
 
     fn exec_jc(&mut self) {
         let addr = self.fetch_mode_addr();
         if self.carry {
-            self.pc = addr;
+            self.core.pc = addr;
         }
     }
 
     fn exec_jsr(&mut self) {
         let addr = self.fetch_mode_addr();
-        self.write(addr, self.pc);
-        self.pc = addr.wrapping_add(1);
+        self.write(addr, self.core.pc);
+        self.core.pc = addr.wrapping_add(1);
     }
 
     fn exec_neg(&mut self) {
@@ -418,309 +385,194 @@ impl Machine {
     }
 
     fn exec_hlt(&mut self) {
-        self.cycling = false;
+        self.core.cycling = false;
     }
+}
 
-    pub fn save_mem<W>(&self, mut output: W) -> Fallible<()>
-    where
-        W: Write,
-    {
-        output.write_all(&MEM_HEADER)?;
+impl machine::Machine for Machine {
+    type Register = Register;
+    type Flag = Flag;
 
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
+    const NAME: &'static str = "ramses";
+    const TITLE: &'static str = "Ramses";
+    const MEM_HEADER: [u8; 4] = [0x03, 0x52, 0x4D, 0x53];
+    const STATE_HEADER: [u8; 4] = [0x05, 0x52, 0x4D, 0x53];
+    const OLD_STATE_HEADERS: &'static [[u8; 4]] = &[STATE_HEADER_V4];
 
-        Ok(())
+    fn core(&self) -> &Core {
+        &self.core
     }
 
-    pub fn load_mem<R>(&mut self, mut input: R) -> Fallible<()>
-    where
-        R: Read,
-    {
-        let mut buf = [0; 4];
+    fn core_mut(&mut self) -> &mut Core {
+        &mut self.core
+    }
 
-        input.read_exact(&mut buf)?;
+    fn decode_exec(&mut self) {
+        self.rm = self.core.ri & 0x3;
+        self.rr = self.core.ri >> 2 & 0x3;
 
-        if buf != MEM_HEADER {
-            Err(InvalidFile)?;
+        match self.core.ri & 0xF0 {
+            NOP => self.exec_nop(),
+            STR => self.exec_str(),
+            LDR => self.exec_ldr(),
+            ADD => self.exec_add(),
+            OR => self.exec_or(),
+            AND => self.exec_and(),
+            NOT => self.exec_not(),
+            SUB => self.exec_sub(),
+            JMP => self.exec_jmp(),
+            JN => self.exec_jn(),
+            JZ => self.exec_jz(),
+            JC => self.exec_jc(),
+            JSR => self.exec_jsr(),
+            NEG => self.exec_neg(),
+            SHR => self.exec_shr(),
+            HLT => self.exec_hlt(),
+            _ => (),
         }
+    }
 
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
+    fn register(&self, reg: Register) -> u8 {
+        match reg {
+            Register::Ra => self.ra,
+            Register::Rb => self.rb,
+            Register::Rx => self.rx,
+            Register::Pc => self.core.pc,
         }
+    }
 
-        Ok(())
+    fn set_register(&mut self, reg: Register, data: u8) {
+        match reg {
+            Register::Ra => self.ra = data,
+            Register::Rb => self.rb = data,
+            Register::Rx => self.rx = data,
+            Register::Pc => self.core.pc = data,
+        }
+    }
+
+    fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::N => self.negative,
+            Flag::Z => self.zero,
+            Flag::C => self.carry,
+        }
     }
 
-    pub fn save_state<W>(&self, mut output: W) -> Fallible<()>
+    fn set_flag(&mut self, flag: Flag, value: bool) {
+        match flag {
+            Flag::N => self.negative = value,
+            Flag::Z => self.zero = value,
+            Flag::C => self.carry = value,
+        }
+    }
+
+    fn save_regs<W>(&self, mut output: W) -> Fallible<()>
     where
         W: Write,
     {
-        output.write_all(&STATE_HEADER)?;
-
-        output.write_all(&[self.ri, self.pc, self.ra])?;
+        output.write_all(&[self.ra, self.rb, self.rx])?;
         output.write_all(&[if self.negative { 1 } else { 0 }])?;
         output.write_all(&[if self.zero { 1 } else { 0 }])?;
         output.write_all(&[if self.carry { 1 } else { 0 }])?;
-        output.write_all(&[if self.cycling { 1 } else { 0 }])?;
-        output.write_all(&self.cycles.to_le_bytes())?;
-        output.write_all(&self.accesses.to_le_bytes())?;
-
-        for &byte in self.mem.iter() {
-            output.write_all(&[byte, 0x00])?;
-        }
-
         Ok(())
     }
 
-    pub fn load_state<R>(&mut self, mut input: R) -> Fallible<()>
+    fn load_regs<R>(&mut self, mut input: R) -> Fallible<()>
     where
         R: Read,
     {
-        let mut buf = [0; 8];
-
-        input.read_exact(&mut buf)?;
-
-        if &buf[.. 4] != &STATE_HEADER {
-            Err(InvalidFile)?;
-        }
-
-        self.ri = buf[4];
-        self.pc = buf[5];
-        self.ra = buf[6];
-        self.negative = buf[7] != 0;
-
-        input.read_exact(&mut buf[.. 3])?;
-
-        self.zero = buf[0] != 0;
-        self.carry = buf[1] != 0;
-        self.cycling = buf[2] != 0;
-
-        input.read_exact(&mut buf)?;
-        self.cycles = u64::from_le_bytes(buf);
-
+        let mut buf = [0; 6];
         input.read_exact(&mut buf)?;
-        self.accesses = u64::from_le_bytes(buf);
-
-        for byte in self.mem.iter_mut() {
-            input.read_exact(&mut buf[.. 2])?;
-            *byte = buf[0];
-        }
-
+        self.ra = buf[0];
+        self.rb = buf[1];
+        self.rx = buf[2];
+        self.negative = buf[3] != 0;
+        self.zero = buf[4] != 0;
+        self.carry = buf[5] != 0;
         Ok(())
     }
 
-    pub fn save_at_path<P>(&self, path: &P) -> Fallible<()>
+    /// Version 4 saved only ra and the flags, so rb and rx are left zeroed.
+    fn load_old_regs<R>(
+        &mut self,
+        _header: [u8; 4],
+        mut input: R,
+    ) -> Fallible<()>
     where
-        P: AsRef<Path> + ?Sized,
+        R: Read,
     {
-        let file = File::create(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.save_mem(file)
-        } else if is_state_file(path) {
-            self.save_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
+        let mut buf = [0; 4];
+        input.read_exact(&mut buf)?;
+        self.ra = buf[0];
+        self.rb = 0;
+        self.rx = 0;
+        self.negative = buf[1] != 0;
+        self.zero = buf[2] != 0;
+        self.carry = buf[3] != 0;
+        Ok(())
+    }
 
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
+    fn instr_info(opcode: u8) -> Option<machine::InstrInfo> {
+        InstrInfo::new(opcode).map(|info| machine::InstrInfo {
+            mnemonic: info.mnemonic,
+            operand: info.operand,
         })
     }
 
-    pub fn load_from_path<P>(&mut self, path: &P) -> Fallible<()>
-    where
-        P: AsRef<Path> + ?Sized,
-    {
-        let file = File::open(path.as_ref())?;
-
-        let res = if is_mem_file(path) {
-            self.load_mem(file)
-        } else if is_state_file(path) {
-            self.load_state(file)
-        } else {
-            Err(InvalidFile.into())
-        };
-
-        res.map_err(|error| {
-            WithPath { path: path.as_ref().into(), error }.into()
+    /// Instructions naming register 3, which does not exist, are undefined.
+    fn is_defined(opcode: u8) -> bool {
+        InstrInfo::new(opcode).is_some_and(|info| {
+            !info.register || opcode >> 2 & 0x3 != REG_NONE
         })
     }
 
-    pub fn display_mem_data<W, B>(
-        &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        B: IntoIterator<Item = u8>,
-        W: Write,
-    {
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(
-                    output,
-                    "{:03} = {:03}\n",
-                    addr, self.mem[addr as usize]
-                )?
-            }
+    fn jump_taken(
+        instr: &machine::Instr,
+        before: &machine::Regs<Self>,
+    ) -> Option<bool> {
+        match instr.opcode & 0xF0 {
+            JN => Some(before.flag(Flag::N)),
+            JZ => Some(before.flag(Flag::Z)),
+            JC => Some(before.flag(Flag::C)),
+            _ => None,
         }
-
-        Ok(())
     }
 
-    pub fn display_mem_opcodes<W, B>(
-        &mut self,
-        bounds: B,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        B: IntoIterator<Item = u8>,
-        W: Write,
-    {
-        let mut needs_operand = false;
-
-        for addr in bounds {
-            if hex {
-                write!(
-                    output,
-                    "{:02X} = {:02X}",
-                    addr, self.mem[addr as usize]
-                )?
-            } else {
-                write!(output, "{:03} = {:03}", addr, self.mem[addr as usize])?
-            }
-
-            if needs_operand {
-                needs_operand = false;
-            } else {
-                needs_operand = debug_mnemonic(
-                    &mut output,
-                    self.mem[addr as usize],
-                    self.mem[addr as usize + 1],
-                    hex,
-                )?;
-            }
-
-            write!(output, "\n")?;
-        }
-
-        Ok(())
+    fn effective_addr(&self, instr: &machine::Instr) -> Option<u8> {
+        let operand = instr.operand?;
+        Some(match instr.opcode & 0x3 {
+            MODE_DIRECT => operand,
+            MODE_INDIRECT => self.core.mem[operand as usize],
+            MODE_IMMEDIATE => instr.addr.wrapping_add(1),
+            _ => operand.wrapping_add(self.rx),
+        })
     }
 
-    pub fn display_registers<W>(
-        &mut self,
-        mut output: W,
-        hex: bool,
-    ) -> Fallible<()>
-    where
-        W: Write,
-    {
-        let flag_n = self.ra >> 7;
-        let flag_z = if self.ra == 0 { 1 } else { 0 };
-        let flag_c = if self.carry { 1 } else { 0 };
-
-        if hex {
-            write!(output, "ra = {:02X}\n", self.ra)?;
-            write!(output, "rb = {:02X}\n", self.rb)?;
-            write!(output, "rx = {:02X}\n", self.rx)?;
-            write!(output, "pc = {:02X}\n", self.pc)?;
-            write!(output, "n  = {:02X}\n", flag_n)?;
-            write!(output, "z  = {:02X}\n", flag_z)?;
-            write!(output, "c  = {:02X}\n", flag_c)?;
-        } else {
-            write!(output, "ra = {:03}\n", self.ra)?;
-            write!(output, "rb = {:03}\n", self.rb)?;
-            write!(output, "rx = {:03}\n", self.rx)?;
-            write!(output, "pc = {:03}\n", self.pc)?;
-            write!(output, "n  = {:03}\n", flag_n)?;
-            write!(output, "z  = {:03}\n", flag_z)?;
-            write!(output, "c  = {:03}\n", flag_c)?;
+    fn format_instr(instr: &machine::Instr) -> String {
+        let mut output = Vec::new();
+        let res = debug_mnemonic(
+            &mut output,
+            instr.opcode,
+            instr.operand.unwrap_or(0),
+            true,
+        );
+        match res {
+            Ok(_) if instr.mnemonic.is_some() => {
+                String::from_utf8_lossy(&output).trim().to_owned()
+            },
+            _ => format!("??? {:02X}", instr.opcode),
         }
-
-        Ok(())
     }
 
-    pub fn display_stats<W>(&mut self, mut output: W) -> Fallible<()>
+    fn debug_mnemonic<W>(
+        output: W,
+        instruction: u8,
+        operand: u8,
+        hex: bool,
+    ) -> Fallible<bool>
     where
         W: Write,
     {
-        write!(output, "cycles = {}\n", self.cycles)?;
-        write!(output, "accesses = {}\n", self.accesses)?;
-
-        Ok(())
-    }
-}
-
-impl fmt::Debug for Machine {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("ramses::Machine")
-            .field("ri", &self.ri)
-            .field("rr", &self.rr)
-            .field("rm", &self.rm)
-            .field("pc", &self.pc)
-            .field("ra", &self.ra)
-            .field("rb", &self.rb)
-            .field("rx", &self.rx)
-            .field("negative", &self.negative)
-            .field("zero", &self.zero)
-            .field("carry", &self.carry)
-            .field("mem", &(&self.mem as &[u8]))
-            .field("cycling", &self.cycling)
-            .field("cycles", &self.cycles)
-            .field("accesses", &self.accesses)
-            .finish()
-    }
-}
-
-impl Default for Machine {
-    fn default() -> Self {
-        Self {
-            ri: 0,
-            rr: 0,
-            rm: 0,
-            pc: 0,
-            ra: 0,
-            rb: 0,
-            rx: 0,
-            negative: false,
-            zero: false,
-            carry: false,
-            mem: [0; 256],
-            cycling: false,
-            cycles: 0,
-            accesses: 0,
-        }
+        debug_mnemonic(output, instruction, operand, hex)
     }
 }
-
-impl PartialEq for Machine {
-    fn eq(&self, other: &Self) -> bool {
-        self.ri == other.ri
-            && self.rr == other.rr
-            && self.rm == other.rm
-            && self.pc == other.pc
-            && self.ra == other.ra
-            && self.rb == other.rb
-            && self.rx == other.rx
-            && self.negative == other.negative
-            && self.carry == other.carry
-            && self.cycling == other.cycling
-            && self.cycles == other.cycles
-            && self.accesses == other.accesses
-            && &self.mem as &[u8] == &other.mem as &[u8]
-    }
-}
-
-impl Eq for Machine {}
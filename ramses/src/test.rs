@@ -3,131 +3,329 @@ use super::*;
 #[test]
 fn string_size() {
     let mut vm = Machine::new();
-    vm.mem[0x1] = LDR | REG_A << 2 | MODE_IMMEDIATE;
-    vm.mem[0x2] = 0;
-    vm.mem[0x3] = LDR | REG_X << 2;
-    vm.mem[0x4] = 0x80;
-    vm.mem[0x5] = LDR | REG_B << 2 | MODE_INDEXED;
-    vm.mem[0x6] = 0;
-    vm.mem[0x7] = JZ;
-    vm.mem[0x8] = 0xF;
-    vm.mem[0x9] = ADD | REG_X << 2 | MODE_IMMEDIATE;
-    vm.mem[0xA] = 1;
-    vm.mem[0xB] = ADD | REG_A << 2 | MODE_IMMEDIATE;
-    vm.mem[0xC] = 1;
-    vm.mem[0xD] = JMP;
-    vm.mem[0xE] = 0x5;
-    vm.mem[0xF] = STR | REG_A << 2;
-    vm.mem[0x10] = 0x81;
-    vm.mem[0x11] = HLT;
-
-    vm.mem[0x80] = 0xA0;
-
-    vm.mem[0xA0] = 100;
-    vm.mem[0xA1] = 99;
-    vm.mem[0xA2] = 98;
-    vm.mem[0xA3] = 97;
-    vm.mem[0xA4] = 0;
+    vm.core.mem[0x1] = LDR | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x2] = 0;
+    vm.core.mem[0x3] = LDR | REG_X << 2;
+    vm.core.mem[0x4] = 0x80;
+    vm.core.mem[0x5] = LDR | REG_B << 2 | MODE_INDEXED;
+    vm.core.mem[0x6] = 0;
+    vm.core.mem[0x7] = JZ;
+    vm.core.mem[0x8] = 0xF;
+    vm.core.mem[0x9] = ADD | REG_X << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0xA] = 1;
+    vm.core.mem[0xB] = ADD | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0xC] = 1;
+    vm.core.mem[0xD] = JMP;
+    vm.core.mem[0xE] = 0x5;
+    vm.core.mem[0xF] = STR | REG_A << 2;
+    vm.core.mem[0x10] = 0x81;
+    vm.core.mem[0x11] = HLT;
+
+    vm.core.mem[0x80] = 0xA0;
+
+    vm.core.mem[0xA0] = 100;
+    vm.core.mem[0xA1] = 99;
+    vm.core.mem[0xA2] = 98;
+    vm.core.mem[0xA3] = 97;
+    vm.core.mem[0xA4] = 0;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x81], 4);
+    assert_eq!(vm.core.mem[0x81], 4);
 }
 
 #[test]
 fn mul_algo() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = LDR;
-    vm.mem[0x1] = 0x85;
-    vm.mem[0x2] = STR;
-    vm.mem[0x3] = 0x82;
-    vm.mem[0x4] = LDR;
-    vm.mem[0x5] = 0x81;
-    vm.mem[0x6] = STR;
-    vm.mem[0x7] = 0x83;
-    vm.mem[0x8] = JZ;
-    vm.mem[0x9] = 0x18;
-    vm.mem[0xA] = ADD;
-    vm.mem[0xB] = 0x84;
-    vm.mem[0xC] = STR;
-    vm.mem[0xD] = 0x83;
-    vm.mem[0xE] = LDR;
-    vm.mem[0xF] = 0x80;
-    vm.mem[0x10] = ADD;
-    vm.mem[0x11] = 0x82;
-    vm.mem[0x12] = STR;
-    vm.mem[0x13] = 0x82;
-    vm.mem[0x14] = LDR;
-    vm.mem[0x15] = 0x83;
-    vm.mem[0x16] = JMP;
-    vm.mem[0x17] = 0x8;
-    vm.mem[0x18] = HLT;
-
-    vm.mem[0x80] = 5;
-    vm.mem[0x81] = 11;
-    vm.mem[0x84] = 255;
-    vm.mem[0x85] = 0;
+    vm.core.mem[0x0] = LDR;
+    vm.core.mem[0x1] = 0x85;
+    vm.core.mem[0x2] = STR;
+    vm.core.mem[0x3] = 0x82;
+    vm.core.mem[0x4] = LDR;
+    vm.core.mem[0x5] = 0x81;
+    vm.core.mem[0x6] = STR;
+    vm.core.mem[0x7] = 0x83;
+    vm.core.mem[0x8] = JZ;
+    vm.core.mem[0x9] = 0x18;
+    vm.core.mem[0xA] = ADD;
+    vm.core.mem[0xB] = 0x84;
+    vm.core.mem[0xC] = STR;
+    vm.core.mem[0xD] = 0x83;
+    vm.core.mem[0xE] = LDR;
+    vm.core.mem[0xF] = 0x80;
+    vm.core.mem[0x10] = ADD;
+    vm.core.mem[0x11] = 0x82;
+    vm.core.mem[0x12] = STR;
+    vm.core.mem[0x13] = 0x82;
+    vm.core.mem[0x14] = LDR;
+    vm.core.mem[0x15] = 0x83;
+    vm.core.mem[0x16] = JMP;
+    vm.core.mem[0x17] = 0x8;
+    vm.core.mem[0x18] = HLT;
+
+    vm.core.mem[0x80] = 5;
+    vm.core.mem[0x81] = 11;
+    vm.core.mem[0x84] = 255;
+    vm.core.mem[0x85] = 0;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x82], 55);
-    assert_eq!(vm.cycles, 94);
-    assert_eq!(vm.accesses, 257);
+    assert_eq!(vm.core.mem[0x82], 55);
+    assert_eq!(vm.core.cycles, 94);
+    assert_eq!(vm.core.accesses, 257);
 }
 
 #[test]
 fn is_pos() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = LDR;
-    vm.mem[0x1] = 0x80;
-    vm.mem[0x2] = NOT;
-    vm.mem[0x3] = JN;
-    vm.mem[0x4] = 0xA;
-    vm.mem[0x5] = LDR;
-    vm.mem[0x6] = 0x83;
-    vm.mem[0x7] = STR;
-    vm.mem[0x8] = 0x81;
-    vm.mem[0x9] = HLT;
-    vm.mem[0xA] = LDR;
-    vm.mem[0xB] = 0x82;
-    vm.mem[0xC] = STR;
-    vm.mem[0xD] = 0x81;
-    vm.mem[0xE] = HLT;
-
-    vm.mem[0x80] = 128;
-    vm.mem[0x82] = 1;
-    vm.mem[0x83] = 0;
+    vm.core.mem[0x0] = LDR;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = NOT;
+    vm.core.mem[0x3] = JN;
+    vm.core.mem[0x4] = 0xA;
+    vm.core.mem[0x5] = LDR;
+    vm.core.mem[0x6] = 0x83;
+    vm.core.mem[0x7] = STR;
+    vm.core.mem[0x8] = 0x81;
+    vm.core.mem[0x9] = HLT;
+    vm.core.mem[0xA] = LDR;
+    vm.core.mem[0xB] = 0x82;
+    vm.core.mem[0xC] = STR;
+    vm.core.mem[0xD] = 0x81;
+    vm.core.mem[0xE] = HLT;
+
+    vm.core.mem[0x80] = 128;
+    vm.core.mem[0x82] = 1;
+    vm.core.mem[0x83] = 0;
 
     vm.execute();
 
-    assert_eq!(vm.mem[0x81], 0);
+    assert_eq!(vm.core.mem[0x81], 0);
 }
 
 #[test]
 fn save_load_mem() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = 42;
-    vm.mem[0xB5] = 220;
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
     let mut buf = Vec::new();
     vm.save_mem(&mut buf).unwrap();
     let mut vm2 = Machine::new();
     vm2.load_mem(&mut &buf[..]).unwrap();
-    assert_eq!(&vm.mem as &[_], &vm2.mem as &[_]);
+    assert_eq!(&vm.core.mem as &[_], &vm2.core.mem as &[_]);
 }
 
 #[test]
 fn save_load_state() {
     let mut vm = Machine::new();
-    vm.mem[0x0] = 42;
-    vm.mem[0xB5] = 220;
-    vm.pc = 0x5;
+    vm.core.mem[0x0] = 42;
+    vm.core.mem[0xB5] = 220;
+    vm.core.pc = 0x5;
     vm.ra = 203;
-    vm.ri = 0x12;
-    vm.cycles = 2;
-    vm.accesses = 6;
+    vm.rb = 17;
+    vm.rx = 0x90;
+    vm.carry = true;
+    vm.core.ri = 0x12;
+    vm.core.cycles = 2;
+    vm.core.accesses = 6;
     let mut buf = Vec::new();
     vm.save_state(&mut buf).unwrap();
     let mut vm2 = Machine::new();
     vm2.load_state(&mut &buf[..]).unwrap();
     assert_eq!(vm, vm2);
 }
+
+#[test]
+fn load_state_v4() {
+    let mut vm = Machine::new();
+    vm.core.mem[0xB5] = 220;
+    vm.core.pc = 0x5;
+    vm.ra = 203;
+    vm.rb = 17;
+    vm.rx = 0x90;
+    vm.carry = true;
+    vm.zero = true;
+    let mut buf = Vec::new();
+    vm.save_state(&mut buf).unwrap();
+
+    // Version 4 had only ra and the flags, without rb and rx.
+    let mut old = STATE_HEADER_V4.to_vec();
+    old.extend_from_slice(&buf[4 .. 7]);
+    old.extend_from_slice(&buf[9 ..]);
+    assert_eq!(
+        Machine::file_kind(STATE_HEADER_V4),
+        Some(machine::FileKind::State)
+    );
+    let mut vm2 = Machine::new();
+    vm2.load_state(&old[..]).unwrap();
+    vm.rb = 0;
+    vm.rx = 0;
+    assert_eq!(vm, vm2);
+
+    let mut buf2 = Vec::new();
+    vm2.save_state(&mut buf2).unwrap();
+    assert_eq!(&buf2[.. 4], &Machine::STATE_HEADER);
+    let mut vm3 = Machine::new();
+    vm3.load_state(&buf2[..]).unwrap();
+    assert_eq!(vm2, vm3);
+
+    old[0] = 0x03;
+    assert!(Machine::new().load_state(&old[..]).is_err());
+}
+
+#[test]
+fn cycle_step_addr() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDR | REG_A << 2 | MODE_INDEXED;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = LDR | REG_B << 2 | MODE_INDIRECT;
+    vm.core.mem[0x3] = 0x90;
+    vm.core.mem[0x4] = STR | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x5] = 0x0;
+    vm.core.mem[0x85] = 0x42;
+    vm.core.mem[0x90] = 0xA0;
+    vm.rx = 0x5;
+
+    let step = vm.cycle();
+    assert_eq!(Machine::format_instr(&step.instr), "LDR A, 80, X");
+    assert_eq!(step.addr, Some(0x85));
+    assert_eq!(step.regs_changed()[0], (Register::Ra, 0x0, 0x42));
+    assert_eq!(step.fetches().len(), 2);
+    let data = machine::Access::Read { addr: 0x85, data: 0x42 };
+    assert_eq!(step.data_accesses(), &[data]);
+
+    let step = vm.cycle();
+    assert_eq!(Machine::format_instr(&step.instr), "LDR B, 90, i");
+    assert_eq!(step.addr, Some(0xA0));
+
+    let step = vm.cycle();
+    assert_eq!(step.addr, Some(0x5));
+    assert_eq!(step.writes().collect::<Vec<_>>(), vec![(0x5, 0x0, 0x42)]);
+    assert_eq!(step.fetches().len(), 1);
+    assert_eq!(step.data_accesses().len(), 1);
+}
+
+#[test]
+fn conditions() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = ADD | REG_X << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x1] = 0x80;
+    vm.core.mem[0x2] = JMP;
+    vm.core.mem[0x3] = 0x0;
+    vm.rx = 0x80;
+
+    let cond = machine::Expr::parse::<Machine>("c == 1 && rx == 0", None);
+    vm.add_condition(cond.unwrap());
+
+    assert_eq!(vm.execute(), machine::StopReason::Condition(0));
+    assert_eq!(vm.core.pc, 0x2);
+}
+
+#[test]
+fn history_flags() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDR | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x1] = 3;
+    vm.core.mem[0x2] = SUB | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x3] = 1;
+    vm.core.mem[0x4] = STR | REG_A << 2;
+    vm.core.mem[0x5] = 0x80;
+    vm.core.mem[0x6] = JZ;
+    vm.core.mem[0x7] = 0xA;
+    vm.core.mem[0x8] = JMP;
+    vm.core.mem[0x9] = 0x2;
+    vm.core.mem[0xA] = HLT;
+    vm.set_recording(true);
+    let start = vm.clone();
+    assert_eq!(vm.execute(), machine::StopReason::Halt);
+
+    let history = machine::History::of(&vm).unwrap();
+    assert_eq!(history.len(), 13);
+    let z = machine::Expr::parse::<Machine>("z", None).unwrap();
+    let event = history.first_became(&z).unwrap();
+    assert_eq!((event.cycle, event.pc), (10, Some(0x2)));
+    let writes = history.writes(0x80);
+    assert_eq!(writes.iter().map(|w| w.new).collect::<Vec<_>>(), [2, 1, 0]);
+
+    assert_eq!(vm.step_back(13), 13);
+    assert!(vm.same_state(&start));
+}
+
+#[test]
+fn display_registers() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = LDR | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x1] = 5;
+    vm.core.mem[0x2] = LDR | REG_B << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x3] = 0x80;
+    vm.set_max_cycles(Some(2));
+    vm.execute();
+
+    // N and Z are the flags set by the last instruction, here on rb, not
+    // the sign and zero of ra.
+    let mut output = Vec::new();
+    vm.display_registers(&mut output, true).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "ra = 05\nrb = 80\nrx = 00\npc = 04\nn  = 01\nz  = 00\nc  = 00\n"
+    );
+
+    let mut output = Vec::new();
+    vm.display_registers(&mut output, false).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "ra = 005\nrb = 128\nrx = 000\npc = 004\nn  = 001\nz  = 000\n\
+         c  = 000\n"
+    );
+}
+
+#[test]
+fn self_modifying_jsr() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = JSR;
+    vm.core.mem[0x1] = 0x1;
+    vm.core.mem[0x2] = HLT;
+    let start = vm.clone();
+
+    vm.set_smc_detection(Some(Vec::new()));
+    match vm.execute() {
+        machine::StopReason::SelfModify(hit) => {
+            assert_eq!((hit.addr, hit.new, hit.code), (0x1, 0x2, 0x0))
+        },
+        reason => panic!("unexpected {:?}", reason),
+    }
+
+    let mut vm = start;
+    vm.set_smc_detection(Some(vec![machine::SmcAllow::Mnemonic("JSR")]));
+    assert_eq!(vm.execute(), machine::StopReason::Halt);
+}
+
+#[test]
+fn strict_register() {
+    let mut vm = Machine::new();
+    vm.core.mem[0x0] = SUB | REG_A << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x1] = 1;
+    vm.core.mem[0x2] = LDR | REG_NONE << 2 | MODE_IMMEDIATE;
+    vm.core.mem[0x3] = 5;
+    vm.core.mem[0x4] = JMP | REG_NONE << 2;
+    vm.core.mem[0x5] = 0x6;
+    vm.core.mem[0x6] = HLT;
+    let start = vm.clone();
+
+    let sub = vm.decode(0x0);
+    assert_eq!((sub.mnemonic, sub.operand), (Some("SUB"), Some(1)));
+    assert!(Machine::is_defined(JMP | REG_NONE << 2));
+    assert!(!Machine::is_defined(LDR | REG_NONE << 2));
+    assert_eq!(
+        Machine::format_instr(&vm.decode(0x2)),
+        "LDR R3, #05 (undefined)"
+    );
+
+    vm.set_strict(true);
+    assert_eq!(vm.execute(), machine::StopReason::Undefined(vm.decode(0x2)));
+    assert_eq!(vm.ra, 0xFF);
+
+    let mut vm = start;
+    assert_eq!(vm.execute(), machine::StopReason::Halt);
+    assert_eq!((vm.ra, vm.rb, vm.rx), (0xFF, 0, 0));
+}